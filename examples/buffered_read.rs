@@ -0,0 +1,95 @@
+// Counts the reads made of the device while copying a 100 MiB file in small
+// chunks, first with positional reads that bypass the buffer and then with
+// buffered sequential reads.
+//
+//     cargo run --release --example buffered_read
+extern crate hfsplus_rescue;
+
+use hfsplus_rescue::FileSystem;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+const BLOCK_SIZE: u32 = 4096;
+const FILE_BLOCKS: u32 = 25600;
+const FIRST_FILE_BLOCK: u32 = 16;
+const CHUNK_SIZE: usize = 512;
+
+struct CountingReader<F> {
+    inner: F,
+    reads: Arc<AtomicUsize>,
+}
+
+impl<F> Read for CountingReader<F> where F: Read {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.inner.read(buf)
+    }
+}
+
+impl<F> Seek for CountingReader<F> where F: Seek {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+// A volume whose allocation file is one extent filling most of it.
+fn volume_image() -> Vec<u8> {
+    let total_blocks = FIRST_FILE_BLOCK + FILE_BLOCKS;
+    let mut image = vec![0; total_blocks as usize * BLOCK_SIZE as usize];
+    {
+        let header = &mut image[1024..1536];
+        header[0..2].copy_from_slice(b"H+");
+        header[3] = 4;
+        put_u32(header, 40, BLOCK_SIZE);
+        put_u32(header, 44, total_blocks);
+        let fork = &mut header[112..192];
+        fork[0..8].copy_from_slice(&(FILE_BLOCKS as u64 * BLOCK_SIZE as u64).to_be_bytes());
+        put_u32(fork, 12, FILE_BLOCKS);
+        put_u32(fork, 16, FIRST_FILE_BLOCK);
+        put_u32(fork, 20, FILE_BLOCKS);
+    }
+    for (idx, byte) in image[FIRST_FILE_BLOCK as usize * BLOCK_SIZE as usize..].iter_mut().enumerate() {
+        *byte = (idx % 251) as u8;
+    }
+    image
+}
+
+fn main() {
+    let reads = Arc::new(AtomicUsize::new(0));
+    let source = CountingReader { inner: Cursor::new(volume_image()), reads: reads.clone() };
+    let filesystem = FileSystem::new(source);
+    let mut file = filesystem.get_volume_header().unwrap().get_file_allocation().unwrap();
+    let mut buf = [0; CHUNK_SIZE];
+
+    reads.store(0, Ordering::Relaxed);
+    let start = Instant::now();
+    let mut offset = 0;
+    loop {
+        let read = file.read_at(offset, &mut buf).unwrap();
+        if read == 0 {
+            break;
+        }
+        offset += read as u64;
+    }
+    println!("Unbuffered: {} bytes in {:?} with {} device reads", offset, start.elapsed(),
+             reads.load(Ordering::Relaxed));
+
+    reads.store(0, Ordering::Relaxed);
+    let start = Instant::now();
+    let mut total = 0;
+    loop {
+        let read = file.read(&mut buf).unwrap();
+        if read == 0 {
+            break;
+        }
+        total += read as u64;
+    }
+    println!("Buffered: {} bytes in {:?} with {} device reads", total, start.elapsed(),
+             reads.load(Ordering::Relaxed));
+}
@@ -1,22 +1,101 @@
 extern crate hfsplus_rescue;
+#[cfg(feature = "json")]
+extern crate serde_json;
 
+use std::env;
 use std::fs::File;
 use std::io::{Read, Seek};
-use hfsplus_rescue::{FileSystem, FileSlice, ForkData};
+use std::process;
+use hfsplus_rescue::{FileSystem, FileSlice, ForkData, HeaderComparison, HeaderCopy};
 
 fn print_fork_extents<'a, F>(fork: &ForkData<'a, F>) where F: Read + Seek {
-    for i in 0..fork.num_extent_descriptors() {
-        println!("Extent: {}", i);
-        println!("{}", fork.get_extent_descriptor(i));
+    let extents = match fork.snapshot() {
+        Ok(snapshot) => snapshot.extents,
+        Err(e) => {
+            println!("Extents: <unreadable: {}>", e);
+            return;
+        },
+    };
+    for (i, &(start_block, block_count)) in extents.iter().take_while(|&&(_, count)| count != 0).enumerate() {
+        println!("Extent {}: start block {}, block count {}", i, start_block, block_count);
     }
 }
 
+fn print_validity(name: &str, error: &Option<String>) {
+    match *error {
+        Some(ref e) => println!("{} header: invalid ({})", name, e),
+        None => println!("{} header: valid", name),
+    }
+}
+
+fn print_newer(name: &str, newer: Option<HeaderCopy>) {
+    match newer {
+        Some(copy) => println!("Newer {}: {}", name, copy),
+        None => println!("Newer {}: neither", name),
+    }
+}
+
+fn print_comparison(comparison: &HeaderComparison) {
+    print_validity("Primary", &comparison.primary_error);
+    print_validity("Alternate", &comparison.alternate_error);
+    print_newer("modify date", comparison.newer_modify_date);
+    print_newer("write count", comparison.newer_write_count);
+    println!("Differing fields (primary / alternate): {}", comparison.differences.len());
+    for difference in &comparison.differences {
+        println!("  {}", difference);
+    }
+}
+
+#[cfg(feature = "json")]
+fn print_comparison_json(comparison: &HeaderComparison) {
+    println!("{}", serde_json::to_string_pretty(comparison).unwrap());
+}
+
+#[cfg(not(feature = "json"))]
+fn print_comparison_json(_comparison: &HeaderComparison) {
+    eprintln!("--json needs the json feature");
+    process::exit(1);
+}
+
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let compare_alt = args.iter().any(|arg| arg == "--compare-alt");
+    let json = args.iter().any(|arg| arg == "--json");
+    if let Some(arg) = args.iter().find(|arg| *arg != "--compare-alt" && *arg != "--json") {
+        eprintln!("Unknown argument {}", arg);
+        process::exit(1);
+    }
+
     let device = File::open("./drive.img").unwrap();
     let partition = FileSlice::new(device, 209735680, None).unwrap();
     let fs = FileSystem::new(partition);
+    if compare_alt {
+        let comparison = fs.compare_volume_headers().unwrap();
+        if json {
+            print_comparison_json(&comparison);
+        } else {
+            print_comparison(&comparison);
+        }
+        return;
+    }
     let header = fs.get_volume_header().unwrap();
     println!("Header: {}", header);
+    match header.get_volume_name() {
+        Ok(name) => println!("Volume name: {}", name),
+        Err(e) => println!("Volume name: <unreadable: {}>", e),
+    }
+    println!("Fingerprint: {}", fs.volume_fingerprint().unwrap());
+    for issue in header.check_consistency().unwrap() {
+        println!("Warning: {}", issue);
+    }
+    if header.get_attributes().unwrap().is_journaled() {
+        println!("Journal info: {}", header.get_journal_info().unwrap());
+    }
+
+    match fs.get_alternate_volume_header() {
+        Ok(alternate) => println!("Alternate header: {}", alternate),
+        Err(e) => println!("Alternate header: {}", e),
+    }
 
     let allocation_fork = header.get_fork_data_allocation();
     println!("Allocation fork: {}", allocation_fork);
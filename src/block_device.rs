@@ -0,0 +1,60 @@
+use libc;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+
+// The size of a regular file or block device. Seeking to the end of a raw
+// device doesn't always give its size, so for those the kernel is asked.
+pub fn source_length(file: &File) -> io::Result<u64> {
+    let metadata = file.metadata()?;
+    if metadata.file_type().is_block_device() {
+        device_size(file)
+    } else {
+        Ok(metadata.len())
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn device_size(file: &File) -> io::Result<u64> {
+    // _IOR(0x12, 114, size_t), where the direction bits depend on the
+    // architecture.
+    #[cfg(any(target_arch = "powerpc", target_arch = "powerpc64", target_arch = "mips", target_arch = "mips64",
+              target_arch = "sparc", target_arch = "sparc64"))]
+    const IOC_READ: u64 = 2 << 29;
+    #[cfg(not(any(target_arch = "powerpc", target_arch = "powerpc64", target_arch = "mips", target_arch = "mips64",
+                  target_arch = "sparc", target_arch = "sparc64")))]
+    const IOC_READ: u64 = 2 << 30;
+    const BLKGETSIZE64: u64 = IOC_READ | (::std::mem::size_of::<usize>() as u64) << 16 | 0x12 << 8 | 114;
+
+    let mut size: u64 = 0;
+    check(unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64 as _, &mut size) })?;
+    Ok(size)
+}
+
+#[cfg(target_os = "macos")]
+fn device_size(file: &File) -> io::Result<u64> {
+    // _IOR('d', 24, uint32_t) and _IOR('d', 25, uint64_t).
+    const DKIOCGETBLOCKSIZE: libc::c_ulong = 0x4004_6418;
+    const DKIOCGETBLOCKCOUNT: libc::c_ulong = 0x4008_6419;
+
+    let mut block_size: u32 = 0;
+    let mut block_count: u64 = 0;
+    check(unsafe { libc::ioctl(file.as_raw_fd(), DKIOCGETBLOCKSIZE, &mut block_size) })?;
+    check(unsafe { libc::ioctl(file.as_raw_fd(), DKIOCGETBLOCKCOUNT, &mut block_count) })?;
+    block_count.checked_mul(block_size as u64)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "block device size overflows"))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn device_size(_file: &File) -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "block device sizes can't be queried on this platform"))
+}
@@ -0,0 +1,1223 @@
+use catalog::decode_thread_name;
+use error::HFSPError;
+use filesystem::SIZE_EXTENT_RECORD;
+use fs;
+use ownership::{ForkType, TreeCoverage};
+use snapshot::{be_u16, be_u32, NUM_EXTENT_DESCRIPTORS};
+use std::cmp;
+use std::collections::HashSet;
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::vec;
+
+const SIZE_BTREE_NODE_DESCRIPTOR: usize = 14;
+const SIZE_BTREE_HEADER_RECORD: usize = 106;
+const MIN_BTREE_NODE_SIZE: u16 = 512;
+const MAX_BTREE_NODE_SIZE: u16 = 32768;
+const MAX_BTREE_DEPTH: usize = 16;
+const NODE_KIND_INDEX: i8 = 0;
+const NODE_KIND_LEAF: i8 = -1;
+const OFFSET_BTREE_FIRST_LEAF_NODE: usize = SIZE_BTREE_NODE_DESCRIPTOR + 10;
+const LEAF_HEIGHT: u8 = 1;
+const KEY_COMPARE_BINARY: u8 = 0xbc;
+const ATTRIBUTE_BAD_CLOSE: u32 = 1;
+const ATTRIBUTE_BIG_KEYS: u32 = 1 << 1;
+const ATTRIBUTE_VARIABLE_INDEX_KEYS: u32 = 1 << 2;
+
+// The header record from node 0 of a B-tree file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct BTreeHeader {
+    pub depth: u16,
+    // Zero when the tree is empty.
+    pub root_node: u32,
+    pub leaf_records: u32,
+    pub first_leaf_node: u32,
+    pub last_leaf_node: u32,
+    pub node_size: u16,
+    pub max_key_length: u16,
+    pub total_nodes: u32,
+    pub free_nodes: u32,
+    pub clump_size: u32,
+    pub btree_type: u8,
+    pub key_compare_type: u8,
+    pub attributes: u32,
+}
+
+impl BTreeHeader {
+    // Reads the header from the start of a B-tree file, such as the catalog
+    // file opened as an HFSFile.
+    pub fn read<R>(reader: &mut R) -> fs::Result<BTreeHeader> where R: Read + Seek {
+        let mut data = [0; SIZE_BTREE_NODE_DESCRIPTOR + SIZE_BTREE_HEADER_RECORD];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_exact(&mut data)?;
+        BTreeHeader::from_bytes(&data)
+    }
+
+    // Expects the start of the header node, including its node descriptor.
+    pub fn from_bytes(data: &[u8]) -> fs::Result<BTreeHeader> {
+        // The header is always in node 0, so the offsets in the fork are those
+        // in the node whatever its size.
+        let error = |offset: usize| HFSPError::from(NodeError::new(0, 0, offset, NodeErrorKind::InvalidHeader));
+        if data.len() < SIZE_BTREE_NODE_DESCRIPTOR + SIZE_BTREE_HEADER_RECORD {
+            return Err(error(SIZE_BTREE_NODE_DESCRIPTOR));
+        }
+        let record = &data[SIZE_BTREE_NODE_DESCRIPTOR..];
+        let header = BTreeHeader {
+            depth: be_u16(record, 0),
+            root_node: be_u32(record, 2),
+            leaf_records: be_u32(record, 6),
+            first_leaf_node: be_u32(record, 10),
+            last_leaf_node: be_u32(record, 14),
+            node_size: be_u16(record, 18),
+            max_key_length: be_u16(record, 20),
+            total_nodes: be_u32(record, 22),
+            free_nodes: be_u32(record, 26),
+            clump_size: be_u32(record, 32),
+            btree_type: record[36],
+            key_compare_type: record[37],
+            attributes: be_u32(record, 38),
+        };
+        if header.depth as usize > MAX_BTREE_DEPTH {
+            return Err(error(SIZE_BTREE_NODE_DESCRIPTOR));
+        }
+        if header.root_node >= header.total_nodes {
+            return Err(error(SIZE_BTREE_NODE_DESCRIPTOR + 2));
+        }
+        if !header.node_size.is_power_of_two() ||
+            !(MIN_BTREE_NODE_SIZE..=MAX_BTREE_NODE_SIZE).contains(&header.node_size) {
+            return Err(error(SIZE_BTREE_NODE_DESCRIPTOR + 18));
+        }
+        Ok(header)
+    }
+
+    // Set when the tree wasn't closed cleanly.
+    pub fn is_bad_close(&self) -> bool {
+        self.attributes & ATTRIBUTE_BAD_CLOSE != 0
+    }
+
+    // Set when key lengths are 16 bits rather than 8, as they always are on
+    // HFS+.
+    pub fn has_big_keys(&self) -> bool {
+        self.attributes & ATTRIBUTE_BIG_KEYS != 0
+    }
+
+    pub fn has_variable_index_keys(&self) -> bool {
+        self.attributes & ATTRIBUTE_VARIABLE_INDEX_KEYS != 0
+    }
+
+    // Set in the catalog of a case-sensitive HFSX volume, whose names are
+    // compared as raw UTF-16 rather than case folded.
+    pub fn has_binary_compare(&self) -> bool {
+        self.key_compare_type == KEY_COMPARE_BINARY
+    }
+}
+
+impl Display for BTreeHeader {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        writeln!(fmt, "Depth: {}", self.depth)?;
+        writeln!(fmt, "Root node: {}", self.root_node)?;
+        writeln!(fmt, "Leaf records: {}", self.leaf_records)?;
+        writeln!(fmt, "Leaf nodes: {}-{}", self.first_leaf_node, self.last_leaf_node)?;
+        writeln!(fmt, "Node size: {}", self.node_size)?;
+        writeln!(fmt, "Max key length: {}", self.max_key_length)?;
+        writeln!(fmt, "Nodes: {} ({} free)", self.total_nodes, self.free_nodes)?;
+        writeln!(fmt, "Clump size: {}", self.clump_size)?;
+        writeln!(fmt, "Type: {:#04x}", self.btree_type)?;
+        writeln!(fmt, "Key compare type: {:#04x}", self.key_compare_type)?;
+        writeln!(fmt, "Attributes: {:#010x}", self.attributes)?;
+        Ok(())
+    }
+}
+
+// Where a B-tree reads its nodes from. Any Read + Seek will do, and a
+// wrapper can cache nodes underneath the tree.
+pub trait NodeReader {
+    fn read_node(&mut self, index: u32, node_size: usize) -> fs::Result<Vec<u8>>;
+
+    // How many whole nodes the underlying file holds, which may be fewer
+    // than the header claims.
+    fn node_capacity(&mut self, node_size: usize) -> fs::Result<u64>;
+
+    fn read_header(&mut self) -> fs::Result<BTreeHeader> {
+        BTreeHeader::from_bytes(&self.read_node(0, MIN_BTREE_NODE_SIZE as usize)?)
+    }
+}
+
+impl<S> NodeReader for S where S: Read + Seek {
+    fn read_node(&mut self, index: u32, node_size: usize) -> fs::Result<Vec<u8>> {
+        let mut result = vec![0; node_size];
+        self.seek(SeekFrom::Start(index as u64 * node_size as u64))?;
+        self.read_exact(&mut result)?;
+        Ok(result)
+    }
+
+    fn node_capacity(&mut self, node_size: usize) -> fs::Result<u64> {
+        Ok(self.seek(SeekFrom::End(0))? / node_size as u64)
+    }
+}
+
+// Decodes and orders the keys of one kind of tree. Keys are given without
+// their length prefix.
+pub trait BTreeKey: Sized {
+    fn decode(key: &[u8]) -> Option<Self>;
+
+    // The header is passed for trees whose ordering depends on it.
+    fn compare(&self, other: &Self, header: &BTreeHeader) -> cmp::Ordering;
+}
+
+// Catalog keys order by parent ID and then name. Names compare as binary
+// UTF-16 on HFSX volumes that say so, and otherwise case-insensitively.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CatalogKey {
+    pub parent_id: u32,
+    pub name: Vec<u16>,
+}
+
+impl CatalogKey {
+    pub fn new(parent_id: u32, name: &str) -> CatalogKey {
+        CatalogKey {
+            parent_id,
+            name: name.encode_utf16().collect(),
+        }
+    }
+
+    pub fn get_name(&self) -> String {
+        String::from_utf16_lossy(&self.name)
+    }
+}
+
+impl BTreeKey for CatalogKey {
+    fn decode(key: &[u8]) -> Option<CatalogKey> {
+        if key.len() < 6 {
+            return None;
+        }
+        Some(CatalogKey {
+            parent_id: be_u32(key, 0),
+            name: decode_utf16(key, 4)?,
+        })
+    }
+
+    fn compare(&self, other: &CatalogKey, header: &BTreeHeader) -> cmp::Ordering {
+        let names = if header.has_binary_compare() {
+            self.name.cmp(&other.name)
+        } else {
+            self.name.iter().cloned().map(fold_case).cmp(other.name.iter().cloned().map(fold_case))
+        };
+        self.parent_id.cmp(&other.parent_id).then(names)
+    }
+}
+
+// Extents overflow keys order by file ID, then fork type, then the block
+// within the fork the record starts at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ExtentKey {
+    pub fork_type: ForkType,
+    pub file_id: u32,
+    pub start_block: u32,
+}
+
+impl BTreeKey for ExtentKey {
+    fn decode(key: &[u8]) -> Option<ExtentKey> {
+        if key.len() < 10 {
+            return None;
+        }
+        Some(ExtentKey {
+            fork_type: ForkType::from_raw(key[0])?,
+            file_id: be_u32(key, 2),
+            start_block: be_u32(key, 6),
+        })
+    }
+
+    fn compare(&self, other: &ExtentKey, _header: &BTreeHeader) -> cmp::Ordering {
+        self.file_id.cmp(&other.file_id)
+            .then(self.fork_type.cmp(&other.fork_type))
+            .then(self.start_block.cmp(&other.start_block))
+    }
+}
+
+// Attributes keys order by file ID, then attribute name as binary UTF-16,
+// then the block the record starts at for attributes stored in extents.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct AttributeKey {
+    pub file_id: u32,
+    pub start_block: u32,
+    pub name: Vec<u16>,
+}
+
+impl AttributeKey {
+    pub fn new(file_id: u32, name: &str) -> AttributeKey {
+        AttributeKey {
+            file_id,
+            start_block: 0,
+            name: name.encode_utf16().collect(),
+        }
+    }
+
+    pub fn get_name(&self) -> String {
+        String::from_utf16_lossy(&self.name)
+    }
+}
+
+impl BTreeKey for AttributeKey {
+    fn decode(key: &[u8]) -> Option<AttributeKey> {
+        if key.len() < 12 {
+            return None;
+        }
+        Some(AttributeKey {
+            file_id: be_u32(key, 2),
+            start_block: be_u32(key, 6),
+            name: decode_utf16(key, 10)?,
+        })
+    }
+
+    fn compare(&self, other: &AttributeKey, _header: &BTreeHeader) -> cmp::Ordering {
+        self.file_id.cmp(&other.file_id)
+            .then(self.name.cmp(&other.name))
+            .then(self.start_block.cmp(&other.start_block))
+    }
+}
+
+pub type CatalogTree<S> = BTree<CatalogKey, S>;
+pub type ExtentsTree<S> = BTree<ExtentKey, S>;
+pub type AttributesTree<S> = BTree<AttributeKey, S>;
+
+// Why a node couldn't be used. Offsets in the node are of the descriptor or
+// offset table field that is wrong.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum NodeErrorKind {
+    UnexpectedKind { found: i8, expected: i8 },
+    UnexpectedHeight { found: u8, expected: u8 },
+    NoRecords,
+    TooManyRecords(u16),
+    RecordOffsetsOutOfOrder { record: usize },
+    RecordOffsetOutOfBounds { record: usize, offset: u16 },
+    // A header record field that is out of range, such as the node size.
+    InvalidHeader,
+    // A record whose key is malformed for the kind of tree.
+    InvalidKey { record: usize },
+    // A leaf record whose data is too short or malformed for its key.
+    InvalidData { record: usize },
+    // A link to a node past the end of the tree or already visited.
+    InvalidLink(u32),
+    // Only reported to the callback of a walk that skips bad nodes, as
+    // otherwise the read error itself is returned.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Unreadable(io::ErrorKind),
+}
+
+impl Display for NodeErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            NodeErrorKind::UnexpectedKind { found, expected } =>
+                write!(f, "node kind {} where {} was expected", found, expected),
+            NodeErrorKind::UnexpectedHeight { found, expected } =>
+                write!(f, "height {} where {} was expected", found, expected),
+            NodeErrorKind::NoRecords => write!(f, "no records"),
+            NodeErrorKind::TooManyRecords(count) => write!(f, "{} records don't fit in the node", count),
+            NodeErrorKind::RecordOffsetsOutOfOrder { record } => write!(f, "record {} ends before it starts", record),
+            NodeErrorKind::RecordOffsetOutOfBounds { record, offset } =>
+                write!(f, "record {} offset {} lies outside the records area", record, offset),
+            NodeErrorKind::InvalidHeader => write!(f, "header record field out of range"),
+            NodeErrorKind::InvalidKey { record } => write!(f, "record {} has a malformed key", record),
+            NodeErrorKind::InvalidData { record } => write!(f, "record {} has malformed data", record),
+            NodeErrorKind::InvalidLink(link) => write!(f, "link to invalid or already visited node {}", link),
+            NodeErrorKind::Unreadable(kind) => write!(f, "unreadable ({:?})", kind),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct NodeError {
+    pub node_number: u32,
+    // Where the faulty field is in the tree's fork.
+    pub byte_offset_in_fork: u64,
+    pub kind: NodeErrorKind,
+}
+
+impl NodeError {
+    fn new(node_number: u32, node_size: usize, offset_in_node: usize, kind: NodeErrorKind) -> NodeError {
+        NodeError {
+            node_number,
+            byte_offset_in_fork: node_number as u64 * node_size as u64 + offset_in_node as u64,
+            kind,
+        }
+    }
+}
+
+impl Display for NodeError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "node {} (fork offset {}): {}", self.node_number, self.byte_offset_in_fork, self.kind)
+    }
+}
+
+impl error::Error for NodeError {}
+
+impl From<NodeError> for HFSPError {
+    fn from(error: NodeError) -> HFSPError {
+        HFSPError::InvalidBTreeNode(error)
+    }
+}
+
+// A leaf record found by a search, with where it is in the tree.
+struct LeafRecord {
+    node_number: u32,
+    node_size: usize,
+    record: usize,
+    data_offset: usize,
+    data: Vec<u8>,
+}
+
+impl LeafRecord {
+    fn invalid_data(&self) -> HFSPError {
+        let kind = NodeErrorKind::InvalidData { record: self.record };
+        NodeError::new(self.node_number, self.node_size, self.data_offset, kind).into()
+    }
+}
+
+pub struct BTree<K, S> {
+    reader: S,
+    key: PhantomData<fn() -> K>,
+}
+
+impl<K, S> BTree<K, S> {
+    pub fn new(reader: S) -> BTree<K, S> {
+        BTree {
+            reader,
+            key: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.reader
+    }
+}
+
+impl<K, S> BTree<K, S> where S: NodeReader {
+    pub fn header(&mut self) -> fs::Result<BTreeHeader> {
+        self.reader.read_header()
+    }
+
+    // Descends from the root to the leaf where a key would be, following the
+    // last child whose first key isn't past it, or the first child if every
+    // key is. The comparison is of a node's key against the target, and
+    // returns None if the key is malformed. Malformed keys fail with
+    // HFSPError::InvalidBTreeNode, naming the node and record.
+    fn find_leaf<C>(&mut self, header: &BTreeHeader, compare: C) -> fs::Result<(u32, Vec<u8>)>
+        where C: Fn(&[u8]) -> Option<cmp::Ordering> {
+        let node_size = header.node_size as usize;
+        let mut node_index = header.root_node;
+        // The root is as high as the tree is deep, and each level down is
+        // one lower, ending with the leaves at height 1.
+        // The header limits the depth, so the descent always ends.
+        let mut height = header.depth;
+        loop {
+            let node = self.reader.read_node(node_index, node_size)?;
+            let (kind, expected_height) = match height {
+                0 | 1 => (NODE_KIND_LEAF, LEAF_HEIGHT),
+                height => (NODE_KIND_INDEX, cmp::min(height, u8::MAX as u16) as u8),
+            };
+            let records = check_node(&node, node_index, kind, expected_height)?;
+            match kind {
+                NODE_KIND_INDEX => {
+                    let mut child = None;
+                    for (idx, range) in records.iter().enumerate() {
+                        let error = || invalid_key(node_index, node_size, idx, range);
+                        // A key too long to leave room for the child pointer
+                        // is as malformed as one that can't be compared.
+                        let (key, data) = split_index_key(&node[range.clone()], header)
+                            .filter(|&(_, data)| data.len() >= 4).ok_or_else(error)?;
+                        if child.is_some() && compare(key).ok_or_else(error)? == cmp::Ordering::Greater {
+                            break;
+                        }
+                        child = Some(be_u32(data, 0));
+                    }
+                    node_index = child.ok_or_else(|| NodeError::new(node_index, node_size, 10, NodeErrorKind::NoRecords))?;
+                    height -= 1;
+                },
+                _ => return Ok((node_index, node)),
+            }
+        }
+    }
+
+    // Finds the leaf record whose key compares equal and returns the data
+    // following the key. The comparison is as for find_leaf.
+    pub fn search_by<C>(&mut self, compare: C) -> fs::Result<Option<Vec<u8>>>
+        where C: Fn(&[u8]) -> Option<cmp::Ordering> {
+        Ok(self.find_record_by(compare)?.map(|found| found.data))
+    }
+
+    // As search_by, but also says where the record is so that data that
+    // can't be decoded can be reported against it.
+    fn find_record_by<C>(&mut self, compare: C) -> fs::Result<Option<LeafRecord>>
+        where C: Fn(&[u8]) -> Option<cmp::Ordering> {
+        let header = self.header()?;
+        if header.root_node == 0 {
+            return Ok(None);
+        }
+        let (index, node) = self.find_leaf(&header, &compare)?;
+        for (idx, range) in btree_node_record_ranges(&node, index)?.iter().enumerate() {
+            let error = || invalid_key(index, node.len(), idx, range);
+            let (key, data) = split_key(&node[range.clone()]).ok_or_else(error)?;
+            if compare(key).ok_or_else(error)? == cmp::Ordering::Equal {
+                return Ok(Some(LeafRecord {
+                    node_number: index,
+                    node_size: node.len(),
+                    record: idx,
+                    data_offset: range.end - data.len(),
+                    data: data.to_vec(),
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    // Iterates over the leaf nodes in key order by following the forward
+    // links from the first leaf. Only the header node is needed, not the
+    // index nodes.
+    pub fn leaf_nodes(&mut self) -> fs::Result<LeafNodes<'_, S>> {
+        let header = self.header()?;
+        Ok(LeafNodes::new(&mut self.reader, &header, header.first_leaf_node))
+    }
+
+    // Calls the callback with the key and data of every leaf record, in key
+    // order, by following the forward links from the first leaf.
+    pub fn walk_leaf_records<C>(&mut self, mut callback: C) -> fs::Result<()>
+        where C: FnMut(&[u8], &[u8]) -> fs::Result<()> {
+        for node in self.leaf_nodes()? {
+            let node = node?;
+            for (idx, range) in node.records.iter().enumerate() {
+                let (key, data) = split_key(&node.data[range.clone()])
+                    .ok_or_else(|| invalid_key(node.index, node.data.len(), idx, range))?;
+                callback(key, data)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Calls the callback with the key and data of every record in the leaf
+    // nodes, in node order rather than key order. Unlike walk_leaf_records
+    // this doesn't rely on the links between nodes, so nodes that can't be
+    // read or parsed are skipped and counted instead of ending the walk.
+    pub fn scan_leaf_records<C>(&mut self, mut callback: C) -> fs::Result<TreeCoverage>
+        where C: FnMut(&[u8], &[u8]) {
+        let header = self.header()?;
+        let node_size = header.node_size as usize;
+        let file_nodes = self.reader.node_capacity(node_size)?;
+        let total_nodes = cmp::min(header.total_nodes as u64, file_nodes) as u32;
+
+        // Nodes the map marks as free are skipped. Only the map record in
+        // the header node is read, not any map nodes after it, so nodes
+        // beyond what it covers are scanned as if in use. So are all nodes
+        // if the header node's records can't be parsed.
+        let header_node = self.reader.read_node(0, node_size)?;
+        let map = btree_node_records(&header_node, 0).ok()
+            .and_then(|records| records.get(2).map(|map| map.to_vec()))
+            .unwrap_or_default();
+        let mut coverage = TreeCoverage {
+            nodes_total: total_nodes,
+            nodes_unreadable: 0,
+        };
+        for node_index in 1..total_nodes {
+            let map_byte = map.get(node_index as usize / 8).cloned().unwrap_or(0xff);
+            if map_byte & (0x80 >> (node_index % 8)) == 0 {
+                continue;
+            }
+            let node = match self.reader.read_node(node_index, node_size) {
+                Ok(node) => node,
+                Err(_) => {
+                    coverage.nodes_unreadable += 1;
+                    continue;
+                },
+            };
+            if node[8] as i8 != NODE_KIND_LEAF {
+                continue;
+            }
+            match btree_node_records(&node, node_index) {
+                Ok(records) => {
+                    for record in records {
+                        if let Some((key, data)) = split_key(record) {
+                            callback(key, data);
+                        }
+                    }
+                },
+                Err(_) => coverage.nodes_unreadable += 1,
+            }
+        }
+        Ok(coverage)
+    }
+}
+
+impl<K, S> BTree<K, S> where K: BTreeKey, S: NodeReader {
+    pub fn search(&mut self, key: &K) -> fs::Result<Option<Vec<u8>>> {
+        Ok(self.find_record(key)?.map(|found| found.data))
+    }
+
+    fn find_record(&mut self, key: &K) -> fs::Result<Option<LeafRecord>> {
+        let header = self.header()?;
+        self.find_record_by(|raw| K::decode(raw).map(|decoded| decoded.compare(key, &header)))
+    }
+
+    // Every leaf record in key order, with its data.
+    pub fn records(&mut self) -> fs::Result<Records<'_, K, S>> {
+        let header = self.header()?;
+        let nodes = LeafNodes::new(&mut self.reader, &header, header.first_leaf_node);
+        Ok(Records::new(nodes, header, None, None))
+    }
+
+    // The leaf records with keys in the range, in key order. Only the index
+    // nodes on the way to the start of the range are read.
+    pub fn range(&mut self, range: Range<K>) -> fs::Result<Records<'_, K, S>> {
+        let header = self.header()?;
+        let first = if header.root_node == 0 {
+            0
+        } else {
+            let start = &range.start;
+            self.find_leaf(&header, |raw| K::decode(raw).map(|decoded| decoded.compare(start, &header)))?.0
+        };
+        let nodes = LeafNodes::new(&mut self.reader, &header, first);
+        Ok(Records::new(nodes, header, Some(range.start), Some(range.end)))
+    }
+}
+
+impl<S> BTree<CatalogKey, S> where S: NodeReader {
+    // Looks up the thread record for a catalog node ID and returns the name
+    // it holds.
+    pub fn find_thread_name(&mut self, cnid: u32) -> fs::Result<String> {
+        // Thread keys have an empty name, so they sort before every other key
+        // with the same parent ID.
+        let found = self.find_record(&CatalogKey { parent_id: cnid, name: Vec::new() })?;
+        let found = found.ok_or(HFSPError::MissingCatalogRecord)?;
+        decode_thread_name(&found.data).ok_or_else(|| found.invalid_data())
+    }
+}
+
+impl<S> BTree<ExtentKey, S> where S: NodeReader {
+    // Returns the overflow extents of a fork that start at the given block
+    // within the fork.
+    pub fn find_overflow_extents(&mut self, file_id: u32, fork_type: ForkType, start_block: u32)
+        -> fs::Result<Option<Vec<(u32, u32)>>> {
+        match self.find_record(&ExtentKey { fork_type, file_id, start_block })? {
+            Some(ref found) if found.data.len() < SIZE_EXTENT_RECORD as usize => Err(found.invalid_data()),
+            Some(LeafRecord { data: record, .. }) => {
+                let extents = (0..NUM_EXTENT_DESCRIPTORS).map(|idx| {
+                    (be_u32(&record, idx * 8), be_u32(&record, idx * 8 + 4))
+                });
+                Ok(Some(extents.collect()))
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+impl<S> BTree<AttributeKey, S> where S: NodeReader {
+    // Returns the record for an attribute of a file, whose data depends on
+    // how the attribute is stored.
+    pub fn find_attribute(&mut self, file_id: u32, name: &str) -> fs::Result<Option<Vec<u8>>> {
+        self.search(&AttributeKey::new(file_id, name))
+    }
+}
+
+// Decoded leaf records from a walk along the leaf chain, stopping with an
+// error at the first node or key that can't be read unless bad nodes are
+// being skipped.
+pub struct Records<'b, K, S> where S: 'b {
+    nodes: LeafNodes<'b, S>,
+    header: BTreeHeader,
+    start: Option<K>,
+    end: Option<K>,
+    pending: vec::IntoIter<(K, Vec<u8>)>,
+}
+
+impl<'b, K, S> Records<'b, K, S> {
+    fn new(nodes: LeafNodes<'b, S>, header: BTreeHeader, start: Option<K>, end: Option<K>) -> Records<'b, K, S> {
+        Records {
+            nodes,
+            header,
+            start,
+            end,
+            pending: Vec::new().into_iter(),
+        }
+    }
+
+    // As for LeafNodes::skip_bad_nodes. Records with malformed keys are
+    // reported and skipped too.
+    pub fn skip_bad_nodes<C>(mut self, on_error: C) -> Records<'b, K, S> where C: FnMut(&NodeError) + 'b {
+        self.nodes = self.nodes.skip_bad_nodes(on_error);
+        self
+    }
+}
+
+impl<'b, K, S> Iterator for Records<'b, K, S> where K: BTreeKey, S: NodeReader {
+    type Item = fs::Result<(K, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<fs::Result<(K, Vec<u8>)>> {
+        loop {
+            if let Some((key, data)) = self.pending.next() {
+                if self.start.as_ref().is_some_and(|start| key.compare(start, &self.header) == cmp::Ordering::Less) {
+                    continue;
+                }
+                if self.end.as_ref().is_some_and(|end| key.compare(end, &self.header) != cmp::Ordering::Less) {
+                    self.nodes.stop();
+                    self.pending = Vec::new().into_iter();
+                    return None;
+                }
+                return Some(Ok((key, data)));
+            }
+            let node = match self.nodes.next()? {
+                Ok(node) => node,
+                Err(e) => return Some(Err(e)),
+            };
+            let mut records = Vec::with_capacity(node.num_records());
+            for (idx, range) in node.records.iter().enumerate() {
+                let record = &node.data[range.clone()];
+                let decoded = split_key(record).and_then(|(key, data)| Some((K::decode(key)?, data.to_vec())));
+                match decoded {
+                    Some(decoded) => records.push(decoded),
+                    None => {
+                        let error = NodeError::new(node.index, node.data.len(), range.start,
+                                                   NodeErrorKind::InvalidKey { record: idx });
+                        if !self.nodes.report(&error) {
+                            self.nodes.stop();
+                            return Some(Err(error.into()));
+                        }
+                    },
+                }
+            }
+            self.pending = records.into_iter();
+        }
+    }
+}
+
+// A leaf node read while following the chain of forward links.
+pub struct LeafNode {
+    index: u32,
+    data: Vec<u8>,
+    records: Vec<Range<usize>>,
+}
+
+impl LeafNode {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    // Zero for the last leaf.
+    pub fn forward_link(&self) -> u32 {
+        be_u32(&self.data, 0)
+    }
+
+    // Zero for the first leaf.
+    pub fn backward_link(&self) -> u32 {
+        be_u32(&self.data, 4)
+    }
+
+    pub fn num_records(&self) -> usize {
+        self.records.len()
+    }
+
+    // The raw records, keys included, in the order they appear in the node.
+    pub fn records(&self) -> impl Iterator<Item = &[u8]> {
+        self.records.iter().map(move |range| &self.data[range.clone()])
+    }
+}
+
+type ErrorCallback<'b> = Box<dyn FnMut(&NodeError) + 'b>;
+
+// Walks the leaf chain, ending with an error at the first node that can't be
+// read, isn't a leaf, or has been visited already.
+pub struct LeafNodes<'b, S> where S: 'b {
+    reader: &'b mut S,
+    node_size: usize,
+    total_nodes: u32,
+    // The node whose forward link is being followed, or 0 for the header.
+    previous: u32,
+    next: u32,
+    seen: HashSet<u32>,
+    on_error: Option<ErrorCallback<'b>>,
+}
+
+impl<'b, S> LeafNodes<'b, S> {
+    fn new(reader: &'b mut S, header: &BTreeHeader, first: u32) -> LeafNodes<'b, S> {
+        LeafNodes {
+            reader,
+            node_size: header.node_size as usize,
+            total_nodes: header.total_nodes,
+            previous: 0,
+            next: first,
+            seen: HashSet::new(),
+            on_error: None,
+        }
+    }
+
+    // Instead of ending the walk, bad nodes are given to the callback and
+    // skipped. The walk carries on from the bad node's forward link if that
+    // looks usable, and otherwise from the next unvisited leaf found by
+    // scanning forwards through the tree.
+    pub fn skip_bad_nodes<C>(mut self, on_error: C) -> LeafNodes<'b, S> where C: FnMut(&NodeError) + 'b {
+        self.on_error = Some(Box::new(on_error));
+        self
+    }
+
+    fn stop(&mut self) {
+        self.next = 0;
+    }
+
+    // Gives the error to the callback, if bad nodes are being skipped.
+    fn report(&mut self, error: &NodeError) -> bool {
+        match self.on_error {
+            Some(ref mut on_error) => {
+                on_error(error);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn is_usable_link(&self, link: u32) -> bool {
+        link != 0 && link < self.total_nodes && !self.seen.contains(&link)
+    }
+}
+
+impl<'b, S> LeafNodes<'b, S> where S: NodeReader {
+    // On failure, also returns the node's forward link if it could be read.
+    fn read_next(&mut self) -> Result<LeafNode, (HFSPError, Option<u32>)> {
+        let index = self.next;
+        // A corrupt link could otherwise send us round in circles.
+        if index >= self.total_nodes || !self.seen.insert(index) {
+            let link_offset = if self.previous == 0 { OFFSET_BTREE_FIRST_LEAF_NODE } else { 0 };
+            let error = NodeError::new(self.previous, self.node_size, link_offset, NodeErrorKind::InvalidLink(index));
+            return Err((error.into(), None));
+        }
+        let data = self.reader.read_node(index, self.node_size).map_err(|e| (e, None))?;
+        let forward_link = be_u32(&data, 0);
+        let records = check_node(&data, index, NODE_KIND_LEAF, LEAF_HEIGHT)
+            .map_err(|e| (e.into(), Some(forward_link)))?;
+        self.previous = index;
+        self.next = forward_link;
+        Ok(LeafNode { index, data, records })
+    }
+
+    // The first unvisited node from the given one that claims to be a leaf,
+    // or 0 if there are none.
+    fn resync(&mut self, from: u32) -> u32 {
+        for index in from..self.total_nodes {
+            if self.seen.contains(&index) {
+                continue;
+            }
+            if let Ok(data) = self.reader.read_node(index, self.node_size) {
+                if data[8] as i8 == NODE_KIND_LEAF && data[9] == LEAF_HEIGHT {
+                    return index;
+                }
+            }
+        }
+        0
+    }
+}
+
+impl<'b, S> Iterator for LeafNodes<'b, S> where S: NodeReader {
+    type Item = fs::Result<LeafNode>;
+
+    fn next(&mut self) -> Option<fs::Result<LeafNode>> {
+        while self.next != 0 {
+            let index = self.next;
+            let (error, forward_link) = match self.read_next() {
+                Ok(node) => return Some(Ok(node)),
+                Err(failure) => failure,
+            };
+            let error = match error {
+                HFSPError::InvalidBTreeNode(error) => error,
+                HFSPError::IOError(ref e) if self.on_error.is_some() =>
+                    NodeError::new(index, self.node_size, 0, NodeErrorKind::Unreadable(e.kind())),
+                error => {
+                    self.stop();
+                    return Some(Err(error));
+                },
+            };
+            if !self.report(&error) {
+                self.stop();
+                return Some(Err(error.into()));
+            }
+            let resume_from = match error.kind {
+                NodeErrorKind::InvalidLink(_) => self.previous + 1,
+                _ => {
+                    self.previous = index;
+                    index + 1
+                },
+            };
+            self.next = match forward_link {
+                Some(link) if self.is_usable_link(link) => link,
+                _ => self.resync(resume_from),
+            };
+        }
+        None
+    }
+}
+
+// Checks the descriptor of a node that should be of the given kind and
+// height, and returns the ranges of its records.
+fn check_node(node: &[u8], index: u32, kind: i8, height: u8) -> Result<Vec<Range<usize>>, NodeError> {
+    let error = |offset: usize, kind: NodeErrorKind| NodeError::new(index, node.len(), offset, kind);
+    if node[8] as i8 != kind {
+        return Err(error(8, NodeErrorKind::UnexpectedKind { found: node[8] as i8, expected: kind }));
+    }
+    if node[9] != height {
+        return Err(error(9, NodeErrorKind::UnexpectedHeight { found: node[9], expected: height }));
+    }
+    btree_node_record_ranges(node, index)
+}
+
+// The record offsets are stored as a stack of u16s at the end of the node,
+// with one more entry than there are records marking the free space.
+fn btree_node_record_ranges(node: &[u8], index: u32) -> Result<Vec<Range<usize>>, NodeError> {
+    let error = |offset: usize, kind: NodeErrorKind| NodeError::new(index, node.len(), offset, kind);
+    let num_records = be_u16(node, 10) as usize;
+    if num_records == 0 {
+        return Err(error(10, NodeErrorKind::NoRecords));
+    }
+    if (num_records + 1) * 2 + SIZE_BTREE_NODE_DESCRIPTOR > node.len() {
+        return Err(error(10, NodeErrorKind::TooManyRecords(num_records as u16)));
+    }
+    let table_start = node.len() - (num_records + 1) * 2;
+    let entry = |idx: usize| node.len() - 2 * (idx + 1);
+    let mut offsets = Vec::with_capacity(num_records + 1);
+    for idx in 0..=num_records {
+        let offset = be_u16(node, entry(idx));
+        if (offset as usize) < SIZE_BTREE_NODE_DESCRIPTOR || offset as usize > table_start {
+            return Err(error(entry(idx), NodeErrorKind::RecordOffsetOutOfBounds { record: idx, offset }));
+        }
+        if offsets.last().is_some_and(|&previous| previous > offset as usize) {
+            return Err(error(entry(idx), NodeErrorKind::RecordOffsetsOutOfOrder { record: idx - 1 }));
+        }
+        offsets.push(offset as usize);
+    }
+    Ok(offsets.windows(2).map(|pair| pair[0]..pair[1]).collect())
+}
+
+fn btree_node_records(node: &[u8], index: u32) -> Result<Vec<&[u8]>, NodeError> {
+    Ok(btree_node_record_ranges(node, index)?.into_iter().map(|range| &node[range]).collect())
+}
+
+// Index node keys take up the maximum key length, whatever their own length,
+// unless the tree says they vary.
+fn split_index_key<'r>(record: &'r [u8], header: &BTreeHeader) -> Option<(&'r [u8], &'r [u8])> {
+    if header.has_variable_index_keys() {
+        return split_key(record);
+    }
+    let (key, _) = split_key(record)?;
+    let key_space = 2 + header.max_key_length as usize;
+    if key_space > record.len() {
+        return None;
+    }
+    Some((key, &record[key_space..]))
+}
+
+// Decodes a length-prefixed UTF-16 string.
+pub fn decode_utf16(data: &[u8], offset: usize) -> Option<Vec<u16>> {
+    let length = be_u16(data, offset) as usize;
+    if data.len() < offset + 2 + length * 2 {
+        return None;
+    }
+    Some((0..length).map(|idx| be_u16(data, offset + 2 + idx * 2)).collect())
+}
+
+// Folds case one UTF-16 unit at a time. This matches the HFS+ case folding
+// table for the Latin, Greek and Cyrillic letters but is only an
+// approximation elsewhere.
+fn fold_case(unit: u16) -> u16 {
+    let folded = char::from_u32(unit as u32).map(|c| {
+        let mut lower = c.to_lowercase();
+        match (lower.next(), lower.next()) {
+            (Some(l), None) if (l as u32) <= 0xffff => l as u32 as u16,
+            _ => unit,
+        }
+    });
+    folded.unwrap_or(unit)
+}
+
+// Splits a record into its key, without the length, and the data after it.
+fn split_key(record: &[u8]) -> Option<(&[u8], &[u8])> {
+    if record.len() < 2 {
+        return None;
+    }
+    let key_length = be_u16(record, 0) as usize;
+    if 2 + key_length > record.len() {
+        return None;
+    }
+    Some((&record[2..2 + key_length], &record[2 + key_length..]))
+}
+
+// The error for the record of a node whose key can't be split off or
+// compared.
+fn invalid_key(index: u32, node_size: usize, record: usize, range: &Range<usize>) -> HFSPError {
+    NodeError::new(index, node_size, range.start, NodeErrorKind::InvalidKey { record }).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use fixture;
+    use std::io::Cursor;
+    use super::*;
+
+    const NODE_SIZE: usize = 512;
+
+    fn extent_record(file_id: u32) -> Vec<u8> {
+        fixture::record(fixture::extent_key(0, file_id, 0), &[0; SIZE_EXTENT_RECORD as usize])
+    }
+
+    fn index_record(key: Vec<u8>, child: u32) -> Vec<u8> {
+        fixture::record(key, &child.to_be_bytes())
+    }
+
+    // An extents tree of a root index node 1 over leaf node 2.
+    fn extents_tree(index_records: &[Vec<u8>], leaf_records: &[Vec<u8>]) -> ExtentsTree<Cursor<Vec<u8>>> {
+        let fields = fixture::BTreeHeaderFields {
+            depth: 2, root: 1, first_leaf: 2, last_leaf: 2, total_nodes: 3, max_key_length: 10,
+            ..Default::default()
+        };
+        let mut data = fixture::btree_header_node(NODE_SIZE, &fields);
+        data.extend(fixture::btree_node(NODE_SIZE, NODE_KIND_INDEX, 2, 0, index_records));
+        data.extend(fixture::btree_node(NODE_SIZE, NODE_KIND_LEAF, LEAF_HEIGHT, 0, leaf_records));
+        BTree::new(Cursor::new(data))
+    }
+
+    // An extents tree of a root index node 1 over a chain of leaves from node
+    // 2, each given its forward link and the file IDs of its records. The
+    // index has a record for the first key of each leaf.
+    fn chained_tree(leaves: &[(u32, &[u32])]) -> ExtentsTree<Cursor<Vec<u8>>> {
+        let last_leaf = leaves.len() as u32 + 1;
+        let fields = fixture::BTreeHeaderFields {
+            depth: 2, root: 1, first_leaf: 2, last_leaf, total_nodes: last_leaf + 1, max_key_length: 10,
+            ..Default::default()
+        };
+        let index_records: Vec<Vec<u8>> = leaves.iter().enumerate()
+            .map(|(idx, &(_, file_ids))| index_record(fixture::extent_key(0, file_ids[0], 0), idx as u32 + 2))
+            .collect();
+        let mut data = fixture::btree_header_node(NODE_SIZE, &fields);
+        data.extend(fixture::btree_node(NODE_SIZE, NODE_KIND_INDEX, 2, 0, &index_records));
+        for &(next, file_ids) in leaves {
+            let records: Vec<Vec<u8>> = file_ids.iter().map(|&file_id| extent_record(file_id)).collect();
+            data.extend(fixture::btree_node(NODE_SIZE, NODE_KIND_LEAF, LEAF_HEIGHT, next, &records));
+        }
+        BTree::new(Cursor::new(data))
+    }
+
+    fn leaf_indices(nodes: LeafNodes<Cursor<Vec<u8>>>) -> Vec<Result<u32, NodeError>> {
+        nodes.map(|node| node.map(|node| node.index()).map_err(|e| node_error::<()>(Err(e)))).collect()
+    }
+
+    fn search(tree: &mut ExtentsTree<Cursor<Vec<u8>>>, file_id: u32) -> fs::Result<Option<Vec<u8>>> {
+        tree.search(&ExtentKey { fork_type: ForkType::Data, file_id, start_block: 0 })
+    }
+
+    fn node_error<T>(result: fs::Result<T>) -> NodeError {
+        match result {
+            Err(HFSPError::InvalidBTreeNode(error)) => error,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+    }
+
+    #[test]
+    fn searches_descend_to_the_leaf() {
+        let mut tree = extents_tree(&[index_record(fixture::extent_key(0, 5, 0), 2)],
+                                    &[extent_record(5), extent_record(7)]);
+        assert!(search(&mut tree, 5).unwrap().is_some());
+        assert!(search(&mut tree, 7).unwrap().is_some());
+        assert!(search(&mut tree, 6).unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_leaf_keys_name_the_node_and_record() {
+        // The second key claims to run past the end of its record.
+        let mut bad = extent_record(7);
+        bad[1] = 200;
+        let mut tree = extents_tree(&[index_record(fixture::extent_key(0, 5, 0), 2)], &[extent_record(5), bad.clone()]);
+        assert!(search(&mut tree, 5).unwrap().is_some());
+        let error = node_error(search(&mut tree, 7));
+        assert_eq!(error.node_number, 2);
+        assert_eq!(error.kind, NodeErrorKind::InvalidKey { record: 1 });
+        let record_start = 14 + extent_record(5).len();
+        assert_eq!(error.byte_offset_in_fork, 2 * NODE_SIZE as u64 + record_start as u64);
+        assert_eq!(node_error(tree.walk_leaf_records(|_, _| Ok(()))), error);
+
+        // A key that splits off but doesn't decode fails the same way.
+        bad = extent_record(7);
+        bad[2] = 0x55;
+        let mut tree = extents_tree(&[index_record(fixture::extent_key(0, 5, 0), 2)], &[extent_record(5), bad]);
+        assert_eq!(node_error(search(&mut tree, 7)), error);
+    }
+
+    #[test]
+    fn malformed_index_keys_name_the_node_and_record() {
+        let first = index_record(fixture::extent_key(0, 5, 0), 2);
+        let cases = [
+            // A fork type that doesn't exist.
+            index_record(fixture::extent_key(0x55, 6, 0), 2),
+            // No room for the child pointer.
+            fixture::extent_key(0, 6, 0),
+            // A key length past the end of the record.
+            index_record(vec![0, 100, 0, 0], 2),
+        ];
+        for second in &cases {
+            let mut tree = extents_tree(&[first.clone(), second.clone()], &[extent_record(5), extent_record(7)]);
+            let error = node_error(search(&mut tree, 7));
+            assert_eq!(error.node_number, 1, "{:?}", second);
+            assert_eq!(error.kind, NodeErrorKind::InvalidKey { record: 1 }, "{:?}", second);
+            assert_eq!(error.byte_offset_in_fork, NODE_SIZE as u64 + 14 + first.len() as u64, "{:?}", second);
+        }
+    }
+
+    #[test]
+    fn record_offsets_outside_the_node_name_the_node_and_record() {
+        let mut tree = extents_tree(&[index_record(fixture::extent_key(0, 5, 0), 2)],
+                                    &[extent_record(5), extent_record(7)]);
+        // The offset of the second leaf record points into the offset table.
+        let leaf = tree.reader.get_mut();
+        let entry = 3 * NODE_SIZE - 4;
+        fixture::put_u16(leaf, entry, NODE_SIZE as u16 - 2);
+        let error = node_error(search(&mut tree, 7));
+        assert_eq!(error.node_number, 2);
+        assert_eq!(error.kind, NodeErrorKind::RecordOffsetOutOfBounds { record: 1, offset: NODE_SIZE as u16 - 2 });
+        assert_eq!(error.byte_offset_in_fork, entry as u64);
+    }
+
+    #[test]
+    fn cyclic_leaf_chains_end_with_a_link_error() {
+        let mut tree = chained_tree(&[(3, &[5]), (2, &[7])]);
+        let indices = leaf_indices(tree.leaf_nodes().unwrap());
+        let error = NodeError::new(3, NODE_SIZE, 0, NodeErrorKind::InvalidLink(2));
+        assert_eq!(indices, [Ok(2), Ok(3), Err(error)]);
+        assert_eq!(error.byte_offset_in_fork, 3 * NODE_SIZE as u64);
+
+        // Links past the end of the tree fail the same way.
+        let mut tree = chained_tree(&[(3, &[5]), (9, &[7])]);
+        let error = NodeError::new(3, NODE_SIZE, 0, NodeErrorKind::InvalidLink(9));
+        assert_eq!(leaf_indices(tree.leaf_nodes().unwrap()), [Ok(2), Ok(3), Err(error)]);
+    }
+
+    // A chain of three leaves whose middle one has the wrong height.
+    fn tree_with_bad_middle_leaf() -> ExtentsTree<Cursor<Vec<u8>>> {
+        let mut tree = chained_tree(&[(3, &[5]), (4, &[7]), (0, &[9])]);
+        tree.reader.get_mut()[3 * NODE_SIZE + 9] = 2;
+        tree
+    }
+
+    #[test]
+    fn skipped_bad_leaves_are_reported_and_the_walk_continues() {
+        let mut tree = tree_with_bad_middle_leaf();
+        let mut errors = Vec::new();
+        let indices = leaf_indices(tree.leaf_nodes().unwrap().skip_bad_nodes(|error| errors.push(*error)));
+        assert_eq!(indices, [Ok(2), Ok(4)]);
+        let kind = NodeErrorKind::UnexpectedHeight { found: 2, expected: LEAF_HEIGHT };
+        assert_eq!(errors, [NodeError::new(3, NODE_SIZE, 9, kind)]);
+    }
+
+    #[test]
+    fn bad_leaves_end_the_walk_unless_skipped() {
+        let mut tree = tree_with_bad_middle_leaf();
+        let kind = NodeErrorKind::UnexpectedHeight { found: 2, expected: LEAF_HEIGHT };
+        let error = NodeError::new(3, NODE_SIZE, 9, kind);
+        assert_eq!(leaf_indices(tree.leaf_nodes().unwrap()), [Ok(2), Err(error)]);
+        assert_eq!(node_error(tree.walk_leaf_records(|_, _| Ok(()))), error);
+    }
+
+    fn file_ids(records: Records<ExtentKey, Cursor<Vec<u8>>>) -> Vec<u32> {
+        records.map(|record| record.unwrap().0.file_id).collect()
+    }
+
+    fn key(file_id: u32) -> ExtentKey {
+        ExtentKey { fork_type: ForkType::Data, file_id, start_block: 0 }
+    }
+
+    #[test]
+    fn ranges_include_their_start_and_exclude_their_end() {
+        let mut tree = chained_tree(&[(3, &[5, 7]), (4, &[9, 11]), (0, &[13, 15])]);
+        assert_eq!(file_ids(tree.records().unwrap()), [5, 7, 9, 11, 13, 15]);
+        assert_eq!(file_ids(tree.range(key(7)..key(11)).unwrap()), [7, 9]);
+        assert_eq!(file_ids(tree.range(key(6)..key(14)).unwrap()), [7, 9, 11, 13]);
+        assert_eq!(file_ids(tree.range(key(1)..key(6)).unwrap()), [5]);
+        assert_eq!(file_ids(tree.range(key(15)..key(100)).unwrap()), [15]);
+    }
+
+    #[test]
+    fn ranges_cross_leaf_boundaries() {
+        let mut tree = chained_tree(&[(3, &[5, 7]), (4, &[9, 11]), (0, &[13, 15])]);
+        assert_eq!(file_ids(tree.range(key(11)..key(14)).unwrap()), [11, 13]);
+        assert_eq!(file_ids(tree.range(key(5)..key(16)).unwrap()), [5, 7, 9, 11, 13, 15]);
+        // The walk stops at the end of the range, so a bad leaf after it
+        // isn't read.
+        tree.reader.get_mut()[4 * NODE_SIZE + 9] = 2;
+        assert_eq!(file_ids(tree.range(key(7)..key(10)).unwrap()), [7, 9]);
+        assert!(tree.range(key(7)..key(14)).unwrap().any(|record| record.is_err()));
+    }
+
+    #[test]
+    fn empty_ranges_have_no_records() {
+        let mut tree = chained_tree(&[(3, &[5, 7]), (4, &[9, 11]), (0, &[13, 15])]);
+        assert!(file_ids(tree.range(key(8)..key(9)).unwrap()).is_empty());
+        assert!(file_ids(tree.range(key(9)..key(9)).unwrap()).is_empty());
+        assert!(file_ids(tree.range(key(11)..key(9)).unwrap()).is_empty());
+        assert!(file_ids(tree.range(key(16)..key(100)).unwrap()).is_empty());
+    }
+
+    fn header_error(patch: fn(&mut Vec<u8>)) -> NodeError {
+        let fields = fixture::BTreeHeaderFields { depth: 2, root: 1, total_nodes: 3, ..Default::default() };
+        let mut data = fixture::btree_header_node(NODE_SIZE, &fields);
+        patch(&mut data);
+        node_error(BTreeHeader::from_bytes(&data))
+    }
+
+    #[test]
+    fn bad_header_fields_are_located() {
+        let depth = header_error(|data| data[15] = MAX_BTREE_DEPTH as u8 + 1);
+        assert_eq!(depth.kind, NodeErrorKind::InvalidHeader);
+        assert_eq!((depth.node_number, depth.byte_offset_in_fork), (0, 14));
+        // A root node past the end of the tree, then a node size that isn't a
+        // power of two.
+        assert_eq!(header_error(|data| data[19] = 3).byte_offset_in_fork, 16);
+        assert_eq!(header_error(|data| data[33] = 0xff).byte_offset_in_fork, 32);
+        assert_eq!(node_error(BTreeHeader::from_bytes(&[0; 20])).byte_offset_in_fork, 14);
+    }
+
+    #[test]
+    fn short_overflow_extent_records_name_the_node_and_record() {
+        let short = fixture::record(fixture::extent_key(0, 7, 0), &[0; 10]);
+        let mut tree = extents_tree(&[index_record(fixture::extent_key(0, 5, 0), 2)], &[extent_record(5), short]);
+        assert!(tree.find_overflow_extents(5, ForkType::Data, 0).unwrap().is_some());
+        let error = node_error(tree.find_overflow_extents(7, ForkType::Data, 0));
+        assert_eq!(error.node_number, 2);
+        assert_eq!(error.kind, NodeErrorKind::InvalidData { record: 1 });
+        let data_start = 14 + extent_record(5).len() + fixture::extent_key(0, 7, 0).len();
+        assert_eq!(error.byte_offset_in_fork, 2 * NODE_SIZE as u64 + data_start as u64);
+    }
+
+    #[test]
+    fn malformed_thread_records_name_the_node_and_record() {
+        let fields = fixture::BTreeHeaderFields {
+            depth: 1, root: 1, first_leaf: 1, last_leaf: 1, total_nodes: 2, max_key_length: 516,
+            ..Default::default()
+        };
+        // The name length claims more than the record holds.
+        let mut thread = fixture::catalog_thread(2, "name");
+        thread.truncate(thread.len() - 2);
+        let records = [fixture::record(fixture::catalog_key(16, ""), &fixture::catalog_thread(2, "good")),
+                       fixture::record(fixture::catalog_key(17, ""), &thread)];
+        let mut data = fixture::btree_header_node(NODE_SIZE, &fields);
+        data.extend(fixture::btree_node(NODE_SIZE, NODE_KIND_LEAF, LEAF_HEIGHT, 0, &records));
+        let mut tree: CatalogTree<_> = BTree::new(Cursor::new(data));
+        assert_eq!(tree.find_thread_name(16).unwrap(), "good");
+        let error = node_error(tree.find_thread_name(17));
+        assert_eq!(error.node_number, 1);
+        assert_eq!(error.kind, NodeErrorKind::InvalidData { record: 1 });
+        let data_start = 14 + records[0].len() + fixture::catalog_key(17, "").len();
+        assert_eq!(error.byte_offset_in_fork, NODE_SIZE as u64 + data_start as u64);
+    }
+}
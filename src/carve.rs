@@ -0,0 +1,475 @@
+use ddrescue::RegionMap;
+use error::HFSPError;
+use fs;
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom};
+
+const DEFAULT_WINDOW_BLOCKS: u64 = 256;
+const MAX_PNG_CHUNKS: usize = 1 << 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LengthEstimate {
+    Exact(u64),
+    AtMost(u64),
+    Unknown,
+}
+
+pub trait Signature {
+    fn name(&self) -> &str;
+
+    // The number of bytes match_header() needs to see. Near the end of the
+    // source it may be given fewer.
+    fn header_length(&self) -> usize;
+
+    fn match_header(&self, data: &[u8]) -> Option<Confidence>;
+
+    // Called with the reader positioned at the start of a match.
+    fn estimate_length(&self, _reader: &mut dyn fs::Source) -> io::Result<LengthEstimate> {
+        Ok(LengthEstimate::Unknown)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CarvedCandidate {
+    pub offset: u64,
+    pub estimated_len: LengthEstimate,
+    pub signature_name: String,
+    pub confidence: Confidence,
+}
+
+pub struct Carver {
+    signatures: Vec<Box<dyn Signature>>,
+    alignment: u64,
+    window_size: u64,
+}
+
+impl Carver {
+    // Matches are looked for at multiples of the alignment, which must be
+    // non-zero.
+    pub fn new(alignment: u64) -> fs::Result<Carver> {
+        if alignment == 0 {
+            return Err(HFSPError::InvalidArgument("carving alignment must be non-zero"));
+        }
+        let result = Carver {
+            signatures: Vec::new(),
+            alignment,
+            window_size: alignment.saturating_mul(DEFAULT_WINDOW_BLOCKS),
+        };
+        Ok(result)
+    }
+
+    pub fn with_builtin_signatures(alignment: u64) -> fs::Result<Carver> {
+        let mut result = Carver::new(alignment)?;
+        for signature in builtin_signatures() {
+            result.add_signature(signature);
+        }
+        Ok(result)
+    }
+
+    pub fn add_signature(&mut self, signature: Box<dyn Signature>) {
+        self.signatures.push(signature);
+    }
+
+    // Sets how many aligned blocks are read from the source at a time.
+    pub fn set_window_blocks(&mut self, blocks: u64) -> fs::Result<()> {
+        if blocks == 0 {
+            return Err(HFSPError::InvalidArgument("carving window must be at least one block"));
+        }
+        self.window_size = self.alignment.checked_mul(blocks)
+            .ok_or(HFSPError::InvalidArgument("carving window is too large"))?;
+        Ok(())
+    }
+
+    // Looks for signature matches at every aligned offset inside the regions
+    // of the map, regardless of their status. Nothing is written anywhere.
+    pub fn scan<S>(&self, source: &mut S, regions: &RegionMap) -> fs::Result<Vec<CarvedCandidate>> where S: Read + Seek {
+        let overlap = self.signatures.iter().map(|s| s.header_length()).max().unwrap_or(0) as u64;
+        let mut matches = Vec::new();
+        let mut buffer = Vec::new();
+        for region in regions.regions() {
+            let mut window_start = align_up(region.offset, self.alignment);
+            while window_start < region.end() {
+                let window_end = cmp::min(window_start.saturating_add(self.window_size), region.end());
+                // Read past the end of the window so headers that straddle it
+                // are seen whole.
+                buffer.resize((window_end - window_start + overlap) as usize, 0);
+                source.seek(SeekFrom::Start(window_start))?;
+                let available = fs::read_fully(source, &mut buffer)?;
+                let mut offset = window_start;
+                while offset < window_end {
+                    let index = (offset - window_start) as usize;
+                    if index >= available {
+                        break;
+                    }
+                    let data = &buffer[index..available];
+                    for (signature_index, signature) in self.signatures.iter().enumerate() {
+                        if let Some(confidence) = signature.match_header(data) {
+                            matches.push((offset, signature_index, confidence));
+                        }
+                    }
+                    offset += self.alignment;
+                }
+                window_start = window_end;
+            }
+        }
+
+        let mut result = Vec::with_capacity(matches.len());
+        for (offset, signature_index, confidence) in matches {
+            let signature = &self.signatures[signature_index];
+            source.seek(SeekFrom::Start(offset))?;
+            // A failed estimate shouldn't lose the candidate.
+            let estimated_len = signature.estimate_length(source).unwrap_or(LengthEstimate::Unknown);
+            result.push(CarvedCandidate {
+                offset,
+                estimated_len,
+                signature_name: signature.name().to_string(),
+                confidence,
+            });
+        }
+        Ok(result)
+    }
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    match value % alignment {
+        0 => value,
+        remainder => value + (alignment - remainder),
+    }
+}
+
+fn read_array<R: Read + ?Sized>(reader: &mut R, length: usize) -> io::Result<Vec<u8>> {
+    let mut buffer = vec![0; length];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+fn be_u32(data: &[u8]) -> u32 {
+    (data[0] as u32) << 24 | (data[1] as u32) << 16 | (data[2] as u32) << 8 | data[3] as u32
+}
+
+fn le_u32(data: &[u8]) -> u32 {
+    (data[3] as u32) << 24 | (data[2] as u32) << 16 | (data[1] as u32) << 8 | data[0] as u32
+}
+
+pub struct MagicSignature {
+    name: &'static str,
+    offset: usize,
+    magic: &'static [u8],
+    confidence: Confidence,
+}
+
+impl MagicSignature {
+    pub fn new(name: &'static str, offset: usize, magic: &'static [u8], confidence: Confidence) -> MagicSignature {
+        MagicSignature {
+            name,
+            offset,
+            magic,
+            confidence,
+        }
+    }
+}
+
+impl Signature for MagicSignature {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn header_length(&self) -> usize {
+        self.offset + self.magic.len()
+    }
+
+    fn match_header(&self, data: &[u8]) -> Option<Confidence> {
+        if data.len() >= self.header_length() && &data[self.offset..self.header_length()] == self.magic {
+            Some(self.confidence)
+        } else {
+            None
+        }
+    }
+}
+
+struct PngSignature;
+
+impl Signature for PngSignature {
+    fn name(&self) -> &str {
+        "png"
+    }
+
+    fn header_length(&self) -> usize {
+        16
+    }
+
+    fn match_header(&self, data: &[u8]) -> Option<Confidence> {
+        if data.starts_with(b"\x89PNG\r\n\x1a\n") && data.len() >= 16 && &data[12..16] == b"IHDR" {
+            Some(Confidence::High)
+        } else {
+            None
+        }
+    }
+
+    fn estimate_length(&self, reader: &mut dyn fs::Source) -> io::Result<LengthEstimate> {
+        let mut length = 8;
+        reader.seek(SeekFrom::Current(8))?;
+        for _ in 0..MAX_PNG_CHUNKS {
+            let header = read_array(reader, 8)?;
+            let data_length = be_u32(&header[0..4]) as u64;
+            length += 12 + data_length;
+            if &header[4..8] == b"IEND" {
+                return Ok(LengthEstimate::Exact(length));
+            }
+            reader.seek(SeekFrom::Current(data_length as i64 + 4))?;
+        }
+        Ok(LengthEstimate::Unknown)
+    }
+}
+
+struct BmpSignature;
+
+impl Signature for BmpSignature {
+    fn name(&self) -> &str {
+        "bmp"
+    }
+
+    fn header_length(&self) -> usize {
+        18
+    }
+
+    // Two magic bytes match far too much on their own, so also require the
+    // reserved fields to be zero and a known DIB header size.
+    fn match_header(&self, data: &[u8]) -> Option<Confidence> {
+        if data.len() < self.header_length() || !data.starts_with(b"BM") || data[6..10] != [0, 0, 0, 0] {
+            return None;
+        }
+        match le_u32(&data[14..18]) {
+            12 | 40 | 52 | 56 | 64 | 108 | 124 => Some(Confidence::Medium),
+            _ => None,
+        }
+    }
+
+    fn estimate_length(&self, reader: &mut dyn fs::Source) -> io::Result<LengthEstimate> {
+        let header = read_array(reader, 6)?;
+        Ok(LengthEstimate::Exact(le_u32(&header[2..6]) as u64))
+    }
+}
+
+struct RiffSignature;
+
+impl Signature for RiffSignature {
+    fn name(&self) -> &str {
+        "riff"
+    }
+
+    fn header_length(&self) -> usize {
+        12
+    }
+
+    fn match_header(&self, data: &[u8]) -> Option<Confidence> {
+        if data.len() < self.header_length() || !data.starts_with(b"RIFF") {
+            return None;
+        }
+        match &data[8..12] {
+            b"WAVE" | b"AVI " | b"WEBP" => Some(Confidence::High),
+            _ => Some(Confidence::Low),
+        }
+    }
+
+    fn estimate_length(&self, reader: &mut dyn fs::Source) -> io::Result<LengthEstimate> {
+        let header = read_array(reader, 8)?;
+        Ok(LengthEstimate::Exact(le_u32(&header[4..8]) as u64 + 8))
+    }
+}
+
+struct SqliteSignature;
+
+impl Signature for SqliteSignature {
+    fn name(&self) -> &str {
+        "sqlite"
+    }
+
+    fn header_length(&self) -> usize {
+        16
+    }
+
+    fn match_header(&self, data: &[u8]) -> Option<Confidence> {
+        if data.starts_with(b"SQLite format 3\0") {
+            Some(Confidence::High)
+        } else {
+            None
+        }
+    }
+
+    fn estimate_length(&self, reader: &mut dyn fs::Source) -> io::Result<LengthEstimate> {
+        let header = read_array(reader, 32)?;
+        let page_size = match (header[16] as u64) << 8 | header[17] as u64 {
+            1 => 65536,
+            size => size,
+        };
+        let page_count = be_u32(&header[28..32]) as u64;
+        if page_count == 0 {
+            Ok(LengthEstimate::Unknown)
+        } else {
+            Ok(LengthEstimate::Exact(page_size * page_count))
+        }
+    }
+}
+
+struct Mp4Signature;
+
+impl Signature for Mp4Signature {
+    fn name(&self) -> &str {
+        "mp4"
+    }
+
+    fn header_length(&self) -> usize {
+        8
+    }
+
+    fn match_header(&self, data: &[u8]) -> Option<Confidence> {
+        if data.len() >= 8 && &data[4..8] == b"ftyp" && be_u32(&data[0..4]) >= 8 {
+            Some(Confidence::Medium)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn builtin_signatures() -> Vec<Box<dyn Signature>> {
+    vec![
+        Box::new(MagicSignature::new("jpeg", 0, b"\xff\xd8\xff", Confidence::Medium)),
+        Box::new(PngSignature),
+        Box::new(MagicSignature::new("gif", 0, b"GIF87a", Confidence::High)),
+        Box::new(MagicSignature::new("gif", 0, b"GIF89a", Confidence::High)),
+        Box::new(MagicSignature::new("pdf", 0, b"%PDF-", Confidence::High)),
+        Box::new(MagicSignature::new("zip", 0, b"PK\x03\x04", Confidence::Medium)),
+        Box::new(MagicSignature::new("gzip", 0, b"\x1f\x8b\x08", Confidence::Medium)),
+        Box::new(MagicSignature::new("bzip2", 4, b"\x31\x41\x59\x26\x53\x59", Confidence::High)),
+        Box::new(MagicSignature::new("7z", 0, b"7z\xbc\xaf\x27\x1c", Confidence::High)),
+        Box::new(MagicSignature::new("tiff", 0, b"II*\0", Confidence::Low)),
+        Box::new(MagicSignature::new("tiff", 0, b"MM\0*", Confidence::Low)),
+        Box::new(BmpSignature),
+        Box::new(RiffSignature),
+        Box::new(Mp4Signature),
+        Box::new(SqliteSignature),
+        Box::new(MagicSignature::new("ole2", 0, b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1", Confidence::High)),
+        Box::new(MagicSignature::new("mp3", 0, b"ID3", Confidence::Low)),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use ddrescue::{BlockStatus, RegionMap};
+    use std::io::Cursor;
+    use super::*;
+
+    const BLOCK_SIZE: u64 = 512;
+
+    // Filler that none of the built-in signatures match.
+    fn free_space(length: usize) -> Vec<u8> {
+        (0..length).map(|i| b"free space "[i % 11]).collect()
+    }
+
+    fn plant(image: &mut [u8], offset: u64, data: &[u8]) {
+        image[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+    }
+
+    fn png() -> Vec<u8> {
+        let mut result = b"\x89PNG\r\n\x1a\n".to_vec();
+        for &(kind, length) in &[(b"IHDR", 13u32), (b"IDAT", 40), (b"IEND", 0)] {
+            result.extend_from_slice(&length.to_be_bytes());
+            result.extend_from_slice(kind);
+            result.extend(vec![0x55; length as usize + 4]);
+        }
+        result
+    }
+
+    fn sqlite(pages: u32) -> Vec<u8> {
+        let mut result = b"SQLite format 3\0".to_vec();
+        result.extend_from_slice(&[0x04, 0x00]);
+        result.extend(vec![0; 10]);
+        result.extend_from_slice(&pages.to_be_bytes());
+        result
+    }
+
+    fn free_map(ranges: &[(u64, u64)]) -> RegionMap {
+        let mut result = RegionMap::new();
+        for &(offset, length) in ranges {
+            result.insert(offset, length, BlockStatus::Finished);
+        }
+        result
+    }
+
+    fn found(candidates: &[CarvedCandidate]) -> Vec<(u64, &str, LengthEstimate)> {
+        candidates.iter().map(|c| (c.offset, &c.signature_name[..], c.estimated_len)).collect()
+    }
+
+    #[test]
+    fn finds_files_planted_in_free_space() {
+        let mut image = free_space(64 * BLOCK_SIZE as usize);
+        let png = png();
+        plant(&mut image, 3 * BLOCK_SIZE, &png);
+        plant(&mut image, 20 * BLOCK_SIZE, b"GIF89a\x01\x00");
+        plant(&mut image, 40 * BLOCK_SIZE, &sqlite(3));
+        // In use, so outside the map, and unaligned.
+        plant(&mut image, 10 * BLOCK_SIZE, b"%PDF-1.4");
+        plant(&mut image, 30 * BLOCK_SIZE + 7, b"%PDF-1.4");
+        let regions = free_map(&[(0, 8 * BLOCK_SIZE), (16 * BLOCK_SIZE, 48 * BLOCK_SIZE)]);
+        let carver = Carver::with_builtin_signatures(BLOCK_SIZE).unwrap();
+        let candidates = carver.scan(&mut Cursor::new(image), &regions).unwrap();
+        assert_eq!(found(&candidates), vec![
+            (3 * BLOCK_SIZE, "png", LengthEstimate::Exact(png.len() as u64)),
+            (20 * BLOCK_SIZE, "gif", LengthEstimate::Unknown),
+            (40 * BLOCK_SIZE, "sqlite", LengthEstimate::Exact(3 * 1024)),
+        ]);
+        assert_eq!(candidates[0].confidence, Confidence::High);
+    }
+
+    #[test]
+    fn finds_headers_straddling_windows() {
+        // Windows are 16 bytes from offset 8, so the PNG and SQLite headers
+        // start half way through one and end in the next.
+        let mut image = free_space(4096);
+        plant(&mut image, 1008, &png());
+        plant(&mut image, 2048, &sqlite(1));
+        plant(&mut image, 4088, b"PK\x03\x04");
+        let mut carver = Carver::with_builtin_signatures(8).unwrap();
+        carver.set_window_blocks(2).unwrap();
+        let candidates = carver.scan(&mut Cursor::new(image), &free_map(&[(3, 4093)])).unwrap();
+        let offsets: Vec<(u64, &str)> = candidates.iter().map(|c| (c.offset, &c.signature_name[..])).collect();
+        assert_eq!(offsets, vec![(1008, "png"), (2048, "sqlite"), (4088, "zip")]);
+    }
+
+    #[test]
+    fn header_may_cross_region_end() {
+        let mut image = free_space(8 * BLOCK_SIZE as usize);
+        plant(&mut image, 2 * BLOCK_SIZE, &png());
+        let mut carver = Carver::with_builtin_signatures(BLOCK_SIZE).unwrap();
+        carver.set_window_blocks(1).unwrap();
+        let candidates = carver.scan(&mut Cursor::new(image), &free_map(&[(2 * BLOCK_SIZE, 4)])).unwrap();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].offset, 2 * BLOCK_SIZE);
+    }
+
+    #[test]
+    fn custom_signatures_are_used() {
+        let mut image = free_space(4 * BLOCK_SIZE as usize);
+        plant(&mut image, BLOCK_SIZE + 100, b"MAGIC");
+        let mut carver = Carver::new(BLOCK_SIZE).unwrap();
+        carver.add_signature(Box::new(MagicSignature::new("custom", 100, b"MAGIC", Confidence::Low)));
+        let candidates = carver.scan(&mut Cursor::new(image), &free_map(&[(0, 4 * BLOCK_SIZE)])).unwrap();
+        assert_eq!(found(&candidates), vec![(BLOCK_SIZE, "custom", LengthEstimate::Unknown)]);
+    }
+
+    #[test]
+    fn rejects_empty_alignment_and_window() {
+        assert!(matches!(Carver::new(0), Err(HFSPError::InvalidArgument(_))));
+        let mut carver = Carver::new(BLOCK_SIZE).unwrap();
+        assert!(matches!(carver.set_window_blocks(0), Err(HFSPError::InvalidArgument(_))));
+        assert!(matches!(carver.set_window_blocks(u64::MAX), Err(HFSPError::InvalidArgument(_))));
+    }
+}
@@ -0,0 +1,188 @@
+use btree::decode_utf16;
+use filesystem::{FileSystem, HFSFile, SIZE_FORK_DATA};
+use fs;
+use ownership::ForkType;
+use snapshot::{be_u16, be_u32, ForkDataSnapshot};
+use std::io::{Read, Seek};
+
+pub const CATALOG_FOLDER_RECORD: u16 = 1;
+pub const CATALOG_FILE_RECORD: u16 = 2;
+pub const CATALOG_FOLDER_THREAD: u16 = 3;
+const OFFSET_CATALOG_FOLDER_ID: usize = 8;
+const OFFSET_CATALOG_FILE_ID: usize = 8;
+const OFFSET_CATALOG_FILE_DATA_FORK: usize = 88;
+const OFFSET_CATALOG_FILE_RESOURCE_FORK: usize = 168;
+
+// A file's entry in the catalog, keyed by its parent folder and name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct FileRecord {
+    pub file_id: u32,
+    pub parent_id: u32,
+    pub name: String,
+    data_fork: ForkDataSnapshot,
+    resource_fork: ForkDataSnapshot,
+}
+
+impl FileRecord {
+    // Parses the key and data of a catalog leaf record, returning None if it
+    // isn't a well-formed file record.
+    pub fn from_record(key: &[u8], data: &[u8]) -> Option<FileRecord> {
+        if data.len() < OFFSET_CATALOG_FILE_RESOURCE_FORK + SIZE_FORK_DATA as usize ||
+            be_u16(data, 0) != CATALOG_FILE_RECORD {
+            return None;
+        }
+        let (parent_id, name) = decode_catalog_key(key)?;
+        let fork_at = |offset: usize| ForkDataSnapshot::from_bytes(&data[offset..offset + SIZE_FORK_DATA as usize]);
+        let result = FileRecord {
+            file_id: be_u32(data, OFFSET_CATALOG_FILE_ID),
+            parent_id,
+            name,
+            data_fork: fork_at(OFFSET_CATALOG_FILE_DATA_FORK),
+            resource_fork: fork_at(OFFSET_CATALOG_FILE_RESOURCE_FORK),
+        };
+        Some(result)
+    }
+
+    pub fn data_fork(&self) -> &ForkDataSnapshot {
+        &self.data_fork
+    }
+
+    pub fn resource_fork(&self) -> &ForkDataSnapshot {
+        &self.resource_fork
+    }
+
+    pub fn get_fork(&self, fork_type: ForkType) -> &ForkDataSnapshot {
+        match fork_type {
+            ForkType::Data => &self.data_fork,
+            ForkType::Resource => &self.resource_fork,
+        }
+    }
+
+    pub fn has_resource_fork(&self) -> bool {
+        self.resource_fork.logical_size != 0
+    }
+
+    pub fn open_fork<'a, F>(&self, parent: &'a FileSystem<F>, fork_type: ForkType) -> fs::Result<HFSFile<'a, F>>
+        where F: Read + Seek {
+        HFSFile::from_snapshot(parent, self.get_fork(fork_type), self.file_id, fork_type)
+    }
+
+    pub fn open_fork_permissive<'a, F>(&self, parent: &'a FileSystem<F>, fork_type: ForkType)
+        -> fs::Result<HFSFile<'a, F>> where F: Read + Seek {
+        HFSFile::from_snapshot_permissive(parent, self.get_fork(fork_type), self.file_id, fork_type)
+    }
+}
+
+// Returns the ID of the folder a catalog folder record describes.
+pub fn decode_folder_id(data: &[u8]) -> Option<u32> {
+    if data.len() < OFFSET_CATALOG_FOLDER_ID + 4 || be_u16(data, 0) != CATALOG_FOLDER_RECORD {
+        return None;
+    }
+    Some(be_u32(data, OFFSET_CATALOG_FOLDER_ID))
+}
+
+pub fn decode_thread_name(data: &[u8]) -> Option<String> {
+    if data.len() < 10 || be_u16(data, 0) != CATALOG_FOLDER_THREAD {
+        return None;
+    }
+    decode_name(data, 8)
+}
+
+// Decodes a length-prefixed UTF-16 name.
+fn decode_name(data: &[u8], offset: usize) -> Option<String> {
+    if data.len() < offset + 2 {
+        return None;
+    }
+    Some(String::from_utf16_lossy(&decode_utf16(data, offset)?))
+}
+
+// Returns the parent ID and name from a catalog key.
+pub fn decode_catalog_key(key: &[u8]) -> Option<(u32, String)> {
+    if key.len() < 4 {
+        return None;
+    }
+    Some((be_u32(key, 0), decode_name(key, 4)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use error::HFSPError;
+    use filesystem::FileSystem;
+    use fixture::{self, VolumeBuilder};
+    use snapshot::SpecialFile;
+    use super::*;
+
+    const BLOCK_SIZE: u32 = 512;
+
+    #[test]
+    fn file_records_are_decoded_from_their_offsets() {
+        let data_fork = fixture::fork_data(1000, &[(10, 2)]);
+        let data = fixture::catalog_file(42, &data_fork, &fixture::fork_data(300, &[(20, 1), (30, 1)]));
+        // The key passed in is the one after its length prefix.
+        let key = fixture::catalog_key(16, "Read Me.txt");
+        let record = FileRecord::from_record(&key[2..], &data).unwrap();
+        assert_eq!(record.file_id, 42);
+        assert_eq!(record.parent_id, 16);
+        assert_eq!(record.name, "Read Me.txt");
+        assert_eq!(record.data_fork().logical_size, 1000);
+        assert_eq!(record.data_fork().extents[0], (10, 2));
+        assert_eq!(record.get_fork(ForkType::Resource).total_blocks, 2);
+        assert_eq!(record.resource_fork().extents[..2], [(20, 1), (30, 1)]);
+        assert!(record.has_resource_fork());
+        let no_resource = fixture::catalog_file(43, &data_fork, &fixture::fork_data(0, &[]));
+        assert!(!FileRecord::from_record(&key[2..], &no_resource).unwrap().has_resource_fork());
+    }
+
+    #[test]
+    fn malformed_file_records_are_skipped() {
+        let data = fixture::catalog_file(42, &fixture::fork_data(1000, &[(10, 2)]), &fixture::fork_data(0, &[]));
+        let key = fixture::catalog_key(16, "name");
+        assert!(FileRecord::from_record(&key[2..], &data[..data.len() - 1]).is_none());
+        assert!(FileRecord::from_record(&key[2..key.len() - 1], &data).is_none());
+        assert!(FileRecord::from_record(&key[2..5], &data).is_none());
+        let mut folder = data.clone();
+        fixture::put_u16(&mut folder, 0, CATALOG_FOLDER_RECORD);
+        assert!(FileRecord::from_record(&key[2..], &folder).is_none());
+    }
+
+    #[test]
+    fn folder_and_thread_records_are_decoded() {
+        assert_eq!(decode_folder_id(&fixture::catalog_folder(17)), Some(17));
+        assert_eq!(decode_folder_id(&fixture::catalog_folder(17)[..11]), None);
+        assert_eq!(decode_folder_id(&fixture::catalog_thread(1, "name")), None);
+        assert_eq!(decode_thread_name(&fixture::catalog_thread(1, "Disk \u{e9}")), Some("Disk \u{e9}".to_string()));
+        assert_eq!(decode_thread_name(&fixture::catalog_thread(1, "")), Some(String::new()));
+        let thread = fixture::catalog_thread(1, "name");
+        assert_eq!(decode_thread_name(&thread[..thread.len() - 1]), None);
+        assert_eq!(decode_thread_name(&fixture::catalog_folder(17)), None);
+        let key = fixture::catalog_key(5, "Documents");
+        assert_eq!(decode_catalog_key(&key[2..]), Some((5, "Documents".to_string())));
+        assert_eq!(decode_catalog_key(&key[2..5]), None);
+    }
+
+    #[test]
+    fn volume_names_come_from_the_root_thread_record() {
+        // A catalog of a header node and a single leaf holding the root
+        // folder and its thread record, in key order.
+        let fields = fixture::BTreeHeaderFields {
+            depth: 1, root: 1, first_leaf: 1, last_leaf: 1, total_nodes: 2, max_key_length: 516,
+            attributes: 6, ..Default::default()
+        };
+        let mut catalog = fixture::btree_header_node(BLOCK_SIZE as usize, &fields);
+        catalog.extend(fixture::btree_node(BLOCK_SIZE as usize, -1, 1, 0, &[
+            fixture::record(fixture::catalog_key(1, "Backup Disk"), &fixture::catalog_folder(2)),
+            fixture::record(fixture::catalog_key(2, ""), &fixture::catalog_thread(1, "Backup Disk")),
+        ]));
+        let extents = [(10, 2)];
+        let image = VolumeBuilder::new(BLOCK_SIZE, 64)
+            .set_fork(SpecialFile::Catalog, catalog.len() as u64, &extents)
+            .write_fork(&extents, &catalog)
+            .build();
+        let filesystem = FileSystem::from_bytes(image);
+        let header = filesystem.get_volume_header().unwrap();
+        assert_eq!(header.get_volume_name().unwrap(), "Backup Disk");
+        assert!(matches!(header.get_btree_catalog().unwrap().find_thread_name(3),
+                         Err(HFSPError::MissingCatalogRecord)));
+    }
+}
@@ -0,0 +1,265 @@
+use filesystem;
+use snapshot::{ForkDataSnapshot, SpecialFile, VolumeHeaderSnapshot, NUM_EXTENT_DESCRIPTORS, SPECIAL_FILES};
+use std::fmt::{self, Display, Formatter};
+
+const IMPLAUSIBLE_COUNT: u32 = 0xffffffff;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum ConsistencyIssue {
+    InvalidBlockSize(u32),
+    VolumeExceedsPartition { volume_bytes: u64, partition_bytes: u64 },
+    FreeBlocksExceedTotal { free_blocks: u32, total_blocks: u32 },
+    AllocationFileTooSmall { logical_size: u64, required: u64 },
+    ForkBlockCountMismatch { file: SpecialFile, total_blocks: u32, extent_blocks: u64 },
+    ImplausibleFileCount(u32),
+    ImplausibleFolderCount(u32),
+}
+
+impl Display for ConsistencyIssue {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match *self {
+            ConsistencyIssue::InvalidBlockSize(size) => write!(fmt, "Invalid block size {}", size),
+            ConsistencyIssue::VolumeExceedsPartition { volume_bytes, partition_bytes } =>
+                write!(fmt, "Volume size {} exceeds partition size {}", volume_bytes, partition_bytes),
+            ConsistencyIssue::FreeBlocksExceedTotal { free_blocks, total_blocks } =>
+                write!(fmt, "Free blocks {} exceed total blocks {}", free_blocks, total_blocks),
+            ConsistencyIssue::AllocationFileTooSmall { logical_size, required } =>
+                write!(fmt, "Allocation file is {} bytes but at least {} are needed", logical_size, required),
+            ConsistencyIssue::ForkBlockCountMismatch { file, total_blocks, extent_blocks } =>
+                write!(fmt, "The {} file claims {} blocks but its extents cover {}", file.get_name(), total_blocks,
+                       extent_blocks),
+            ConsistencyIssue::ImplausibleFileCount(count) => write!(fmt, "Implausible file count {}", count),
+            ConsistencyIssue::ImplausibleFolderCount(count) => write!(fmt, "Implausible folder count {}", count),
+        }
+    }
+}
+
+pub fn check_header(header: &VolumeHeaderSnapshot, partition_bytes: u64) -> Vec<ConsistencyIssue> {
+    let mut issues = Vec::new();
+    if !filesystem::is_valid_block_size(header.block_size) {
+        issues.push(ConsistencyIssue::InvalidBlockSize(header.block_size));
+    }
+    let volume_bytes = header.total_blocks as u64 * header.block_size as u64;
+    if volume_bytes > partition_bytes {
+        issues.push(ConsistencyIssue::VolumeExceedsPartition { volume_bytes, partition_bytes });
+    }
+    if header.free_blocks > header.total_blocks {
+        issues.push(ConsistencyIssue::FreeBlocksExceedTotal {
+            free_blocks: header.free_blocks,
+            total_blocks: header.total_blocks,
+        });
+    }
+    let required = (header.total_blocks as u64).div_ceil(8);
+    if header.allocation_file.logical_size < required {
+        issues.push(ConsistencyIssue::AllocationFileTooSmall {
+            logical_size: header.allocation_file.logical_size,
+            required,
+        });
+    }
+    for &file in SPECIAL_FILES.iter() {
+        let fork = header.get_special_file(file);
+        let extent_blocks: u64 = fork.extents.iter().map(|&(_, count)| count as u64).sum();
+        // With every inline extent in use the remainder may legitimately live
+        // in the extents overflow file.
+        let may_overflow = fork.extents[NUM_EXTENT_DESCRIPTORS - 1].1 != 0;
+        let total_blocks = fork.total_blocks as u64;
+        if extent_blocks > total_blocks || (extent_blocks < total_blocks && !may_overflow) {
+            issues.push(ConsistencyIssue::ForkBlockCountMismatch {
+                file,
+                total_blocks: fork.total_blocks,
+                extent_blocks,
+            });
+        }
+    }
+    if header.file_count == IMPLAUSIBLE_COUNT {
+        issues.push(ConsistencyIssue::ImplausibleFileCount(header.file_count));
+    }
+    if header.folder_count == IMPLAUSIBLE_COUNT {
+        issues.push(ConsistencyIssue::ImplausibleFolderCount(header.folder_count));
+    }
+    issues
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum ExtentIssue {
+    OutsideVolume { index: usize, start: u32, count: u32 },
+    Overlap { first: usize, second: usize },
+}
+
+impl Display for ExtentIssue {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match *self {
+            ExtentIssue::OutsideVolume { index, start, count } =>
+                write!(fmt, "Extent {} ({} blocks from block {}) lies outside the volume", index, count, start),
+            ExtentIssue::Overlap { first, second } => write!(fmt, "Extents {} and {} overlap", first, second),
+        }
+    }
+}
+
+// Checks the in-use extents, which end at the first empty descriptor.
+pub fn check_extents(extents: &[(u32, u32)], total_blocks: u32) -> Vec<ExtentIssue> {
+    let used: Vec<(u64, u64)> = extents.iter()
+        .take_while(|&&(_, count)| count != 0)
+        .map(|&(start, count)| (start as u64, start as u64 + count as u64))
+        .collect();
+    let mut issues = Vec::new();
+    for (index, &(start, end)) in used.iter().enumerate() {
+        if end > total_blocks as u64 {
+            issues.push(ExtentIssue::OutsideVolume { index, start: start as u32, count: (end - start) as u32 });
+        }
+    }
+    for first in 0..used.len() {
+        for second in first + 1..used.len() {
+            if used[first].0 < used[second].1 && used[second].0 < used[first].1 {
+                issues.push(ExtentIssue::Overlap { first, second });
+            }
+        }
+    }
+    issues
+}
+
+// Compares a fork's logical size with the space allocated to it. Slack of
+// less than one block is normal and not reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum ForkConsistency {
+    Consistent,
+    Truncated { logical_size: u64, allocated_bytes: u64 },
+    Slack { logical_size: u64, allocated_bytes: u64 },
+}
+
+impl ForkConsistency {
+    // Bytes of the logical size with no allocation behind them.
+    pub fn missing_bytes(&self) -> u64 {
+        match *self {
+            ForkConsistency::Truncated { logical_size, allocated_bytes } => logical_size - allocated_bytes,
+            _ => 0,
+        }
+    }
+
+    // Allocated bytes past the logical end of the fork, which may hold
+    // stale data worth carving.
+    pub fn slack_bytes(&self) -> u64 {
+        match *self {
+            ForkConsistency::Slack { logical_size, allocated_bytes } => allocated_bytes - logical_size,
+            _ => 0,
+        }
+    }
+}
+
+impl Display for ForkConsistency {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match *self {
+            ForkConsistency::Consistent => write!(fmt, "Consistent"),
+            ForkConsistency::Truncated { logical_size, allocated_bytes } =>
+                write!(fmt, "Allocation of {} bytes is smaller than the logical size of {} (data will be truncated)",
+                       allocated_bytes, logical_size),
+            ForkConsistency::Slack { logical_size, allocated_bytes } =>
+                write!(fmt, "Allocation of {} bytes is larger than the logical size of {} ({} bytes of slack)",
+                       allocated_bytes, logical_size, self.slack_bytes()),
+        }
+    }
+}
+
+pub fn check_fork(fork: &ForkDataSnapshot, block_size: u32) -> ForkConsistency {
+    let logical_size = fork.logical_size;
+    let allocated_bytes = fork.total_blocks as u64 * block_size as u64;
+    if allocated_bytes < logical_size {
+        ForkConsistency::Truncated { logical_size, allocated_bytes }
+    } else if allocated_bytes - logical_size >= block_size as u64 {
+        ForkConsistency::Slack { logical_size, allocated_bytes }
+    } else {
+        ForkConsistency::Consistent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use filesystem::FileSystem;
+    use fixture::{self, VolumeBuilder};
+    use super::*;
+
+    const BLOCK_SIZE: u32 = 512;
+    const TOTAL_BLOCKS: u32 = 64;
+
+    // A volume whose header has no issues until patched.
+    fn volume() -> VolumeBuilder {
+        let mut volume = VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS);
+        volume.set_fork(SpecialFile::Allocation, BLOCK_SIZE as u64, &[(1, 1)]);
+        volume
+    }
+
+    fn header_issues(volume: &mut VolumeBuilder) -> Vec<ConsistencyIssue> {
+        let filesystem = FileSystem::from_bytes(volume.build());
+        let issues = filesystem.get_volume_header_unchecked().check_consistency().unwrap();
+        issues
+    }
+
+    #[test]
+    fn consistent_headers_have_no_issues() {
+        assert_eq!(header_issues(&mut volume()), []);
+    }
+
+    #[test]
+    fn free_blocks_above_total_blocks_are_reported() {
+        let mut volume = volume();
+        fixture::put_u32(volume.header(), 48, TOTAL_BLOCKS + 1);
+        let issues = header_issues(&mut volume);
+        assert_eq!(issues, [ConsistencyIssue::FreeBlocksExceedTotal { free_blocks: 65, total_blocks: 64 }]);
+        assert_eq!(issues[0].to_string(), "Free blocks 65 exceed total blocks 64");
+        fixture::put_u32(volume.header(), 48, TOTAL_BLOCKS);
+        assert_eq!(header_issues(&mut volume), []);
+    }
+
+    #[test]
+    fn overlapping_extents_are_reported() {
+        let extents = [(10, 4), (20, 2), (12, 4), (0, 0), (11, 1)];
+        assert_eq!(check_extents(&extents, TOTAL_BLOCKS), [ExtentIssue::Overlap { first: 0, second: 2 }]);
+        assert_eq!(check_extents(&[(10, 4), (14, 4)], TOTAL_BLOCKS), []);
+    }
+
+    #[test]
+    fn extents_past_the_end_of_the_volume_are_reported() {
+        let extents = [(10, 2), (TOTAL_BLOCKS - 4, 2), (TOTAL_BLOCKS - 1, 2)];
+        let issues = check_extents(&extents, TOTAL_BLOCKS);
+        assert_eq!(issues, [ExtentIssue::OutsideVolume { index: 2, start: TOTAL_BLOCKS - 1, count: 2 }]);
+        assert_eq!(issues[0].to_string(), "Extent 2 (2 blocks from block 63) lies outside the volume");
+
+        let mut volume = volume();
+        volume.set_fork(SpecialFile::Catalog, 4 * BLOCK_SIZE as u64, &extents);
+        let filesystem = FileSystem::from_bytes(volume.build());
+        let header = filesystem.get_volume_header_unchecked();
+        assert_eq!(header.get_fork_data_catalog().validate(TOTAL_BLOCKS).unwrap(), issues);
+    }
+
+    #[test]
+    fn logical_sizes_beyond_the_allocation_are_truncated() {
+        let mut volume = volume();
+        volume.set_fork(SpecialFile::Catalog, 3 * BLOCK_SIZE as u64 + 1, &[(10, 3)]);
+        let filesystem = FileSystem::from_bytes(volume.build());
+        let header = filesystem.get_volume_header_unchecked();
+        let consistency = header.get_fork_data_catalog().check_consistency(BLOCK_SIZE).unwrap();
+        assert_eq!(consistency, ForkConsistency::Truncated { logical_size: 1537, allocated_bytes: 1536 });
+        assert_eq!(consistency.missing_bytes(), 1);
+        assert_eq!(consistency.slack_bytes(), 0);
+    }
+
+    #[test]
+    fn allocations_are_compared_with_the_logical_size() {
+        let fork = |logical_size, total_blocks| ForkDataSnapshot {
+            logical_size,
+            clump_size: 0,
+            total_blocks,
+            extents: [(0, 0); NUM_EXTENT_DESCRIPTORS],
+        };
+        assert_eq!(check_fork(&fork(1536, 3), BLOCK_SIZE), ForkConsistency::Consistent);
+        assert_eq!(check_fork(&fork(1025, 3), BLOCK_SIZE), ForkConsistency::Consistent);
+        let slack = check_fork(&fork(1024, 3), BLOCK_SIZE);
+        assert_eq!(slack, ForkConsistency::Slack { logical_size: 1024, allocated_bytes: 1536 });
+        assert_eq!(slack.slack_bytes(), 512);
+        assert_eq!(slack.missing_bytes(), 0);
+        assert_eq!(check_fork(&fork(1, 0), BLOCK_SIZE).missing_bytes(), 1);
+    }
+}
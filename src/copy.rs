@@ -0,0 +1,108 @@
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CopyProgress {
+    pub bytes_copied: u64,
+    // What the copy will reach if nothing fails, which for a partial file is
+    // its recoverable length.
+    pub total_bytes: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum CopyOutcome {
+    Complete { bytes_copied: u64 },
+    // The file could only be copied up to its recoverable length.
+    Truncated { bytes_copied: u64, length: u64 },
+    // Unreadable parts of the file were written out as zeros.
+    ZeroFilled { bytes_copied: u64, damaged_bytes: u64 },
+}
+
+impl CopyOutcome {
+    pub fn bytes_copied(&self) -> u64 {
+        match *self {
+            CopyOutcome::Complete { bytes_copied } => bytes_copied,
+            CopyOutcome::Truncated { bytes_copied, .. } => bytes_copied,
+            CopyOutcome::ZeroFilled { bytes_copied, .. } => bytes_copied,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        match *self {
+            CopyOutcome::Complete { .. } => true,
+            CopyOutcome::Truncated { .. } => false,
+            CopyOutcome::ZeroFilled { .. } => false,
+        }
+    }
+}
+
+impl Display for CopyOutcome {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            CopyOutcome::Complete { bytes_copied } => write!(f, "Copied {} bytes", bytes_copied),
+            CopyOutcome::Truncated { bytes_copied, length } =>
+                write!(f, "Copied {} of {} bytes before the damaged part of the file", bytes_copied, length),
+            CopyOutcome::ZeroFilled { bytes_copied, damaged_bytes } =>
+                write!(f, "Copied {} bytes, of which {} were unreadable and zero-filled", bytes_copied, damaged_bytes),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CopyErrorKind {
+    Read,
+    Write,
+}
+
+// Everything before logical_offset was written out. The device offset is
+// only known for reads within the file's extents.
+#[derive(Debug)]
+pub struct CopyError {
+    pub kind: CopyErrorKind,
+    pub logical_offset: u64,
+    pub device_offset: Option<u64>,
+    pub error: io::Error,
+}
+
+impl Display for CopyError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match (self.kind, self.device_offset) {
+            (CopyErrorKind::Read, Some(device_offset)) => write!(f, "Read failed at file offset {} (device offset {}): {}",
+                                                                 self.logical_offset, device_offset, self.error),
+            (CopyErrorKind::Read, None) => write!(f, "Read failed at file offset {}: {}", self.logical_offset, self.error),
+            (CopyErrorKind::Write, _) => write!(f, "Write failed at file offset {}: {}", self.logical_offset, self.error),
+        }
+    }
+}
+
+impl error::Error for CopyError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn copy_error(kind: CopyErrorKind, device_offset: Option<u64>) -> String {
+        let error = CopyError {
+            kind,
+            logical_offset: 4096,
+            device_offset,
+            error: io::Error::other("bad sector"),
+        };
+        error.to_string()
+    }
+
+    #[test]
+    fn copy_errors_say_which_side_failed() {
+        assert_eq!(copy_error(CopyErrorKind::Read, Some(8192)),
+                   "Read failed at file offset 4096 (device offset 8192): bad sector");
+        assert_eq!(copy_error(CopyErrorKind::Read, None), "Read failed at file offset 4096: bad sector");
+        assert_eq!(copy_error(CopyErrorKind::Write, None), "Write failed at file offset 4096: bad sector");
+        assert_eq!(copy_error(CopyErrorKind::Write, Some(8192)), "Write failed at file offset 4096: bad sector");
+    }
+}
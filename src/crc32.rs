@@ -0,0 +1,82 @@
+// The CRC-32 used by zlib and most archivers (IEEE 802.3, reflected).
+
+const POLYNOMIAL: u32 = 0xedb88320;
+
+const TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut idx = 0;
+    while idx < 256 {
+        let mut value = idx as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            value = if value & 1 != 0 { (value >> 1) ^ POLYNOMIAL } else { value >> 1 };
+            bit += 1;
+        }
+        table[idx] = value;
+        idx += 1;
+    }
+    table
+}
+
+#[derive(Clone)]
+pub struct Crc32 {
+    value: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32 {
+            value: 0xffffffff,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.value = TABLE[((self.value ^ byte as u32) & 0xff) as usize] ^ (self.value >> 8);
+        }
+    }
+
+    pub fn finish(self) -> u32 {
+        !self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fixture;
+    use super::*;
+
+    fn checksum(data: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(data);
+        crc.finish()
+    }
+
+    #[test]
+    fn known_checksums() {
+        let cases: [(&[u8], u32); 5] = [
+            (b"", 0),
+            (b"a", 0xe8b7be43),
+            (b"abc", 0x352441c2),
+            (b"123456789", 0xcbf43926),
+            (b"The quick brown fox jumps over the lazy dog", 0x414fa339),
+        ];
+        for &(input, expected) in &cases {
+            assert_eq!(checksum(input), expected, "{:?}", String::from_utf8_lossy(input));
+        }
+    }
+
+    #[test]
+    fn updates_in_chunks_match_one_update() {
+        let data = fixture::pattern_bytes(1000);
+        for &split in &[1, 3, 64, 999] {
+            let mut crc = Crc32::new();
+            for chunk in data.chunks(split) {
+                crc.update(chunk);
+            }
+            assert_eq!(crc.finish(), checksum(&data), "{}", split);
+        }
+    }
+}
@@ -0,0 +1,526 @@
+use error::HFSPError;
+use fs;
+use std::cmp;
+use std::collections::BinaryHeap;
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, BufRead, Write};
+use std::slice;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BlockStatus {
+    NonTried,
+    NonTrimmed,
+    NonScraped,
+    BadSector,
+    Finished,
+}
+
+impl BlockStatus {
+    // Older ddrescue versions called non-scraped blocks "non-split", but
+    // used the same character.
+    pub fn from_char(c: char) -> Option<BlockStatus> {
+        match c {
+            '?' => Some(BlockStatus::NonTried),
+            '*' => Some(BlockStatus::NonTrimmed),
+            '/' => Some(BlockStatus::NonScraped),
+            '-' => Some(BlockStatus::BadSector),
+            '+' => Some(BlockStatus::Finished),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(self) -> char {
+        match self {
+            BlockStatus::NonTried => '?',
+            BlockStatus::NonTrimmed => '*',
+            BlockStatus::NonScraped => '/',
+            BlockStatus::BadSector => '-',
+            BlockStatus::Finished => '+',
+        }
+    }
+
+    pub fn is_finished(self) -> bool {
+        self == BlockStatus::Finished
+    }
+}
+
+impl Display for BlockStatus {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        let name = match *self {
+            BlockStatus::NonTried => "non-tried",
+            BlockStatus::NonTrimmed => "non-trimmed",
+            BlockStatus::NonScraped => "non-scraped",
+            BlockStatus::BadSector => "bad-sector",
+            BlockStatus::Finished => "finished",
+        };
+        write!(fmt, "{}", name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    Copying,
+    Trimming,
+    Scraping,
+    Retrying,
+    Filling,
+    GeneratingApproximate,
+    Finished,
+}
+
+impl Phase {
+    pub fn from_char(c: char) -> Option<Phase> {
+        match c {
+            '?' => Some(Phase::Copying),
+            '*' => Some(Phase::Trimming),
+            '/' => Some(Phase::Scraping),
+            '-' => Some(Phase::Retrying),
+            'F' => Some(Phase::Filling),
+            'G' => Some(Phase::GeneratingApproximate),
+            '+' => Some(Phase::Finished),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(self) -> char {
+        match self {
+            Phase::Copying => '?',
+            Phase::Trimming => '*',
+            Phase::Scraping => '/',
+            Phase::Retrying => '-',
+            Phase::Filling => 'F',
+            Phase::GeneratingApproximate => 'G',
+            Phase::Finished => '+',
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub offset: u64,
+    pub length: u64,
+    pub status: BlockStatus,
+}
+
+impl Region {
+    pub fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+}
+
+// Regions are kept sorted, non-overlapping, non-empty, and with adjacent
+// regions of the same status merged.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RegionMap {
+    regions: Vec<Region>,
+}
+
+impl RegionMap {
+    pub fn new() -> RegionMap {
+        RegionMap {
+            regions: Vec::new(),
+        }
+    }
+
+    // Builds a map from regions in any order. Where regions overlap, later
+    // ones take precedence, as if each had been inserted in turn.
+    pub fn from_regions<I>(regions: I) -> RegionMap where I: IntoIterator<Item = Region> {
+        let mut regions: Vec<(usize, Region)> = regions.into_iter().filter(|r| r.length != 0).map(|r| {
+            Region { offset: r.offset, length: r.offset.saturating_add(r.length) - r.offset, status: r.status }
+        }).enumerate().collect();
+        regions.sort_by_key(|&(_, r)| r.offset);
+        let regions = if regions.windows(2).any(|pair| pair[0].1.end() > pair[1].1.offset) {
+            resolve_overlaps(&regions)
+        } else {
+            regions.into_iter().map(|(_, r)| r).collect()
+        };
+        let mut result = RegionMap { regions };
+        result.coalesce();
+        result
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    pub fn regions(&self) -> slice::Iter<'_, Region> {
+        self.regions.iter()
+    }
+
+    pub fn regions_with_status(&self, status: BlockStatus) -> impl Iterator<Item = &Region> {
+        self.regions.iter().filter(move |r| r.status == status)
+    }
+
+    pub fn total_length(&self, status: BlockStatus) -> u64 {
+        self.regions_with_status(status).map(|r| r.length).sum()
+    }
+
+    // Marks a range with the given status, replacing whatever was recorded
+    // for it before.
+    pub fn insert(&mut self, offset: u64, length: u64, status: BlockStatus) {
+        if length == 0 {
+            return;
+        }
+        let end = offset.saturating_add(length);
+        let length = end - offset;
+        let mut regions = Vec::with_capacity(self.regions.len() + 2);
+        for region in &self.regions {
+            if region.end() <= offset || region.offset >= end {
+                regions.push(*region);
+                continue;
+            }
+            if region.offset < offset {
+                regions.push(Region { offset: region.offset, length: offset - region.offset, status: region.status });
+            }
+            if region.end() > end {
+                regions.push(Region { offset: end, length: region.end() - end, status: region.status });
+            }
+        }
+        regions.push(Region { offset, length, status });
+        regions.sort_by_key(|r| r.offset);
+        self.regions = regions;
+        self.coalesce();
+    }
+
+    fn coalesce(&mut self) {
+        let mut merged: Vec<Region> = Vec::with_capacity(self.regions.len());
+        for region in self.regions.drain(..) {
+            if let Some(last) = merged.last_mut() {
+                if last.end() == region.offset && last.status == region.status {
+                    last.length += region.length;
+                    continue;
+                }
+            }
+            merged.push(region);
+        }
+        self.regions = merged;
+    }
+
+    pub fn status_at(&self, offset: u64) -> Option<BlockStatus> {
+        let index = match self.regions.binary_search_by_key(&offset, |r| r.offset) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+        let region = &self.regions[index];
+        if offset < region.end() {
+            Some(region.status)
+        } else {
+            None
+        }
+    }
+
+    // Returns the part of this map that lies within the given byte range.
+    pub fn restrict(&self, offset: u64, length: u64) -> RegionMap {
+        let mut range = RegionMap::new();
+        range.insert(offset, length, BlockStatus::Finished);
+        self.intersection(&range)
+    }
+
+    // True only if every byte of the range is present in the map and finished.
+    pub fn is_range_finished(&self, offset: u64, length: u64) -> bool {
+        let mut position = offset;
+        let end = offset.saturating_add(length);
+        for region in self.restrict(offset, length).regions() {
+            if region.offset != position || !region.status.is_finished() {
+                return false;
+            }
+            position = region.end();
+        }
+        position >= end
+    }
+
+    pub fn with_status(&self, status: BlockStatus) -> RegionMap {
+        RegionMap {
+            regions: self.regions_with_status(status).cloned().collect(),
+        }
+    }
+
+    // Bytes covered by either map. Where both cover a byte, the status from
+    // this map is kept.
+    pub fn union(&self, other: &RegionMap) -> RegionMap {
+        RegionMap::from_regions(other.regions.iter().chain(self.regions.iter()).cloned())
+    }
+
+    // Bytes covered by both maps, with the status from this map.
+    pub fn intersection(&self, other: &RegionMap) -> RegionMap {
+        let mut regions = Vec::new();
+        let mut j = 0;
+        for region in &self.regions {
+            while j < other.regions.len() && other.regions[j].end() <= region.offset {
+                j += 1;
+            }
+            let mut k = j;
+            while k < other.regions.len() && other.regions[k].offset < region.end() {
+                let start = cmp::max(region.offset, other.regions[k].offset);
+                let end = cmp::min(region.end(), other.regions[k].end());
+                regions.push(Region { offset: start, length: end - start, status: region.status });
+                k += 1;
+            }
+        }
+        let mut result = RegionMap { regions };
+        result.coalesce();
+        result
+    }
+
+    // Bytes covered by this map but not by the other.
+    pub fn subtract(&self, other: &RegionMap) -> RegionMap {
+        let mut regions = Vec::new();
+        let mut j = 0;
+        for region in &self.regions {
+            while j < other.regions.len() && other.regions[j].end() <= region.offset {
+                j += 1;
+            }
+            let mut position = region.offset;
+            let mut k = j;
+            while k < other.regions.len() && other.regions[k].offset < region.end() {
+                if other.regions[k].offset > position {
+                    regions.push(Region { offset: position, length: other.regions[k].offset - position, status: region.status });
+                }
+                position = cmp::max(position, other.regions[k].end());
+                k += 1;
+            }
+            if position < region.end() {
+                regions.push(Region { offset: position, length: region.end() - position, status: region.status });
+            }
+        }
+        let mut result = RegionMap { regions };
+        result.coalesce();
+        result
+    }
+}
+
+// Sweeps over the sorted regions, giving each stretch between region
+// boundaries the status of the latest region covering it. Regions are paired
+// with their original positions.
+fn resolve_overlaps(regions: &[(usize, Region)]) -> Vec<Region> {
+    let mut boundaries: Vec<u64> = regions.iter().flat_map(|&(_, r)| [r.offset, r.end()]).collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+    // The covering regions by original position, latest first. Regions that
+    // have ended are only removed once they reach the top.
+    let mut active = BinaryHeap::new();
+    let mut next = 0;
+    let mut result = Vec::new();
+    for pair in boundaries.windows(2) {
+        let (start, end) = (pair[0], pair[1]);
+        while next < regions.len() && regions[next].1.offset <= start {
+            active.push((regions[next].0, next));
+            next += 1;
+        }
+        while active.peek().is_some_and(|&(_, idx)| regions[idx].1.end() <= start) {
+            active.pop();
+        }
+        if let Some(&(_, idx)) = active.peek() {
+            result.push(Region { offset: start, length: end - start, status: regions[idx].1.status });
+        }
+    }
+    result
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mapfile {
+    pub current_position: u64,
+    pub current_phase: Phase,
+    pub current_pass: Option<u32>,
+    pub regions: RegionMap,
+}
+
+impl Mapfile {
+    pub fn new(regions: RegionMap) -> Mapfile {
+        Mapfile {
+            current_position: 0,
+            current_phase: Phase::Finished,
+            current_pass: Some(1),
+            regions,
+        }
+    }
+
+    pub fn parse<R: BufRead>(reader: R) -> fs::Result<Mapfile> {
+        let mut status_line = None;
+        let mut regions = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            let line = match line.find('#') {
+                Some(idx) => &line[..idx],
+                None => &line[..],
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            if status_line.is_none() {
+                // Logfiles from before ddrescue 1.20 have no current_pass.
+                if fields.len() < 2 || fields.len() > 3 {
+                    return Err(HFSPError::InvalidMapfile);
+                }
+                let position = parse_number(fields[0])?;
+                let phase = single_char(fields[1]).and_then(Phase::from_char).ok_or(HFSPError::InvalidMapfile)?;
+                let pass = match fields.get(2) {
+                    Some(pass) => Some(u32::try_from(parse_number(pass)?).map_err(|_| HFSPError::InvalidMapfile)?),
+                    None => None,
+                };
+                status_line = Some((position, phase, pass));
+            } else {
+                if fields.len() != 3 {
+                    return Err(HFSPError::InvalidMapfile);
+                }
+                let offset = parse_number(fields[0])?;
+                let length = parse_number(fields[1])?;
+                let status = single_char(fields[2]).and_then(BlockStatus::from_char).ok_or(HFSPError::InvalidMapfile)?;
+                regions.push(Region { offset, length, status });
+            }
+        }
+        let (current_position, current_phase, current_pass) = status_line.ok_or(HFSPError::InvalidMapfile)?;
+        Ok(Mapfile {
+            current_position,
+            current_phase,
+            current_pass,
+            regions: RegionMap::from_regions(regions),
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "# Mapfile. Created by hfsplus-rescue")?;
+        match self.current_pass {
+            Some(pass) => {
+                writeln!(writer, "# current_pos  current_status  current_pass")?;
+                writeln!(writer, "0x{:08X}     {}               {}", self.current_position, self.current_phase.to_char(), pass)?;
+            },
+            None => {
+                writeln!(writer, "# current_pos  current_status")?;
+                writeln!(writer, "0x{:08X}     {}", self.current_position, self.current_phase.to_char())?;
+            },
+        }
+        writeln!(writer, "#      pos        size  status")?;
+        for region in self.regions.regions() {
+            writeln!(writer, "0x{:08X}  0x{:08X}  {}", region.offset, region.length, region.status.to_char())?;
+        }
+        Ok(())
+    }
+}
+
+fn single_char(field: &str) -> Option<char> {
+    let mut chars = field.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c),
+        _ => None,
+    }
+}
+
+// ddrescue reads numbers with strtoll(..., 0), so accept hex, octal and
+// decimal in the same way.
+fn parse_number(field: &str) -> fs::Result<u64> {
+    let result = if field.starts_with("0x") || field.starts_with("0X") {
+        u64::from_str_radix(&field[2..], 16)
+    } else if field.len() > 1 && field.starts_with('0') {
+        u64::from_str_radix(&field[1..], 8)
+    } else {
+        field.parse()
+    };
+    result.map_err(|_| HFSPError::InvalidMapfile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAPFILE_1_27: &str = include_str!("../testdata/ddrescue-1.27.map");
+    const LOGFILE_1_19: &str = include_str!("../testdata/ddrescue-1.19.log");
+
+    fn data_lines(text: &str) -> Vec<&str> {
+        text.lines().filter(|line| !line.starts_with('#')).collect()
+    }
+
+    fn region(offset: u64, length: u64, status: BlockStatus) -> Region {
+        Region { offset, length, status }
+    }
+
+    #[test]
+    fn parses_current_mapfile() {
+        let mapfile = Mapfile::parse(MAPFILE_1_27.as_bytes()).unwrap();
+        assert_eq!(mapfile.current_position, 0x5D9DCA000);
+        assert_eq!(mapfile.current_phase, Phase::Scraping);
+        assert_eq!(mapfile.current_pass, Some(1));
+        assert_eq!(mapfile.regions.regions().count(), 40);
+        let total: u64 = mapfile.regions.regions().map(|r| r.length).sum();
+        assert_eq!(mapfile.regions.regions().next_back().unwrap().end(), total);
+        assert_eq!(mapfile.regions.status_at(0x99950E00), Some(BlockStatus::BadSector));
+    }
+
+    #[test]
+    fn parses_logfile_without_pass() {
+        let mapfile = Mapfile::parse(LOGFILE_1_19.as_bytes()).unwrap();
+        assert_eq!(mapfile.current_position, 0x0AF22000);
+        assert_eq!(mapfile.current_phase, Phase::Copying);
+        assert_eq!(mapfile.current_pass, None);
+        assert_eq!(mapfile.regions.total_length(BlockStatus::NonScraped), 0x016F8800 + 0x064A0000);
+    }
+
+    #[test]
+    fn writing_reproduces_fixtures() {
+        for &fixture in &[MAPFILE_1_27, LOGFILE_1_19] {
+            let mapfile = Mapfile::parse(fixture.as_bytes()).unwrap();
+            let mut written = Vec::new();
+            mapfile.write(&mut written).unwrap();
+            let written = String::from_utf8(written).unwrap();
+            assert_eq!(data_lines(&written), data_lines(fixture));
+            assert_eq!(Mapfile::parse(written.as_bytes()).unwrap(), mapfile);
+        }
+    }
+
+    #[test]
+    fn later_lines_take_precedence() {
+        let text = "0 +\n0x300 0x100 -\n0 0x400 +\n0x100 0x100 ?\n0x180 0x200 *\n";
+        let mapfile = Mapfile::parse(text.as_bytes()).unwrap();
+        let regions: Vec<Region> = mapfile.regions.regions().cloned().collect();
+        assert_eq!(regions, vec![
+            region(0, 0x100, BlockStatus::Finished),
+            region(0x100, 0x80, BlockStatus::NonTried),
+            region(0x180, 0x200, BlockStatus::NonTrimmed),
+            region(0x380, 0x80, BlockStatus::Finished),
+        ]);
+        let mut inserted = RegionMap::new();
+        inserted.insert(0x300, 0x100, BlockStatus::BadSector);
+        inserted.insert(0, 0x400, BlockStatus::Finished);
+        inserted.insert(0x100, 0x100, BlockStatus::NonTried);
+        inserted.insert(0x180, 0x200, BlockStatus::NonTrimmed);
+        assert_eq!(mapfile.regions, inserted);
+    }
+
+    #[test]
+    fn unsorted_lines_are_sorted_and_merged() {
+        let text = "0 +\n0x200 0x100 +\n0 0x100 +\n0x100 0x100 +\n0x400 0x100 -\n";
+        let mapfile = Mapfile::parse(text.as_bytes()).unwrap();
+        let regions: Vec<Region> = mapfile.regions.regions().cloned().collect();
+        assert_eq!(regions, vec![region(0, 0x300, BlockStatus::Finished), region(0x400, 0x100, BlockStatus::BadSector)]);
+    }
+
+    #[test]
+    fn rejects_out_of_range_pass() {
+        assert_eq!(Mapfile::parse("0 ? 4294967295\n".as_bytes()).unwrap().current_pass, Some(u32::MAX));
+        assert!(matches!(Mapfile::parse("0 ? 4294967296\n".as_bytes()), Err(HFSPError::InvalidMapfile)));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for &text in &["", "0 + 1 2\n", "0 X\n", "0 +\n0 0x100\n", "0 +\n0 0x100 +-\n", "0 +\n0 zz +\n"] {
+            assert!(matches!(Mapfile::parse(text.as_bytes()), Err(HFSPError::InvalidMapfile)), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn union_prefers_this_map() {
+        let mut a = RegionMap::new();
+        a.insert(0x100, 0x100, BlockStatus::BadSector);
+        let mut b = RegionMap::new();
+        b.insert(0, 0x400, BlockStatus::Finished);
+        let regions: Vec<Region> = a.union(&b).regions().cloned().collect();
+        assert_eq!(regions, vec![
+            region(0, 0x100, BlockStatus::Finished),
+            region(0x100, 0x100, BlockStatus::BadSector),
+            region(0x200, 0x200, BlockStatus::Finished),
+        ]);
+    }
+}
@@ -1,3 +1,4 @@
+use btree::NodeError;
 use std::convert;
 use std::error;
 use std::fmt;
@@ -7,27 +8,77 @@ use std::io;
 pub enum HFSPError {
     IOError(io::Error),
     InvalidVolumeHeader,
+    SignatureVersionMismatch,
+    PlainHFSNotSupported,
     InvalidFileView,
-    ExtentOverflowNotSupported,
+    MissingOverflowExtents,
+    InvalidMapfile,
+    InvalidOverlay,
+    ReadOnlySource,
+    UnsupportedVersion(u16),
+    InvalidBlockSize(u32),
+    InvalidBTree,
+    MissingCatalogRecord,
+    InvalidExtent { index: usize, start: u32, count: u32 },
+    TruncatedFork { logical_size: u64, allocated_bytes: u64 },
+    BeyondRecoverableLength { offset: u64, recoverable_length: u64 },
+    ImplausibleForkSize { claimed: u64, maximum: u64 },
+    ArithmeticOverflow,
+    InvalidBTreeNode(NodeError),
+    InvalidArgument(&'static str),
+}
+
+impl HFSPError {
+    fn message(&self) -> &str {
+        match *self {
+            HFSPError::IOError(_) => "IO Error",
+            HFSPError::InvalidVolumeHeader => "Invalid Volume Header",
+            HFSPError::SignatureVersionMismatch => "Volume header version does not match its signature",
+            HFSPError::PlainHFSNotSupported => "Plain HFS volumes without an embedded HFS+ volume are not supported",
+            HFSPError::InvalidFileView => "Invalid partition offset or length",
+            HFSPError::MissingOverflowExtents => "Extents overflow file does not cover the rest of the fork",
+            HFSPError::InvalidMapfile => "Invalid ddrescue mapfile",
+            HFSPError::InvalidOverlay => "Invalid overlay file",
+            HFSPError::ReadOnlySource => "The source was not opened for writing",
+            HFSPError::UnsupportedVersion(_) => "Unsupported volume header version",
+            HFSPError::InvalidBlockSize(_) => "Invalid block size",
+            HFSPError::InvalidBTree => "Corrupt B-tree node",
+            HFSPError::MissingCatalogRecord => "Catalog record not found",
+            HFSPError::InvalidExtent { .. } => "Extent lies outside the volume",
+            HFSPError::TruncatedFork { .. } => "Fork allocation is smaller than its logical size",
+            HFSPError::BeyondRecoverableLength { .. } => "Read past the recoverable part of a damaged fork",
+            HFSPError::ImplausibleForkSize { .. } => "Fork is larger than the size limit",
+            HFSPError::ArithmeticOverflow => "Values read from the volume overflowed when combined",
+            HFSPError::InvalidBTreeNode(_) => "Invalid B-tree node",
+            HFSPError::InvalidArgument(_) => "Invalid argument",
+        }
+    }
 }
 
 impl fmt::Display for HFSPError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use std::error::Error;
         match *self {
-            _ => write!(f, "{}", self.description()),
+            HFSPError::IOError(ref e) => write!(f, "{}: {}", self.message(), e),
+            HFSPError::UnsupportedVersion(version) => write!(f, "{} {:#06x}", self.message(), version),
+            HFSPError::InvalidBlockSize(size) => write!(f, "{} {}", self.message(), size),
+            HFSPError::InvalidExtent { index, start, count } =>
+                write!(f, "{}: extent {} covers {} blocks from block {}", self.message(), index, count, start),
+            HFSPError::TruncatedFork { logical_size, allocated_bytes } =>
+                write!(f, "{}: {} bytes allocated for {}", self.message(), allocated_bytes, logical_size),
+            HFSPError::BeyondRecoverableLength { offset, recoverable_length } =>
+                write!(f, "{}: offset {} but only {} bytes recoverable", self.message(), offset, recoverable_length),
+            HFSPError::ImplausibleForkSize { claimed, maximum } =>
+                write!(f, "{}: {} bytes claimed but at most {} allowed", self.message(), claimed, maximum),
+            HFSPError::InvalidBTreeNode(ref error) => write!(f, "{}: {}", self.message(), error),
+            HFSPError::InvalidArgument(reason) => write!(f, "{}: {}", self.message(), reason),
+            _ => write!(f, "{}", self.message()),
         }
     }
 }
 
 impl error::Error for HFSPError {
     fn description(&self) -> &str {
-        match *self {
-            HFSPError::IOError(_) => &"IO Error",
-            HFSPError::InvalidVolumeHeader => &"Invalid Volume Header",
-            HFSPError::InvalidFileView => &"Invalid partition offset or length",
-            HFSPError::ExtentOverflowNotSupported => &"Extent overflow file support is unimplemented",
-        }
+        self.message()
     }
 }
 
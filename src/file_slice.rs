@@ -1,54 +1,337 @@
-use fs;
+#[cfg(all(feature = "block-device", unix))]
+use block_device;
+use fs::{self, TryClone};
 use error::HFSPError;
-use std::io::{self, Read, Seek, SeekFrom};
+use std::cmp;
+#[cfg(all(feature = "block-device", unix))]
+use std::fs::File;
+#[cfg(feature = "write-support")]
+use std::io::Write;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
 
+// The inner file is shared with nothing else, but its cursor isn't trusted:
+// each read seeks to the slice's own position first.
 pub struct FileSlice<F> {
     offset: u64,
     length: u64,
+    position: u64,
     file: F,
 }
 
 impl<F> FileSlice<F> where F: Seek {
     pub fn new(mut file: F, offset: u64, length: Option<u64>) -> fs::Result<FileSlice<F>> {
         let file_length = file.seek(SeekFrom::End(0))?;
+        FileSlice::with_source_length(file, file_length, offset, length)
+    }
+
+    // For sources such as raw block devices whose length can't be found by
+    // seeking to the end.
+    pub fn with_source_length(file: F, file_length: u64, offset: u64, length: Option<u64>)
+        -> fs::Result<FileSlice<F>> {
         if file_length < offset {
             return Err(HFSPError::InvalidFileView);
         }
         let length = match length {
             None => file_length - offset,
-            Some(length) => if file_length < offset + length {
+            Some(length) => if file_length < offset.checked_add(length).ok_or(HFSPError::ArithmeticOverflow)? {
                 return Err(HFSPError::InvalidFileView);
             } else {
                 length
             },
         };
         let result = FileSlice {
-            offset: offset,
-            length: length,
-            file: file,
+            offset,
+            length,
+            position: 0,
+            file,
         };
         Ok(result)
     }
 }
 
-impl<F> Read for FileSlice<F> where F: Read {
+impl<T> FileSlice<Cursor<T>> where T: AsRef<[u8]> {
+    pub fn from_bytes(data: T, offset: u64, length: Option<u64>) -> fs::Result<FileSlice<Cursor<T>>> {
+        let data_length = data.as_ref().len() as u64;
+        FileSlice::with_source_length(Cursor::new(data), data_length, offset, length)
+    }
+}
+
+impl<F> FileSlice<F> {
+    // Where the slice starts in the inner file.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    // Relative to the start of the slice, and may be past its end.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+}
+
+impl<F> FileSlice<F> where F: TryClone {
+    // A slice of this slice, reading from its own clone of the inner file
+    // rather than nesting another layer of FileSlice.
+    pub fn slice(&self, offset: u64, length: u64) -> fs::Result<FileSlice<F>> {
+        let end = offset.checked_add(length).ok_or(HFSPError::ArithmeticOverflow)?;
+        if end > self.length {
+            return Err(HFSPError::InvalidFileView);
+        }
+        let result = FileSlice {
+            offset: self.offset + offset,
+            length,
+            position: 0,
+            file: self.file.try_clone()?,
+        };
+        Ok(result)
+    }
+}
+
+impl<F> TryClone for FileSlice<F> where F: TryClone {
+    fn try_clone(&self) -> io::Result<FileSlice<F>> {
+        let result = FileSlice {
+            offset: self.offset,
+            length: self.length,
+            position: self.position,
+            file: self.file.try_clone()?,
+        };
+        Ok(result)
+    }
+}
+
+#[cfg(all(feature = "block-device", unix))]
+impl FileSlice<File> {
+    // Like new, but finds the length of a block device by asking the
+    // kernel rather than seeking.
+    pub fn from_device(file: File, offset: u64, length: Option<u64>) -> fs::Result<FileSlice<File>> {
+        let file_length = block_device::source_length(&file)?;
+        FileSlice::with_source_length(file, file_length, offset, length)
+    }
+}
+
+impl<F> Read for FileSlice<F> where F: Read + Seek {
     fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
-        self.file.read(data)
+        if data.is_empty() || self.position >= self.length {
+            return Ok(0);
+        }
+        let size = cmp::min(data.len() as u64, self.length - self.position) as usize;
+        self.file.seek(SeekFrom::Start(self.offset + self.position))?;
+        let read = self.file.read(&mut data[..size])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(feature = "write-support")]
+impl<F> FileSlice<F> where F: Write + Seek {
+    // Writes all of the data at an offset within the slice, without moving
+    // the position. Nothing is written unless all of it fits.
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let end = offset.checked_add(data.len() as u64);
+        if end.is_none_or(|end| end > self.length) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "write past the end of the slice"));
+        }
+        self.file.seek(SeekFrom::Start(self.offset + offset))?;
+        self.file.write_all(data)
+    }
+}
+
+// Writes are cut short at the end of the slice, and fail once there, so the
+// slice never grows.
+#[cfg(feature = "write-support")]
+impl<F> Write for FileSlice<F> where F: Write + Seek {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        if self.position >= self.length {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "write past the end of the slice"));
+        }
+        let size = cmp::min(data.len() as u64, self.length - self.position) as usize;
+        self.file.seek(SeekFrom::Start(self.offset + self.position))?;
+        let written = self.file.write(&data[..size])?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
     }
 }
 
 impl<F> Seek for FileSlice<F> where F: Seek {
     fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
-        match pos {
-            io::SeekFrom::Start(offset) => {
-                self.file.seek(io::SeekFrom::Start(offset + self.offset)).map(|o| o - self.offset)
-            },
-            io::SeekFrom::Current(offset) => {
-                self.file.seek(io::SeekFrom::Current(offset)).map(|o| o - self.offset)
-            },
-            io::SeekFrom::End(offset) => {
-                self.file.seek(io::SeekFrom::End(self.offset as i64 + self.length as i64 + offset)).map(|o| o - self.offset)
-            },
+        let position = match pos {
+            io::SeekFrom::Start(offset) => offset,
+            io::SeekFrom::Current(offset) => fs::offset_position(self.position, offset)?,
+            io::SeekFrom::End(offset) => fs::offset_position(self.length, offset)?,
+        };
+        if self.offset.checked_add(position).is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to an overflowing position"));
+        }
+        self.position = position;
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fixture;
+    use std::io::{Cursor, Read};
+    use super::*;
+
+    fn read_all<F>(slice: &mut FileSlice<F>) -> Vec<u8> where F: Read + Seek {
+        let mut result = Vec::new();
+        slice.seek(SeekFrom::Start(0)).unwrap();
+        slice.read_to_end(&mut result).unwrap();
+        result
+    }
+
+    #[test]
+    fn slices_past_the_end_are_rejected() {
+        let data = fixture::pattern_bytes(1000);
+        for &(offset, length) in &[(1001, None), (1001, Some(0)), (0, Some(1001)), (999, Some(2))] {
+            match FileSlice::new(Cursor::new(&data[..]), offset, length) {
+                Err(HFSPError::InvalidFileView) => {},
+                other => panic!("unexpected result {:?} for {}, {:?}", other.map(|_| ()), offset, length),
+            }
+        }
+        match FileSlice::from_bytes(&data[..], u64::MAX, Some(2)) {
+            Err(HFSPError::InvalidFileView) => {},
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
         }
+        match FileSlice::from_bytes(&data[..], 10, Some(u64::MAX)) {
+            Err(HFSPError::ArithmeticOverflow) => {},
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+
+        let slice = FileSlice::from_bytes(&data[..], 100, Some(500)).unwrap();
+        for &(offset, length) in &[(0, 501), (500, 1), (501, 0), (400, 101)] {
+            match slice.slice(offset, length) {
+                Err(HFSPError::InvalidFileView) => {},
+                other => panic!("unexpected result {:?} for {}, {}", other.map(|_| ()), offset, length),
+            }
+        }
+        match slice.slice(1, u64::MAX) {
+            Err(HFSPError::ArithmeticOverflow) => {},
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn slices_may_reach_the_end() {
+        let data = fixture::pattern_bytes(1000);
+        let mut slice = FileSlice::new(Cursor::new(&data[..]), 600, None).unwrap();
+        assert_eq!(slice.len(), 400);
+        assert_eq!(read_all(&mut slice), &data[600..]);
+        let mut slice = FileSlice::new(Cursor::new(&data[..]), 0, Some(1000)).unwrap();
+        assert_eq!(read_all(&mut slice), data);
+    }
+
+    #[test]
+    fn zero_length_slices_read_nothing() {
+        let data = fixture::pattern_bytes(1000);
+        let cases = [
+            FileSlice::from_bytes(&data[..], 1000, None).unwrap(),
+            FileSlice::from_bytes(&data[..], 300, Some(0)).unwrap(),
+            FileSlice::from_bytes(&data[..], 0, Some(10)).unwrap().slice(10, 0).unwrap(),
+        ];
+        for mut slice in cases {
+            assert!(slice.is_empty());
+            let mut buf = [0; 10];
+            assert_eq!(slice.read(&mut buf).unwrap(), 0);
+            assert_eq!(slice.seek(SeekFrom::Start(5)).unwrap(), 5);
+            assert_eq!(slice.read(&mut buf).unwrap(), 0);
+            assert_eq!(read_all(&mut slice), &[] as &[u8]);
+        }
+    }
+
+    #[test]
+    fn seeks_at_the_boundaries() {
+        // Each seek starts from position 200 of a 500-byte slice at offset
+        // 100. None means the seek fails and leaves the position alone.
+        let cases = [
+            (SeekFrom::Start(0), Some(0)),
+            (SeekFrom::Start(499), Some(499)),
+            (SeekFrom::Start(500), Some(500)),
+            (SeekFrom::Start(10000), Some(10000)),
+            (SeekFrom::Start(u64::MAX - 100), Some(u64::MAX - 100)),
+            (SeekFrom::Start(u64::MAX - 99), None),
+            (SeekFrom::Start(u64::MAX), None),
+            (SeekFrom::Current(0), Some(200)),
+            (SeekFrom::Current(-200), Some(0)),
+            (SeekFrom::Current(-201), None),
+            (SeekFrom::Current(299), Some(499)),
+            (SeekFrom::Current(300), Some(500)),
+            (SeekFrom::Current(i64::MAX), Some(200 + i64::MAX as u64)),
+            (SeekFrom::Current(i64::MIN), None),
+            (SeekFrom::End(0), Some(500)),
+            (SeekFrom::End(-1), Some(499)),
+            (SeekFrom::End(-500), Some(0)),
+            (SeekFrom::End(-501), None),
+            (SeekFrom::End(1), Some(501)),
+            (SeekFrom::End(i64::MAX), Some(500 + i64::MAX as u64)),
+            (SeekFrom::End(i64::MIN), None),
+        ];
+        let data = fixture::pattern_bytes(1000);
+        let mut slice = FileSlice::from_bytes(&data[..], 100, Some(500)).unwrap();
+        for &(pos, expected) in &cases {
+            slice.seek(SeekFrom::Start(200)).unwrap();
+            match (slice.seek(pos), expected) {
+                (Ok(position), Some(expected)) => assert_eq!(position, expected, "{:?}", pos),
+                (Err(ref e), None) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{:?}", pos),
+                (result, _) => panic!("unexpected result {:?} for {:?}", result, pos),
+            }
+            let position = expected.unwrap_or(200);
+            assert_eq!(slice.position(), position, "{:?}", pos);
+            assert_eq!(slice.stream_position().unwrap(), position, "{:?}", pos);
+
+            // Reads never leave the slice.
+            let mut buf = [0; 10];
+            let read = slice.read(&mut buf).unwrap();
+            if position < 500 {
+                let start = 100 + position as usize;
+                let size = cmp::min(10, 500 - position as usize);
+                assert_eq!(read, size, "{:?}", pos);
+                assert_eq!(buf[..size], data[start..start + size], "{:?}", pos);
+            } else {
+                assert_eq!(read, 0, "{:?}", pos);
+            }
+        }
+    }
+
+    #[test]
+    fn nested_slices_are_relative_to_their_parent() {
+        let data = fixture::pattern_bytes(1000);
+        let outer = FileSlice::from_bytes(&data[..], 100, Some(800)).unwrap();
+        let mut middle = outer.slice(200, 400).unwrap();
+        let mut inner = middle.slice(50, 100).unwrap();
+        assert_eq!((middle.offset(), middle.len()), (300, 400));
+        assert_eq!((inner.offset(), inner.len()), (350, 100));
+        assert_eq!(read_all(&mut middle), &data[300..700]);
+        assert_eq!(read_all(&mut inner), &data[350..450]);
+
+        // Reads from one slice don't move the others.
+        let mut buf = [0; 10];
+        middle.seek(SeekFrom::Start(20)).unwrap();
+        inner.seek(SeekFrom::Start(90)).unwrap();
+        assert_eq!(inner.read(&mut buf).unwrap(), 10);
+        assert_eq!(buf, data[440..450]);
+        assert_eq!(inner.read(&mut buf).unwrap(), 0);
+        assert_eq!(middle.read(&mut buf).unwrap(), 10);
+        assert_eq!(buf, data[320..330]);
+
+        // A nested slice can't reach past its parent, even where the source
+        // has more data.
+        assert!(inner.slice(0, 101).is_err());
+        let mut last = inner.slice(99, 1).unwrap();
+        assert_eq!(read_all(&mut last), &data[449..450]);
     }
 }
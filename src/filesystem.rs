@@ -1,24 +1,98 @@
+use btree::{AttributesTree, BTree, CatalogTree, ExtentsTree};
+use catalog::{decode_catalog_key, decode_folder_id, FileRecord};
 use chrono::{self, TimeZone};
+use consistency::{self, ConsistencyIssue, ExtentIssue, ForkConsistency};
+use copy::{CopyError, CopyErrorKind, CopyOutcome, CopyProgress};
 use error::HFSPError;
+use file_slice::FileSlice;
+use fragmentation::{FragmentationHistogram, FragmentationReport};
 use fs;
+#[cfg(feature = "hashing")]
+use hashing::{Digests, HashAlgorithm, HashingWriter};
+#[cfg(feature = "hashing")]
+use md5;
 use num;
+use ownership::{self, ForkType, OwnerSearch};
+use snapshot::{be_u16, be_u32, ForkDataSnapshot, HeaderComparison, JournalInfoBlockSnapshot, VolumeHeaderSnapshot,
+               NUM_EXTENT_DESCRIPTORS, SPECIAL_FILES, SpecialFile};
+use std::collections::HashMap;
 use std::fmt::{self, Display, Formatter};
-use std::io::{self, Read, Seek, SeekFrom};
+use std::io::{self, BufRead, Cursor, Read, Seek, SeekFrom, Write};
 use std::mem;
+use std::ops::Range;
 use std::slice;
+use std::str;
 use std::cmp;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 const OFFSET_VOLUME_HEADER: u64 = 1024;
-const OFFSET_VOLUME_HEADER_FORKS: u64 = 112;
+const OFFSET_ALTERNATE_VOLUME_HEADER_FROM_END: u64 = 1024;
+pub const OFFSET_VOLUME_HEADER_FORKS: u64 = 112;
 const OFFSET_FORK_DATA_EXTENT_RECORD: u64 = 16;
 const SIZE_EXTENT_DESCRIPTOR: u64 = 8;
-const SIZE_EXTENT_RECORD: u64 = SIZE_EXTENT_DESCRIPTOR * 8;
-const SIZE_FORK_DATA: u64 = 16 + SIZE_EXTENT_RECORD;
+pub const SIZE_EXTENT_RECORD: u64 = SIZE_EXTENT_DESCRIPTOR * 8;
+pub const SIZE_FORK_DATA: u64 = 16 + SIZE_EXTENT_RECORD;
+pub const SIGNATURE_HFS_PLUS: &[u8; 2] = b"H+";
+pub const SIGNATURE_HFSX: &[u8; 2] = b"HX";
+const VERSION_HFS_PLUS: u16 = 4;
+const VERSION_HFSX: u16 = 5;
+const SIGNATURE_HFS: &[u8; 2] = b"BD";
+const OFFSET_MDB_ALLOCATION_BLOCK_SIZE: usize = 20;
+const OFFSET_MDB_ALLOCATION_BLOCK_START: usize = 28;
+const OFFSET_MDB_EMBED_SIGNATURE: usize = 124;
+const OFFSET_MDB_EMBED_EXTENT: usize = 126;
+const SIZE_MDB: usize = 162;
+const SIZE_HFS_SECTOR: u64 = 512;
+pub const SIZE_VOLUME_HEADER: usize = 512;
+pub const OFFSET_FINDER_INFO: usize = 80;
+const SIZE_FINDER_INFO: usize = 32;
+const SCAN_CHUNK_SIZE: u64 = 1 << 20;
+const COPY_CHUNK_SIZE: usize = 1 << 20;
+const MAX_READAHEAD: usize = 1 << 20;
+const MIN_BLOCK_SIZE: u32 = 512;
+const MAX_BLOCK_SIZE: u32 = 1 << 20;
 
 #[derive(Debug)]
 pub struct FileSystem<F> {
     file: Mutex<F>,
+    writable: bool,
+    geometry: Mutex<Option<Geometry>>,
+    fork_size_limit: ForkSizeLimit,
+}
+
+// The largest logical size a fork may claim before opening it fails with
+// HFSPError::ImplausibleForkSize.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ForkSizeLimit {
+    // The total size of the volume's allocation blocks.
+    #[default]
+    VolumeSize,
+    Bytes(u64),
+    Unlimited,
+}
+
+// The header fields needed whenever a fork is opened, kept after the first
+// good read of the primary header. None of them can be changed through this
+// crate.
+#[derive(Clone, Debug)]
+struct Geometry {
+    block_size: u32,
+    total_blocks: u32,
+    extents_file: ForkDataSnapshot,
+}
+
+impl Geometry {
+    fn from_header(header: &VolumeHeaderSnapshot) -> fs::Result<Geometry> {
+        if !is_valid_block_size(header.block_size) {
+            return Err(HFSPError::InvalidBlockSize(header.block_size));
+        }
+        let result = Geometry {
+            block_size: header.block_size,
+            total_blocks: header.total_blocks,
+            extents_file: *header.get_special_file(SpecialFile::Extents),
+        };
+        Ok(result)
+    }
 }
 
 pub trait Structure<F> {
@@ -31,11 +105,18 @@ pub trait Structure<F> {
         file.read(buff)
     }
 
+    fn read_bytes(&self, offset: usize, buff: &mut [u8]) -> fs::Result<()> where F: Read + Seek {
+        let mut file = self.get_filesystem().file.lock().unwrap();
+        file.seek(SeekFrom::Start(self.get_offset() + offset as u64))?;
+        file.read_exact(buff)?;
+        Ok(())
+    }
+
     fn read_number<T: num::PrimInt>(&self, offset: usize) -> fs::Result<T> where F: Read + Seek {
         let mut result: T = T::zero();
         let ptr = &mut result as *mut T as *mut u8;
         let length = mem::size_of::<T>();
-        let mut buffer = unsafe { slice::from_raw_parts_mut(ptr, length) };
+        let buffer = unsafe { slice::from_raw_parts_mut(ptr, length) };
         {
             let mut file = self.get_filesystem().file.lock().unwrap();
             file.seek(SeekFrom::Start(self.get_offset() + offset as u64))?;
@@ -45,22 +126,59 @@ pub trait Structure<F> {
         Ok(result)
     }
 
-    fn read_date(&self, offset: usize, is_local: bool) -> fs::Result<chrono::DateTime<chrono::Local>> where F: Read + Seek {
+    fn read_date(&self, offset: usize, is_local: bool) -> fs::Result<HFSDate> where F: Read + Seek {
         let seconds: u32 = self.read_number(offset)?;
-        let duration = chrono::Duration::seconds(seconds as i64);
-        let origin_date = chrono::NaiveDate::from_ymd(1904, 1, 1);
-        let origin_time = chrono::NaiveTime::from_hms(0,0,0);
-        let origin = chrono::NaiveDateTime::new(origin_date, origin_time);
+        Ok(hfs_date(seconds, is_local))
+    }
 
-        let date = if is_local {
-            chrono::Local.from_local_datetime(&origin).single().unwrap() + duration
-        } else {
-            chrono::Local.from_utc_datetime(&origin) + duration
-        };
-        Ok(date)
+    #[cfg(feature = "repair")]
+    fn write_number<T: num::PrimInt>(&self, offset: usize, value: T) -> fs::Result<()> where F: Write + Seek {
+        let filesystem = self.get_filesystem();
+        if !filesystem.writable {
+            return Err(HFSPError::ReadOnlySource);
+        }
+        let value = num::PrimInt::to_be(value);
+        let ptr = &value as *const T as *const u8;
+        let buffer = unsafe { slice::from_raw_parts(ptr, mem::size_of::<T>()) };
+        let mut file = filesystem.file.lock().unwrap();
+        file.seek(SeekFrom::Start(self.get_offset() + offset as u64))?;
+        file.write_all(buffer)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+// Dates are given in local time when the local-time feature is enabled, and
+// in UTC otherwise, for targets without a local time zone.
+#[cfg(feature = "local-time")]
+pub type HFSDate = chrono::DateTime<chrono::Local>;
+#[cfg(not(feature = "local-time"))]
+pub type HFSDate = chrono::DateTime<chrono::Utc>;
+
+fn hfs_date_origin() -> chrono::NaiveDateTime {
+    let origin_date = chrono::NaiveDate::from_ymd_opt(1904, 1, 1).unwrap();
+    let origin_time = chrono::NaiveTime::from_hms_opt(0,0,0).unwrap();
+    chrono::NaiveDateTime::new(origin_date, origin_time)
+}
+
+#[cfg(feature = "local-time")]
+pub fn hfs_date(seconds: u32, is_local: bool) -> HFSDate {
+    let duration = chrono::Duration::seconds(seconds as i64);
+    let origin = hfs_date_origin();
+    if is_local {
+        chrono::Local.from_local_datetime(&origin).single().unwrap() + duration
+    } else {
+        chrono::Local.from_utc_datetime(&origin) + duration
     }
 }
 
+// Without a time zone, a date stored in local time is taken to be UTC.
+#[cfg(not(feature = "local-time"))]
+pub fn hfs_date(seconds: u32, _is_local: bool) -> HFSDate {
+    let duration = chrono::Duration::seconds(seconds as i64);
+    chrono::Utc.from_utc_datetime(&hfs_date_origin()) + duration
+}
+
 impl<F> Structure<F> for FileSystem<F> {
     fn get_offset(&self) -> u64 {
         0
@@ -75,28 +193,288 @@ impl<F> FileSystem<F> where F: Read + Seek {
     pub fn new(file: F) -> FileSystem<F> {
         FileSystem {
             file: Mutex::new(file),
+            writable: false,
+            geometry: Mutex::new(None),
+            fork_size_limit: ForkSizeLimit::default(),
         }
     }
 
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    pub fn get_fork_size_limit(&self) -> ForkSizeLimit {
+        self.fork_size_limit
+    }
+
+    pub fn set_fork_size_limit(&mut self, limit: ForkSizeLimit) {
+        self.fork_size_limit = limit;
+    }
+
     pub fn get_volume_header<'a>(&'a self) -> fs::Result<VolumeHeader<'a, F>> {
+        #[cfg(feature = "tracing")]
+        let _span = ::tracing::debug_span!("open_volume").entered();
         let result = VolumeHeader::new(self, OFFSET_VOLUME_HEADER);
         result.validate()?;
         Ok(result)
     }
 
-    fn validate_bytes(&self, offset: u64, bytes: &[u8]) -> fs::Result<()> {
-        let mut file = self.file.lock().unwrap();
-        file.seek(SeekFrom::Start(offset))?;
-        let mut data = vec![0; bytes.len()];
-        file.read_exact(&mut data[..])?;
-        for (x, y) in bytes.iter().zip(data.iter()) {
-            if x != y {
-                return Err(HFSPError::InvalidVolumeHeader);
+    fn get_geometry(&self) -> fs::Result<Geometry> {
+        if let Some(ref geometry) = *self.geometry.lock().unwrap() {
+            return Ok(geometry.clone());
+        }
+        let geometry = Geometry::from_header(&self.get_volume_header()?.snapshot()?)?;
+        *self.geometry.lock().unwrap() = Some(geometry.clone());
+        Ok(geometry)
+    }
+
+    // Forks opened through a header use its own geometry, so that those of
+    // the alternate header or of an unchecked primary one can be read when
+    // the primary header is damaged. Only the validated primary header's
+    // geometry is cached.
+    fn get_header_geometry(&self, offset: u64) -> fs::Result<Geometry> {
+        if offset == OFFSET_VOLUME_HEADER {
+            if let Some(ref geometry) = *self.geometry.lock().unwrap() {
+                return Ok(geometry.clone());
             }
         }
-        Ok(())
+        Geometry::from_header(&VolumeHeader::new(self, offset).snapshot()?)
+    }
+
+    // Skips all validation, for inspecting damaged headers.
+    pub fn get_volume_header_unchecked<'a>(&'a self) -> VolumeHeader<'a, F> {
+        VolumeHeader::new(self, OFFSET_VOLUME_HEADER)
+    }
+
+    pub fn get_alternate_volume_header<'a>(&'a self) -> fs::Result<VolumeHeader<'a, F>> {
+        let result = self.get_alternate_volume_header_unchecked()?;
+        result.validate()?;
+        Ok(result)
+    }
+
+    // Like get_volume_header_unchecked(), but fails if the volume is too
+    // small to hold an alternate header.
+    pub fn get_alternate_volume_header_unchecked<'a>(&'a self) -> fs::Result<VolumeHeader<'a, F>> {
+        let length = self.file.lock().unwrap().seek(SeekFrom::End(0))?;
+        if length < OFFSET_VOLUME_HEADER + OFFSET_ALTERNATE_VOLUME_HEADER_FROM_END {
+            return Err(HFSPError::InvalidVolumeHeader);
+        }
+        Ok(VolumeHeader::new(self, length - OFFSET_ALTERNATE_VOLUME_HEADER_FROM_END))
+    }
+
+    // Reads both copies of the header whether or not they validate.
+    pub fn compare_volume_headers(&self) -> fs::Result<HeaderComparison> {
+        let primary = self.get_volume_header_unchecked();
+        let alternate = self.get_alternate_volume_header_unchecked()?;
+        Ok(HeaderComparison::new(primary.snapshot()?, alternate.snapshot()?,
+                                 primary.validate().err().map(|e| e.to_string()),
+                                 alternate.validate().err().map(|e| e.to_string())))
+    }
+
+    pub fn volume_fingerprint(&self) -> fs::Result<VolumeFingerprint> {
+        let header = self.get_volume_header()?;
+        let mut finder_info = [0; SIZE_FINDER_INFO];
+        header.read_bytes(OFFSET_FINDER_INFO, &mut finder_info)?;
+        let mut raw = [0; SIZE_VOLUME_HEADER];
+        header.read_bytes(0, &mut raw)?;
+        let result = VolumeFingerprint {
+            finder_info,
+            write_count: header.get_write_count()?,
+            modify_date: header.read_number(20)?,
+            next_catalog_id: header.get_next_catalog_id()?,
+            header_hash: fnv1a(&raw),
+        };
+        Ok(result)
+    }
+
+    pub fn find_owner_of_block(&self, block: u32) -> fs::Result<OwnerSearch> {
+        self.find_owners_of_blocks(&[block])
+    }
+
+    // Searches the special files in the volume header, the file records in
+    // the catalog and the records in the extents overflow file for forks
+    // using any of the blocks. The trees are scanned node by node so that
+    // damaged nodes can be skipped, and the result says how many were.
+    pub fn find_owners_of_blocks(&self, blocks: &[u32]) -> fs::Result<OwnerSearch> {
+        let mut blocks = blocks.to_vec();
+        blocks.sort();
+        blocks.dedup();
+        let header = self.get_volume_header()?;
+        let snapshot = header.snapshot()?;
+        let block_size = snapshot.block_size;
+        let mut owners = Vec::new();
+        for &special in SPECIAL_FILES.iter() {
+            let extents = snapshot.get_special_file(special).extents.iter().cloned()
+                .take_while(|&(_, count)| count != 0);
+            ownership::match_extents(&mut owners, &blocks, extents, 0, block_size, special.get_cnid(), ForkType::Data);
+        }
+
+        // Folder IDs map to the parent ID and name of the folder, and file IDs
+        // to those of the file, for building paths afterwards.
+        let mut folders = HashMap::new();
+        let mut files = HashMap::new();
+        let catalog = header.get_btree_catalog().and_then(|mut tree| tree.scan_leaf_records(|key, data| {
+            if let Some(record) = FileRecord::from_record(key, data) {
+                for &fork_type in [ForkType::Data, ForkType::Resource].iter() {
+                    let extents = record.get_fork(fork_type).extents.iter().cloned().take_while(|&(_, count)| count != 0);
+                    ownership::match_extents(&mut owners, &blocks, extents, 0, block_size, record.file_id, fork_type);
+                }
+                files.insert(record.file_id, (record.parent_id, record.name));
+            } else if let (Some(folder_id), Some(key)) = (decode_folder_id(data), decode_catalog_key(key)) {
+                folders.insert(folder_id, key);
+            }
+        })).ok();
+
+        // Overflow extents are matched from the extents file directly rather
+        // than through the catalog, so they are found even when the file's
+        // catalog record is in a damaged node.
+        let extents_overflow = header.get_btree_extents()
+            .and_then(|mut tree| tree.scan_leaf_records(|key, data| {
+                if key.len() < 10 || data.len() < SIZE_EXTENT_RECORD as usize {
+                    return;
+                }
+                let fork_type = match ForkType::from_raw(key[0]) {
+                    Some(fork_type) => fork_type,
+                    None => return,
+                };
+                let extents = (0..NUM_EXTENT_DESCRIPTORS).map(|idx| (be_u32(data, idx * 8), be_u32(data, idx * 8 + 4)))
+                    .take_while(|&(_, count)| count != 0);
+                ownership::match_extents(&mut owners, &blocks, extents, be_u32(key, 6) as u64, block_size,
+                                         be_u32(key, 2), fork_type);
+            })).ok();
+
+        for owner in owners.iter_mut() {
+            owner.path = files.get(&owner.cnid).and_then(|&(parent_id, ref name)| {
+                catalog_path(&folders, parent_id, name)
+            });
+        }
+        owners.sort_by_key(|owner| (owner.block, owner.cnid, owner.fork_type));
+        let result = OwnerSearch {
+            owners,
+            catalog,
+            extents_overflow,
+        };
+        Ok(result)
+    }
+
+    // Looks for volumes whose partitions start at sector-aligned offsets in
+    // [start, end) spaced step bytes apart, returning the partition offset of
+    // each plausible header found. Unreadable areas are skipped. The callback
+    // is given the offset scanned up to so far. The step must be a non-zero
+    // multiple of the 512 byte sector size.
+    pub fn scan_for_volume_headers<P>(source: &mut F, start: u64, end: u64, step: u64, mut progress: P)
+        -> fs::Result<Vec<(u64, VolumeHeaderSnapshot)>> where P: FnMut(u64) {
+        if step == 0 || !step.is_multiple_of(SIZE_HFS_SECTOR) {
+            return Err(HFSPError::InvalidArgument("scan step must be a non-zero multiple of the sector size"));
+        }
+        let chunk_size = cmp::max(step, SCAN_CHUNK_SIZE / step * step);
+        let mut result = Vec::new();
+        let mut buffer = Vec::new();
+        let mut chunk_start = start.div_ceil(SIZE_HFS_SECTOR) * SIZE_HFS_SECTOR;
+        while chunk_start < end {
+            let chunk_end = cmp::min(chunk_start.saturating_add(chunk_size), end);
+            buffer.resize((chunk_end - chunk_start) as usize + SIZE_VOLUME_HEADER, 0);
+            let available = source.seek(SeekFrom::Start(chunk_start + OFFSET_VOLUME_HEADER))
+                .and_then(|_| fs::read_fully(source, &mut buffer));
+            let mut candidate = chunk_start;
+            while candidate < chunk_end {
+                let index = (candidate - chunk_start) as usize;
+                let header = match available {
+                    Ok(available) if index + SIZE_VOLUME_HEADER <= available => {
+                        Some(VolumeHeaderSnapshot::from_bytes(&buffer[index..index + SIZE_VOLUME_HEADER]))
+                    },
+                    Ok(_) => None,
+                    // Fall back to reading candidates one at a time so a bad
+                    // sector only hides the headers it actually covers.
+                    Err(_) => read_header_at(source, candidate + OFFSET_VOLUME_HEADER),
+                };
+                if let Some(header) = header {
+                    if is_plausible_header(&header) {
+                        result.push((candidate, header));
+                    }
+                }
+                candidate += step;
+            }
+            progress(chunk_end);
+            chunk_start = candidate;
+        }
+        Ok(result)
+    }
+}
+
+pub fn is_valid_block_size(block_size: u32) -> bool {
+    block_size.is_power_of_two() && (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+}
+
+fn read_header_at<F>(source: &mut F, offset: u64) -> Option<VolumeHeaderSnapshot> where F: Read + Seek {
+    let mut raw = [0; SIZE_VOLUME_HEADER];
+    source.seek(SeekFrom::Start(offset)).ok()?;
+    source.read_exact(&mut raw).ok()?;
+    Some(VolumeHeaderSnapshot::from_bytes(&raw))
+}
+
+fn is_plausible_header(header: &VolumeHeaderSnapshot) -> bool {
+    let expected_version = match &header.signature {
+        SIGNATURE_HFS_PLUS => VERSION_HFS_PLUS,
+        SIGNATURE_HFSX => VERSION_HFSX,
+        _ => return false,
+    };
+    header.version == expected_version && is_valid_block_size(header.block_size) &&
+        header.total_blocks != 0 && header.free_blocks <= header.total_blocks
+}
+
+#[cfg(feature = "repair")]
+impl<F> FileSystem<F> where F: Read + Write + Seek {
+    // Only file systems created this way will accept writes. The source must
+    // itself have been opened for writing.
+    pub fn new_writable(file: F) -> FileSystem<F> {
+        FileSystem {
+            file: Mutex::new(file),
+            writable: true,
+            geometry: Mutex::new(None),
+            fork_size_limit: ForkSizeLimit::default(),
+        }
+    }
+}
+
+impl<F> FileSystem<FileSlice<F>> where F: Read + Seek {
+    // Opens either a bare HFS+/HFSX volume or one embedded in an HFS wrapper,
+    // slicing the source onto the HFS+ volume in the latter case.
+    pub fn open_embedded(mut file: F) -> fs::Result<FileSystem<FileSlice<F>>> {
+        let mut mdb = [0; SIZE_MDB];
+        file.seek(SeekFrom::Start(OFFSET_VOLUME_HEADER))?;
+        file.read_exact(&mut mdb)?;
+        let signature = &mdb[0..2];
+        if signature == SIGNATURE_HFS_PLUS || signature == SIGNATURE_HFSX {
+            return Ok(FileSystem::new(FileSlice::new(file, 0, None)?));
+        }
+        if signature != SIGNATURE_HFS {
+            return Err(HFSPError::InvalidVolumeHeader);
+        }
+        if &mdb[OFFSET_MDB_EMBED_SIGNATURE..OFFSET_MDB_EMBED_SIGNATURE + 2] != SIGNATURE_HFS_PLUS {
+            return Err(HFSPError::PlainHFSNotSupported);
+        }
+        let block_size = be_u32(&mdb, OFFSET_MDB_ALLOCATION_BLOCK_SIZE) as u64;
+        let first_block_sector = be_u16(&mdb, OFFSET_MDB_ALLOCATION_BLOCK_START) as u64;
+        let start_block = be_u16(&mdb, OFFSET_MDB_EMBED_EXTENT) as u64;
+        let block_count = be_u16(&mdb, OFFSET_MDB_EMBED_EXTENT + 2) as u64;
+        let offset = first_block_sector * SIZE_HFS_SECTOR + start_block * block_size;
+        let length = block_count * block_size;
+        Ok(FileSystem::new(FileSlice::new(file, offset, Some(length))?))
+    }
+}
+
+impl FileSystem<fs::DynSource> {
+    pub fn new_dyn<S>(source: S) -> fs::DynFileSystem where S: fs::Source + Send + 'static {
+        FileSystem::new(Box::new(source))
     }
+}
 
+impl<T> FileSystem<Cursor<T>> where T: AsRef<[u8]> {
+    // For images or metadata dumps held in memory, owned or borrowed.
+    pub fn from_bytes(data: T) -> FileSystem<Cursor<T>> {
+        FileSystem::new(Cursor::new(data))
+    }
 }
 
 #[derive(Debug)]
@@ -118,19 +496,104 @@ impl<'a, F> Structure<F> for VolumeHeader<'a, F> where F: 'a {
 impl<'a, F> VolumeHeader<'a, F> where F: Read + Seek {
     fn new(parent: &'a FileSystem<F>, offset: u64) -> VolumeHeader<'a, F> {
         VolumeHeader {
-            parent: parent,
-            offset: offset,
+            parent,
+            offset,
         }
     }
 
     fn validate(&self) -> fs::Result<()> {
-        self.parent.validate_bytes(self.offset, b"H+")
+        let mut signature = [0; 2];
+        self.read_bytes(0, &mut signature)?;
+        let expected_version = match &signature {
+            SIGNATURE_HFS_PLUS => VERSION_HFS_PLUS,
+            SIGNATURE_HFSX => VERSION_HFSX,
+            _ => return Err(HFSPError::InvalidVolumeHeader),
+        };
+        let version = self.get_version()?;
+        if version != VERSION_HFS_PLUS && version != VERSION_HFSX {
+            return Err(HFSPError::UnsupportedVersion(version));
+        }
+        if version != expected_version {
+            return Err(HFSPError::SignatureVersionMismatch);
+        }
+        let block_size = self.get_block_size()?;
+        if !is_valid_block_size(block_size) {
+            return Err(HFSPError::InvalidBlockSize(block_size));
+        }
+        Ok(())
+    }
+
+    pub fn check_consistency(&self) -> fs::Result<Vec<ConsistencyIssue>> {
+        let snapshot = self.snapshot()?;
+        let partition_bytes = self.parent.file.lock().unwrap().seek(SeekFrom::End(0))?;
+        Ok(consistency::check_header(&snapshot, partition_bytes))
+    }
+
+    pub fn get_signature(&self) -> fs::Result<[u8; 2]> {
+        let mut signature = [0; 2];
+        self.read_bytes(0, &mut signature)?;
+        Ok(signature)
+    }
+
+    pub fn is_hfsx(&self) -> fs::Result<bool> {
+        Ok(&self.get_signature()? == SIGNATURE_HFSX)
+    }
+
+    // HFS+ volumes are always case-insensitive. HFSX volumes may be either,
+    // as recorded in the header of the catalog B-tree.
+    pub fn is_case_sensitive(&self) -> fs::Result<bool> {
+        if !self.is_hfsx()? {
+            return Ok(false);
+        }
+        Ok(self.get_btree_catalog()?.header()?.has_binary_compare())
+    }
+
+    pub fn snapshot(&self) -> fs::Result<VolumeHeaderSnapshot> {
+        let mut raw = [0; SIZE_VOLUME_HEADER];
+        self.read_bytes(0, &mut raw)?;
+        Ok(VolumeHeaderSnapshot::from_bytes(&raw))
     }
 
     pub fn get_version(&self) -> fs::Result<u16> {
         self.read_number(2)
     }
 
+    pub fn get_attributes_raw(&self) -> fs::Result<u32> {
+        self.read_number(4)
+    }
+
+    pub fn get_attributes(&self) -> fs::Result<VolumeAttributes> {
+        Ok(VolumeAttributes::new(self.get_attributes_raw()?))
+    }
+
+    pub fn get_last_mounted_version(&self) -> fs::Result<FourCC> {
+        let mut code = [0; 4];
+        self.read_bytes(8, &mut code)?;
+        Ok(FourCC::new(code))
+    }
+
+    pub fn get_last_mounted_by(&self) -> fs::Result<LastMounted> {
+        Ok(LastMounted::from_fourcc(self.get_last_mounted_version()?))
+    }
+
+    pub fn get_journal_info_block(&self) -> fs::Result<u32> {
+        self.read_number(12)
+    }
+
+    pub fn get_journal_info(&self) -> fs::Result<JournalInfoBlock<'a, F>> {
+        let block = self.get_journal_info_block()?;
+        let block_size = self.get_block_size()?;
+        Ok(JournalInfoBlock::new(self.parent, block, block_size))
+    }
+
+    pub fn get_finder_info(&self) -> fs::Result<FinderInfo> {
+        let mut words = [0; 8];
+        for (idx, word) in words.iter_mut().enumerate() {
+            *word = self.read_number(OFFSET_FINDER_INFO + idx * 4)?;
+        }
+        Ok(FinderInfo::new(words))
+    }
+
     pub fn get_file_count(&self) -> fs::Result<u32> {
         self.read_number(32)
     }
@@ -151,288 +614,2532 @@ impl<'a, F> VolumeHeader<'a, F> where F: Read + Seek {
         self.read_number(48)
     }
 
-    pub fn get_modify_date(&self) -> fs::Result<chrono::DateTime<chrono::Local>> {
+    pub fn get_capacity(&self) -> fs::Result<u64> {
+        Ok(self.get_total_blocks()? as u64 * self.get_block_size()? as u64)
+    }
+
+    pub fn get_used_bytes(&self) -> fs::Result<u64> {
+        let used_blocks = self.get_total_blocks()?.saturating_sub(self.get_free_blocks()?);
+        Ok(used_blocks as u64 * self.get_block_size()? as u64)
+    }
+
+    // The number of blocks the allocation bitmap has room to describe.
+    pub fn get_allocation_file_coverage(&self) -> fs::Result<u64> {
+        Ok(self.get_fork_data_allocation().get_logical_size()?.saturating_mul(8))
+    }
+
+    pub fn get_next_catalog_id(&self) -> fs::Result<u32> {
+        self.read_number(64)
+    }
+
+    pub fn get_write_count(&self) -> fs::Result<u32> {
+        self.read_number(68)
+    }
+
+    // Unlike the other dates in the header, this one is stored in local time.
+    pub fn get_create_date(&self) -> fs::Result<HFSDate> {
+        self.read_date(16, true)
+    }
+
+    pub fn get_modify_date(&self) -> fs::Result<HFSDate> {
         self.read_date(20, false)
     }
 
-    pub fn get_backup_date(&self) -> fs::Result<chrono::DateTime<chrono::Local>> {
+    pub fn get_backup_date(&self) -> fs::Result<HFSDate> {
         self.read_date(24, false)
     }
 
-    pub fn get_checked_date(&self) -> fs::Result<chrono::DateTime<chrono::Local>> {
-        self.read_date(24, false)
+    pub fn get_checked_date(&self) -> fs::Result<HFSDate> {
+        self.read_date(28, false)
+    }
+
+    pub fn get_encodings_bitmap(&self) -> fs::Result<u64> {
+        self.read_number(72)
+    }
+
+    pub fn get_encodings(&self) -> fs::Result<Vec<TextEncoding>> {
+        Ok(decode_encodings(self.get_encodings_bitmap()?))
+    }
+
+    // The fork data of the special file at the index in the header.
+    fn fork_data(&self, index: u64, file_id: u32) -> ForkData<'a, F> {
+        ForkData::new(self.parent, self.offset, self.offset + OFFSET_VOLUME_HEADER_FORKS + SIZE_FORK_DATA * index, file_id)
     }
 
     pub fn get_fork_data_allocation(&self) -> ForkData<'a, F> {
-        ForkData::new(self.parent, self.offset + OFFSET_VOLUME_HEADER_FORKS)
+        self.fork_data(0, CNID_ALLOCATION_FILE)
     }
 
     pub fn get_file_allocation(&self) -> fs::Result<HFSFile<'a, F>> {
-        HFSFile::new(self.parent, self.get_fork_data_allocation())
+        HFSFile::new(self.get_fork_data_allocation())
     }
 
     pub fn get_fork_data_extents(&self) -> ForkData<'a, F> {
-        ForkData::new(self.parent, self.offset + OFFSET_VOLUME_HEADER_FORKS + SIZE_FORK_DATA)
+        self.fork_data(1, CNID_EXTENTS_FILE)
     }
 
     pub fn get_file_extents(&self) -> fs::Result<HFSFile<'a, F>> {
-        HFSFile::new(self.parent, self.get_fork_data_extents())
+        HFSFile::new(self.get_fork_data_extents())
     }
 
     pub fn get_fork_data_catalog(&self) -> ForkData<'a, F> {
-        ForkData::new(self.parent, self.offset + OFFSET_VOLUME_HEADER_FORKS + SIZE_FORK_DATA * 2)
+        self.fork_data(2, CNID_CATALOG_FILE)
     }
 
     pub fn get_file_catalog(&self) -> fs::Result<HFSFile<'a, F>> {
-        HFSFile::new(self.parent, self.get_fork_data_catalog())
+        HFSFile::new(self.get_fork_data_catalog())
     }
 
-    pub fn get_btree_catalog(&self) -> fs::Result<BTree<HFSFile<'a, F>>> {
+    pub fn get_btree_catalog(&self) -> fs::Result<CatalogTree<HFSFile<'a, F>>> {
         Ok(BTree::new(self.get_file_catalog()?))
     }
 
+    pub fn get_btree_extents(&self) -> fs::Result<ExtentsTree<HFSFile<'a, F>>> {
+        Ok(BTree::new(self.get_file_extents()?))
+    }
+
+    // Covers the data and resource forks of every file in the catalog. Forks
+    // whose extents can't be determined are counted as unreadable.
+    pub fn get_fragmentation_histogram(&self) -> fs::Result<FragmentationHistogram> {
+        let geometry = self.parent.get_header_geometry(self.offset)?;
+        let block_size = geometry.block_size;
+        let mut extents_tree = None;
+        let mut histogram = FragmentationHistogram::new();
+        self.get_btree_catalog()?.walk_leaf_records(|key, data| {
+            let record = match FileRecord::from_record(key, data) {
+                Some(record) => record,
+                None => return Ok(()),
+            };
+            for &fork_type in [ForkType::Data, ForkType::Resource].iter() {
+                let fork = record.get_fork(fork_type);
+                if fork.logical_size == 0 {
+                    continue;
+                }
+                match collect_fork_extents(self.parent, fork, record.file_id, fork_type, &geometry, &mut extents_tree) {
+                    Ok(extents) => {
+                        let report = FragmentationReport::new(&extents, fork.logical_size, block_size);
+                        histogram.add(record.file_id, &report);
+                    },
+                    Err(_) => histogram.add_unreadable(),
+                }
+            }
+            Ok(())
+        })?;
+        Ok(histogram)
+    }
+
+    // Every file record in the catalog, in key order.
+    pub fn get_file_records(&self) -> fs::Result<Vec<FileRecord>> {
+        let mut result = Vec::new();
+        self.get_btree_catalog()?.walk_leaf_records(|key, data| {
+            result.extend(FileRecord::from_record(key, data));
+            Ok(())
+        })?;
+        Ok(result)
+    }
+
+    // Finds a file's record by walking the catalog, so this is slow on large
+    // volumes.
+    pub fn find_file_record(&self, file_id: u32) -> fs::Result<FileRecord> {
+        let mut result = None;
+        self.get_btree_catalog()?.walk_leaf_records(|key, data| {
+            if result.is_none() {
+                result = FileRecord::from_record(key, data).filter(|record| record.file_id == file_id);
+            }
+            Ok(())
+        })?;
+        result.ok_or(HFSPError::MissingCatalogRecord)
+    }
+
+    // The name is kept in the root folder's thread record in the catalog.
+    pub fn get_volume_name(&self) -> fs::Result<String> {
+        self.get_btree_catalog()?.find_thread_name(CNID_ROOT_FOLDER)
+    }
+
     pub fn get_fork_data_attributes(&self) -> ForkData<'a, F> {
-        ForkData::new(self.parent, self.offset + OFFSET_VOLUME_HEADER_FORKS + SIZE_FORK_DATA * 3)
+        self.fork_data(3, CNID_ATTRIBUTES_FILE)
     }
 
     pub fn get_file_attributes(&self) -> fs::Result<HFSFile<'a, F>> {
-        HFSFile::new(self.parent, self.get_fork_data_attributes())
+        HFSFile::new(self.get_fork_data_attributes())
+    }
+
+    pub fn get_btree_attributes(&self) -> fs::Result<AttributesTree<HFSFile<'a, F>>> {
+        Ok(BTree::new(self.get_file_attributes()?))
     }
 
     pub fn get_fork_data_startup(&self) -> ForkData<'a, F> {
-        ForkData::new(self.parent, self.offset + OFFSET_VOLUME_HEADER_FORKS + SIZE_FORK_DATA * 4)
+        self.fork_data(4, CNID_STARTUP_FILE)
     }
 
     pub fn get_file_startup(&self) -> fs::Result<HFSFile<'a, F>> {
-        HFSFile::new(self.parent, self.get_fork_data_startup())
+        HFSFile::new(self.get_fork_data_startup())
     }
 }
 
 impl<'a, F> Display for VolumeHeader<'a, F> where F: Read + Seek {
     fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
-        writeln!(fmt, "Version: {:?}", self.get_version())?;
-        writeln!(fmt, "Folder count: {:?}", self.get_folder_count())?;
-        writeln!(fmt, "Modify date: {:?}", self.get_modify_date())?;
-        writeln!(fmt, "Backup date: {:?}", self.get_backup_date())?;
-        writeln!(fmt, "Checked date: {:?}", self.get_checked_date())?;
-        writeln!(fmt, "File count: {:?}", self.get_file_count())?;
-        writeln!(fmt, "Block size: {:?}", self.get_block_size())?;
-        writeln!(fmt, "Total blocks: {:?}", self.get_total_blocks())?;
-        writeln!(fmt, "Free blocks: {:?}", self.get_free_blocks())?;
+        writeln!(fmt, "Version: {}", Field(self.get_version()))?;
+        writeln!(fmt, "Attributes: {}", Field(self.get_attributes()))?;
+        writeln!(fmt, "Last mounted version: {}", Field(self.get_last_mounted_version()))?;
+        writeln!(fmt, "Last mounted by: {}", Field(self.get_last_mounted_by()))?;
+        match self.get_finder_info() {
+            Ok(finder_info) => {
+                writeln!(fmt, "Blessed folder: {}", finder_info.get_blessed_folder_cnid())?;
+                writeln!(fmt, "Volume ID: {:016x}", finder_info.get_volume_id())?;
+                #[cfg(feature = "hashing")]
+                writeln!(fmt, "Volume UUID: {}", finder_info.get_volume_uuid().unwrap_or_else(|| "none".to_string()))?;
+            },
+            Err(e) => writeln!(fmt, "Finder info: {}", Field::<String>(Err(e)))?,
+        }
+        writeln!(fmt, "Folder count: {}", Field(self.get_folder_count()))?;
+        writeln!(fmt, "Create date: {}", Field(self.get_create_date()))?;
+        writeln!(fmt, "Modify date: {}", Field(self.get_modify_date()))?;
+        writeln!(fmt, "Backup date: {}", Field(self.get_backup_date()))?;
+        writeln!(fmt, "Checked date: {}", Field(self.get_checked_date()))?;
+        writeln!(fmt, "File count: {}", Field(self.get_file_count()))?;
+        writeln!(fmt, "Block size: {}", Field(self.get_block_size()))?;
+        writeln!(fmt, "Total blocks: {}", Field(self.get_total_blocks()))?;
+        writeln!(fmt, "Free blocks: {}", Field(self.get_free_blocks()))?;
+        writeln!(fmt, "Write count: {}", Field(self.get_write_count()))?;
+        writeln!(fmt, "Encodings: {}", Field(self.get_encodings().map(|e| format!("{:?}", e))))?;
+
+        writeln!(fmt, "Capacity: {}", Field(self.get_capacity().map(ByteSize)))?;
+        let used = self.get_used_bytes().and_then(|used| {
+            Ok(format!("{}, {}", ByteSize(used), Percentage(used, self.get_capacity()?)))
+        });
+        writeln!(fmt, "Used space: {}", Field(used))?;
+        let coverage = self.get_allocation_file_coverage().and_then(|covered| {
+            let total = self.get_total_blocks()? as u64;
+            Ok(format!("{} of {} blocks, {}", covered, total, Percentage(cmp::min(covered, total), total)))
+        });
+        writeln!(fmt, "Allocation file coverage: {}", Field(coverage))?;
         Ok(())
     }
 }
 
-#[derive(Debug)]
-pub struct ForkData<'a, F> where F: 'a {
-    parent: &'a FileSystem<F>,
-    offset: u64,
+// Displays a value read from disk, or a marker if the read failed.
+struct Field<T>(fs::Result<T>);
+
+impl<T> Display for Field<T> where T: Display {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match self.0 {
+            Ok(ref value) => write!(fmt, "{}", value),
+            Err(ref e) => write!(fmt, "<unreadable: {}>", e),
+        }
+    }
 }
 
-impl<'a, F> Structure<F> for ForkData<'a, F> where F: 'a {
-    fn get_offset(&self) -> u64 {
-        self.offset
+struct ByteSize(u64);
+
+impl Display for ByteSize {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(fmt, "{:.1} GiB ({} bytes)", self.0 as f64 / (1u64 << 30) as f64, self.0)
     }
+}
 
-    fn get_filesystem(&self) -> &FileSystem<F> {
-        self.parent
+struct Percentage(u64, u64);
+
+impl Display for Percentage {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match self.1 {
+            0 => write!(fmt, "-%"),
+            total => write!(fmt, "{:.1}%", self.0 as f64 * 100.0 / total as f64),
+        }
     }
 }
 
+// The namespace macOS hashes the 64-bit volume identifier into when deriving
+// the volume UUID shown by Disk Utility.
+#[cfg(feature = "hashing")]
+const VOLUME_UUID_NAMESPACE: [u8; 16] = [
+    0xb3, 0xe2, 0x0f, 0x39, 0xf2, 0x92, 0x11, 0xd6, 0x97, 0xa4, 0x00, 0x30, 0x65, 0x43, 0xec, 0xac,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct FinderInfo {
+    words: [u32; 8],
+}
 
-impl<'a, F> ForkData<'a, F> where F: Read + Seek {
-    fn new(parent: &'a FileSystem<F>, offset: u64) -> ForkData<'a, F> {
-        ForkData {
-            parent: parent,
-            offset: offset,
+impl FinderInfo {
+    pub fn new(words: [u32; 8]) -> FinderInfo {
+        FinderInfo {
+            words,
         }
     }
 
-    pub fn get_logical_size(&self) -> fs::Result<u64> {
-        self.read_number(0)
+    pub fn get_words(&self) -> [u32; 8] {
+        self.words
     }
 
-    pub fn get_clump_size(&self) -> fs::Result<u32> {
-        self.read_number(8)
+    pub fn get_blessed_folder_cnid(&self) -> u32 {
+        self.words[0]
     }
 
-    pub fn get_total_blocks(&self) -> fs::Result<u32> {
-        self.read_number(12)
+    pub fn get_startup_app_cnid(&self) -> u32 {
+        self.words[1]
     }
 
-    pub fn num_extent_descriptors(&self) -> usize {
-        8
+    pub fn get_open_folder_cnid(&self) -> u32 {
+        self.words[2]
     }
 
-    pub fn get_extent_descriptor(&self, index: usize) -> ExtentDescriptor<'a, F> {
-        assert!(index < self.num_extent_descriptors());
-        ExtentDescriptor::new(self.parent,
-                              self.offset + OFFSET_FORK_DATA_EXTENT_RECORD + SIZE_EXTENT_RECORD * index as u64)
+    pub fn get_os9_system_folder_cnid(&self) -> u32 {
+        self.words[3]
     }
-}
 
-impl<'a, F> Display for ForkData<'a, F> where F: Read + Seek {
-    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
-        writeln!(fmt, "Logical size: {:?}", self.get_logical_size())?;
-        writeln!(fmt, "Clump size: {:?}", self.get_clump_size())?;
-        writeln!(fmt, "Total blocks: {:?}", self.get_total_blocks())?;
-        Ok(())
+    pub fn get_osx_system_folder_cnid(&self) -> u32 {
+        self.words[5]
+    }
+
+    pub fn get_volume_id(&self) -> u64 {
+        (self.words[6] as u64) << 32 | self.words[7] as u64
+    }
+
+    // Volumes that never had an identifier assigned have no UUID. It is
+    // derived with MD5, so needs the hashing feature.
+    #[cfg(feature = "hashing")]
+    pub fn get_volume_uuid(&self) -> Option<String> {
+        let volume_id = self.get_volume_id();
+        if volume_id == 0 {
+            return None;
+        }
+        let mut data = VOLUME_UUID_NAMESPACE.to_vec();
+        data.extend_from_slice(&volume_id.to_be_bytes());
+        let mut uuid = md5::digest(&data);
+        uuid[6] = (uuid[6] & 0x0f) | 0x30;
+        uuid[8] = (uuid[8] & 0x3f) | 0x80;
+        let hex: Vec<String> = uuid.iter().map(|b| format!("{:02X}", b)).collect();
+        Some(format!("{}-{}-{}-{}-{}", hex[0..4].concat(), hex[4..6].concat(), hex[6..8].concat(),
+                     hex[8..10].concat(), hex[10..16].concat()))
     }
 }
 
-#[derive(Debug)]
-pub struct ExtentDescriptor<'a, F> where F: 'a {
-    parent: &'a FileSystem<F>,
-    offset: u64,
+// Bits are numbered by Mac text encoding, except that MacUkrainian (152) and
+// MacFarsi (140) are folded down to bits 48 and 49.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum TextEncoding {
+    MacRoman,
+    MacJapanese,
+    MacChineseTrad,
+    MacKorean,
+    MacArabic,
+    MacHebrew,
+    MacGreek,
+    MacCyrillic,
+    MacDevanagari,
+    MacGurmukhi,
+    MacGujarati,
+    MacOriya,
+    MacBengali,
+    MacTamil,
+    MacTelugu,
+    MacKannada,
+    MacMalayalam,
+    MacSinhalese,
+    MacBurmese,
+    MacKhmer,
+    MacThai,
+    MacLaotian,
+    MacGeorgian,
+    MacArmenian,
+    MacChineseSimp,
+    MacTibetan,
+    MacMongolian,
+    MacEthiopic,
+    MacCentralEurRoman,
+    MacVietnamese,
+    MacExtArabic,
+    MacSymbol,
+    MacDingbats,
+    MacTurkish,
+    MacCroatian,
+    MacIcelandic,
+    MacRomanian,
+    MacCeltic,
+    MacGaelic,
+    MacUkrainian,
+    MacFarsi,
+    Unknown(u8),
 }
 
-impl<'a, F> Structure<F> for ExtentDescriptor<'a, F> where F: 'a {
-    fn get_offset(&self) -> u64 {
-        self.offset
+impl TextEncoding {
+    pub fn from_bit(bit: u8) -> TextEncoding {
+        match bit {
+            0 => TextEncoding::MacRoman,
+            1 => TextEncoding::MacJapanese,
+            2 => TextEncoding::MacChineseTrad,
+            3 => TextEncoding::MacKorean,
+            4 => TextEncoding::MacArabic,
+            5 => TextEncoding::MacHebrew,
+            6 => TextEncoding::MacGreek,
+            7 => TextEncoding::MacCyrillic,
+            9 => TextEncoding::MacDevanagari,
+            10 => TextEncoding::MacGurmukhi,
+            11 => TextEncoding::MacGujarati,
+            12 => TextEncoding::MacOriya,
+            13 => TextEncoding::MacBengali,
+            14 => TextEncoding::MacTamil,
+            15 => TextEncoding::MacTelugu,
+            16 => TextEncoding::MacKannada,
+            17 => TextEncoding::MacMalayalam,
+            18 => TextEncoding::MacSinhalese,
+            19 => TextEncoding::MacBurmese,
+            20 => TextEncoding::MacKhmer,
+            21 => TextEncoding::MacThai,
+            22 => TextEncoding::MacLaotian,
+            23 => TextEncoding::MacGeorgian,
+            24 => TextEncoding::MacArmenian,
+            25 => TextEncoding::MacChineseSimp,
+            26 => TextEncoding::MacTibetan,
+            27 => TextEncoding::MacMongolian,
+            28 => TextEncoding::MacEthiopic,
+            29 => TextEncoding::MacCentralEurRoman,
+            30 => TextEncoding::MacVietnamese,
+            31 => TextEncoding::MacExtArabic,
+            33 => TextEncoding::MacSymbol,
+            34 => TextEncoding::MacDingbats,
+            35 => TextEncoding::MacTurkish,
+            36 => TextEncoding::MacCroatian,
+            37 => TextEncoding::MacIcelandic,
+            38 => TextEncoding::MacRomanian,
+            39 => TextEncoding::MacCeltic,
+            40 => TextEncoding::MacGaelic,
+            48 => TextEncoding::MacUkrainian,
+            49 => TextEncoding::MacFarsi,
+            _ => TextEncoding::Unknown(bit),
+        }
     }
+}
 
-    fn get_filesystem(&self) -> &FileSystem<F> {
-        self.parent
-    }
+pub fn decode_encodings(bitmap: u64) -> Vec<TextEncoding> {
+    (0..64u8).filter(|bit| bitmap & (1 << bit) != 0).map(TextEncoding::from_bit).collect()
 }
 
-impl<'a, F> ExtentDescriptor<'a, F> where F: Read + Seek {
-    fn new(parent: &'a FileSystem<F>, offset: u64) -> ExtentDescriptor<'a, F> {
-        ExtentDescriptor {
-            parent: parent,
-            offset: offset,
-        }
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct FourCC([u8; 4]);
+
+impl FourCC {
+    pub fn new(code: [u8; 4]) -> FourCC {
+        FourCC(code)
     }
 
-    pub fn get_start_block(&self) -> fs::Result<u32> {
-        self.read_number(0)
+    pub fn get_bytes(&self) -> [u8; 4] {
+        self.0
     }
 
-    pub fn get_block_count(&self) -> fs::Result<u32> {
-        self.read_number(4)
+    pub fn get_raw(&self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        str::from_utf8(&self.0).ok()
     }
 }
 
-impl<'a, F> Display for ExtentDescriptor<'a, F> where F: Read + Seek {
+impl Display for FourCC {
     fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
-        writeln!(fmt, "Start block: {:?}", self.get_start_block())?;
-        writeln!(fmt, "Block count: {:?}", self.get_block_count())?;
+        for &byte in &self.0 {
+            match byte {
+                b'\\' => write!(fmt, "\\\\")?,
+                0x20..=0x7e => write!(fmt, "{}", byte as char)?,
+                _ => write!(fmt, "\\x{:02x}", byte)?,
+            }
+        }
         Ok(())
     }
 }
 
-#[derive(Debug)]
-pub struct HFSFile<'a, F> where F: 'a {
-    parent: &'a FileSystem<F>,
-    length: u64,
-    block_size: u64,
-    offsets: Vec<(u64, u32)>,
-    offset: u64,
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum LastMounted {
+    MacOS8,
+    MacOSX,
+    MacOSXJournaled,
+    Fsck,
+    Linux,
+    Unknown(FourCC),
 }
 
-impl<'a, F> HFSFile<'a, F> where F: Read + Seek {
-    // TODO: Extent overflow support
-    // TODO: Read truncated files when later extents are damaged
-    fn new(parent: &'a FileSystem<F>, fork_data: ForkData<'a, F>) -> fs::Result<HFSFile<'a, F>> {
-        let length = fork_data.get_logical_size()?;
-        let block_size = parent.get_volume_header()?.get_block_size()?;
-
-        let mut offsets = Vec::new();
-        let mut seen_blocks = 0;
-        for idx in 0..(fork_data.num_extent_descriptors()) {
-            let end_offset_bytes = seen_blocks as u64 * block_size as u64;
-            if end_offset_bytes >= length {
-                break;
-            }
-            let descriptor = fork_data.get_extent_descriptor(idx);
-            offsets.push((end_offset_bytes, descriptor.get_start_block()?));
-            seen_blocks += descriptor.get_block_count()?;
+impl LastMounted {
+    pub fn from_fourcc(code: FourCC) -> LastMounted {
+        match &code.get_bytes() {
+            b"8.10" => LastMounted::MacOS8,
+            b"10.0" => LastMounted::MacOSX,
+            b"HFSJ" => LastMounted::MacOSXJournaled,
+            b"fsck" | b"FSK!" => LastMounted::Fsck,
+            b"H+Lx" => LastMounted::Linux,
+            _ => LastMounted::Unknown(code),
         }
+    }
 
-        let end_offset_bytes = seen_blocks as u64 * block_size as u64;
-        if end_offset_bytes < length {
-            return Err(HFSPError::ExtentOverflowNotSupported);
+    // Whether volumes last mounted by this implementation can be trusted to
+    // have been written correctly. The Linux driver doesn't replay or update
+    // the journal, and has been seen to corrupt journaled volumes.
+    pub fn writes_supported(&self) -> bool {
+        match *self {
+            LastMounted::MacOS8 | LastMounted::MacOSX | LastMounted::MacOSXJournaled | LastMounted::Fsck => true,
+            LastMounted::Linux | LastMounted::Unknown(_) => false,
         }
+    }
+}
 
-        let result = HFSFile {
-            parent: parent,
-            block_size: block_size as u64,
-            length: length,
-            offsets: offsets,
-            offset: 0,
-        };
-        Ok(result)
+impl Display for LastMounted {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match *self {
+            LastMounted::MacOS8 => write!(fmt, "Mac OS 8.1 to 9.2.2"),
+            LastMounted::MacOSX => write!(fmt, "Mac OS X"),
+            LastMounted::MacOSXJournaled => write!(fmt, "Mac OS X (journaled)"),
+            LastMounted::Fsck => write!(fmt, "fsck_hfs"),
+            LastMounted::Linux => write!(fmt, "Linux"),
+            LastMounted::Unknown(code) => write!(fmt, "unknown ({})", code),
+        }
     }
 }
 
-impl<'a, F> Read for HFSFile<'a, F> where F: Read + Seek {
-    fn read(&mut self, buf: &mut[u8]) -> io::Result<usize> {
-        if self.offset > self.length {
-            panic!("Cannot read beyond end of file");
+const JOURNAL_IN_FS: u32 = 1;
+const JOURNAL_ON_OTHER_DEVICE: u32 = 1 << 1;
+const JOURNAL_NEED_INIT: u32 = 1 << 2;
+pub const SIZE_JOURNAL_DEVICE_SIGNATURE: usize = 32;
+const SIZE_JOURNAL_INFO_BLOCK: usize = 52;
+
+const ATTRIBUTE_HARDWARE_LOCK: u32 = 1 << 7;
+const ATTRIBUTE_UNMOUNTED: u32 = 1 << 8;
+const ATTRIBUTE_SPARED_BLOCKS: u32 = 1 << 9;
+const ATTRIBUTE_NO_CACHE_REQUIRED: u32 = 1 << 10;
+const ATTRIBUTE_BOOT_VOLUME_INCONSISTENT: u32 = 1 << 11;
+const ATTRIBUTE_CATALOG_NODE_IDS_REUSED: u32 = 1 << 12;
+const ATTRIBUTE_JOURNALED: u32 = 1 << 13;
+const ATTRIBUTE_SOFTWARE_LOCK: u32 = 1 << 15;
+
+const ATTRIBUTE_NAMES: [(u32, &str); 8] = [
+    (ATTRIBUTE_HARDWARE_LOCK, "hardware-locked"),
+    (ATTRIBUTE_UNMOUNTED, "unmounted"),
+    (ATTRIBUTE_SPARED_BLOCKS, "spared-blocks"),
+    (ATTRIBUTE_NO_CACHE_REQUIRED, "no-cache-required"),
+    (ATTRIBUTE_BOOT_VOLUME_INCONSISTENT, "boot-volume-inconsistent"),
+    (ATTRIBUTE_CATALOG_NODE_IDS_REUSED, "catalog-node-ids-reused"),
+    (ATTRIBUTE_JOURNALED, "journaled"),
+    (ATTRIBUTE_SOFTWARE_LOCK, "software-locked"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct VolumeAttributes {
+    raw: u32,
+}
+
+impl VolumeAttributes {
+    pub fn new(raw: u32) -> VolumeAttributes {
+        VolumeAttributes {
+            raw,
         }
-        let read_size = cmp::min(buf.len() as u64, self.length - self.offset) as usize;
-        if read_size == 0 {
+    }
+
+    pub fn get_raw(&self) -> u32 {
+        self.raw
+    }
+
+    fn is_set(&self, bit: u32) -> bool {
+        self.raw & bit != 0
+    }
+
+    pub fn is_hardware_locked(&self) -> bool {
+        self.is_set(ATTRIBUTE_HARDWARE_LOCK)
+    }
+
+    pub fn was_unmounted_cleanly(&self) -> bool {
+        self.is_set(ATTRIBUTE_UNMOUNTED)
+    }
+
+    pub fn has_bad_blocks_spared(&self) -> bool {
+        self.is_set(ATTRIBUTE_SPARED_BLOCKS)
+    }
+
+    pub fn is_no_cache_required(&self) -> bool {
+        self.is_set(ATTRIBUTE_NO_CACHE_REQUIRED)
+    }
+
+    pub fn is_boot_volume_inconsistent(&self) -> bool {
+        self.is_set(ATTRIBUTE_BOOT_VOLUME_INCONSISTENT)
+    }
+
+    pub fn are_catalog_node_ids_reused(&self) -> bool {
+        self.is_set(ATTRIBUTE_CATALOG_NODE_IDS_REUSED)
+    }
+
+    pub fn is_journaled(&self) -> bool {
+        self.is_set(ATTRIBUTE_JOURNALED)
+    }
+
+    pub fn is_software_locked(&self) -> bool {
+        self.is_set(ATTRIBUTE_SOFTWARE_LOCK)
+    }
+}
+
+impl Display for VolumeAttributes {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(fmt, "0x{:08x}", self.raw)?;
+        let names: Vec<&str> = ATTRIBUTE_NAMES.iter().filter(|&&(bit, _)| self.is_set(bit)).map(|&(_, name)| name).collect();
+        if !names.is_empty() {
+            write!(fmt, " ({})", names.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+// Identifies a volume and its state, so a resumed session can tell that it is
+// looking at the same, unmodified device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct VolumeFingerprint {
+    pub finder_info: [u8; SIZE_FINDER_INFO],
+    pub write_count: u32,
+    pub modify_date: u32,
+    pub next_catalog_id: u32,
+    pub header_hash: u64,
+}
+
+impl VolumeFingerprint {
+    pub fn differences(&self, other: &VolumeFingerprint) -> Vec<&'static str> {
+        let mut result = Vec::new();
+        if self.finder_info != other.finder_info {
+            result.push("finderInfo");
+        }
+        if self.write_count != other.write_count {
+            result.push("writeCount");
+        }
+        if self.modify_date != other.modify_date {
+            result.push("modifyDate");
+        }
+        if self.next_catalog_id != other.next_catalog_id {
+            result.push("nextCatalogID");
+        }
+        if self.header_hash != other.header_hash {
+            result.push("header hash");
+        }
+        result
+    }
+}
+
+impl Display for VolumeFingerprint {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        for byte in &self.finder_info {
+            write!(fmt, "{:02x}", byte)?;
+        }
+        write!(fmt, "-{:08x}-{:08x}-{:08x}-{:016x}", self.write_count, self.modify_date, self.next_catalog_id,
+               self.header_hash)
+    }
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[derive(Debug)]
+pub struct ForkData<'a, F> where F: 'a {
+    parent: &'a FileSystem<F>,
+    // The offset of the volume header holding the fork data.
+    header_offset: u64,
+    offset: u64,
+    file_id: u32,
+}
+
+impl<'a, F> Structure<F> for ForkData<'a, F> where F: 'a {
+    fn get_offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn get_filesystem(&self) -> &FileSystem<F> {
+        self.parent
+    }
+}
+
+
+impl<'a, F> ForkData<'a, F> where F: Read + Seek {
+    fn new(parent: &'a FileSystem<F>, header_offset: u64, offset: u64, file_id: u32) -> ForkData<'a, F> {
+        ForkData {
+            parent,
+            header_offset,
+            offset,
+            file_id,
+        }
+    }
+
+    pub fn get_logical_size(&self) -> fs::Result<u64> {
+        self.read_number(0)
+    }
+
+    pub fn get_clump_size(&self) -> fs::Result<u32> {
+        self.read_number(8)
+    }
+
+    pub fn get_total_blocks(&self) -> fs::Result<u32> {
+        self.read_number(12)
+    }
+
+    pub fn snapshot(&self) -> fs::Result<ForkDataSnapshot> {
+        let mut raw = [0; SIZE_FORK_DATA as usize];
+        self.read_bytes(0, &mut raw)?;
+        Ok(ForkDataSnapshot::from_bytes(&raw))
+    }
+
+    pub fn check_consistency(&self, block_size: u32) -> fs::Result<ForkConsistency> {
+        Ok(self.snapshot()?.check_consistency(block_size))
+    }
+
+    pub fn validate(&self, total_blocks: u32) -> fs::Result<Vec<ExtentIssue>> {
+        Ok(consistency::check_extents(&self.snapshot()?.extents, total_blocks))
+    }
+
+    pub fn num_extent_descriptors(&self) -> usize {
+        8
+    }
+
+    pub fn get_extent_descriptor(&self, index: usize) -> ExtentDescriptor<'a, F> {
+        assert!(index < self.num_extent_descriptors());
+        ExtentDescriptor::new(self.parent,
+                              self.offset + OFFSET_FORK_DATA_EXTENT_RECORD + SIZE_EXTENT_DESCRIPTOR * index as u64)
+    }
+
+    // The (start block, block count) pairs of the extents in use, stopping at
+    // the first empty descriptor.
+    pub fn extent_descriptors(&self) -> UsedExtentDescriptors<'a, F> {
+        UsedExtentDescriptors {
+            inner: self.extent_descriptors_raw(),
+        }
+    }
+
+    // All eight descriptors, including unused ones.
+    pub fn extent_descriptors_raw(&self) -> ExtentDescriptors<'a, F> {
+        ExtentDescriptors {
+            fork_data: ForkData::new(self.parent, self.header_offset, self.offset, self.file_id),
+            index: 0,
+        }
+    }
+}
+
+pub struct ExtentDescriptors<'a, F> where F: 'a {
+    fork_data: ForkData<'a, F>,
+    index: usize,
+}
+
+impl<'a, F> Iterator for ExtentDescriptors<'a, F> where F: Read + Seek {
+    type Item = fs::Result<(u32, u32)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.fork_data.num_extent_descriptors() {
+            return None;
+        }
+        let result = self.fork_data.get_extent_descriptor(self.index).snapshot();
+        self.index += 1;
+        Some(result)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.fork_data.num_extent_descriptors() - self.index;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a, F> ExactSizeIterator for ExtentDescriptors<'a, F> where F: Read + Seek {}
+
+// Ends after the first empty descriptor or read error.
+pub struct UsedExtentDescriptors<'a, F> where F: 'a {
+    inner: ExtentDescriptors<'a, F>,
+}
+
+impl<'a, F> Iterator for UsedExtentDescriptors<'a, F> where F: Read + Seek {
+    type Item = fs::Result<(u32, u32)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let finished = self.inner.fork_data.num_extent_descriptors();
+        match self.inner.next()? {
+            Ok((_, 0)) => {
+                self.inner.index = finished;
+                None
+            },
+            Ok(extent) => Some(Ok(extent)),
+            Err(e) => {
+                self.inner.index = finished;
+                Some(Err(e))
+            },
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.inner.size_hint().1)
+    }
+}
+
+impl<'a, F> Display for ForkData<'a, F> where F: Read + Seek {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match self.snapshot() {
+            Ok(fork) => {
+                writeln!(fmt, "Logical size: {}", fork.logical_size)?;
+                writeln!(fmt, "Clump size: {}", fork.clump_size)?;
+                writeln!(fmt, "Total blocks: {}", fork.total_blocks)?;
+            },
+            Err(e) => writeln!(fmt, "Fork data: {}", Field::<String>(Err(e)))?,
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct ExtentDescriptor<'a, F> where F: 'a {
+    parent: &'a FileSystem<F>,
+    offset: u64,
+}
+
+impl<'a, F> Structure<F> for ExtentDescriptor<'a, F> where F: 'a {
+    fn get_offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn get_filesystem(&self) -> &FileSystem<F> {
+        self.parent
+    }
+}
+
+impl<'a, F> ExtentDescriptor<'a, F> where F: Read + Seek {
+    fn new(parent: &'a FileSystem<F>, offset: u64) -> ExtentDescriptor<'a, F> {
+        ExtentDescriptor {
+            parent,
+            offset,
+        }
+    }
+
+    pub fn get_start_block(&self) -> fs::Result<u32> {
+        self.read_number(0)
+    }
+
+    pub fn get_block_count(&self) -> fs::Result<u32> {
+        self.read_number(4)
+    }
+
+    // The (start block, block count) pair, as held in ForkDataSnapshot.
+    pub fn snapshot(&self) -> fs::Result<(u32, u32)> {
+        Ok((self.get_start_block()?, self.get_block_count()?))
+    }
+}
+
+impl<'a, F> Display for ExtentDescriptor<'a, F> where F: Read + Seek {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        writeln!(fmt, "Start block: {}", Field(self.get_start_block()))?;
+        writeln!(fmt, "Block count: {}", Field(self.get_block_count()))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct JournalInfoBlock<'a, F> where F: 'a {
+    parent: &'a FileSystem<F>,
+    offset: u64,
+}
+
+impl<'a, F> Structure<F> for JournalInfoBlock<'a, F> where F: 'a {
+    fn get_offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn get_filesystem(&self) -> &FileSystem<F> {
+        self.parent
+    }
+}
+
+impl<'a, F> JournalInfoBlock<'a, F> where F: Read + Seek {
+    fn new(parent: &'a FileSystem<F>, block: u32, block_size: u32) -> JournalInfoBlock<'a, F> {
+        JournalInfoBlock {
+            parent,
+            offset: block as u64 * block_size as u64,
+        }
+    }
+
+    pub fn get_flags(&self) -> fs::Result<u32> {
+        self.read_number(0)
+    }
+
+    pub fn is_journal_in_fs(&self) -> fs::Result<bool> {
+        Ok(self.get_flags()? & JOURNAL_IN_FS != 0)
+    }
+
+    pub fn is_journal_on_other_device(&self) -> fs::Result<bool> {
+        Ok(self.get_flags()? & JOURNAL_ON_OTHER_DEVICE != 0)
+    }
+
+    pub fn needs_init(&self) -> fs::Result<bool> {
+        Ok(self.get_flags()? & JOURNAL_NEED_INIT != 0)
+    }
+
+    pub fn get_device_signature(&self) -> fs::Result<[u8; SIZE_JOURNAL_DEVICE_SIGNATURE]> {
+        let mut signature = [0; SIZE_JOURNAL_DEVICE_SIGNATURE];
+        self.read_bytes(4, &mut signature)?;
+        Ok(signature)
+    }
+
+    // Byte offset of the journal from the start of the volume.
+    pub fn get_journal_offset(&self) -> fs::Result<u64> {
+        self.read_number(36)
+    }
+
+    pub fn get_journal_size(&self) -> fs::Result<u64> {
+        self.read_number(44)
+    }
+
+    pub fn snapshot(&self) -> fs::Result<JournalInfoBlockSnapshot> {
+        let mut raw = [0; SIZE_JOURNAL_INFO_BLOCK];
+        self.read_bytes(0, &mut raw)?;
+        Ok(JournalInfoBlockSnapshot::from_bytes(&raw))
+    }
+}
+
+impl<'a, F> Display for JournalInfoBlock<'a, F> where F: Read + Seek {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        writeln!(fmt, "Flags: {}", Field(self.get_flags()))?;
+        writeln!(fmt, "Journal offset: {}", Field(self.get_journal_offset()))?;
+        writeln!(fmt, "Journal size: {}", Field(self.get_journal_size()))?;
+        Ok(())
+    }
+}
+
+pub struct HFSFile<'a, F> where F: 'a {
+    parent: &'a FileSystem<F>,
+    length: u64,
+    recoverable_length: u64,
+    block_size: u64,
+    // Shared between clones, as it never changes once the file is open.
+    offsets: Arc<Vec<(u64, u32, u32)>>,
+    // How many of the offsets came from the fork data rather than the
+    // extents overflow file.
+    inline_extents: usize,
+    // Why the extent map of a permissively opened file stops short.
+    truncation: Option<Arc<HFSPError>>,
+    offset: u64,
+    // Holds file data from buffer_start. The buffer is keyed by file offset
+    // rather than invalidated on seek, so seeking back into it is free.
+    buffer: Vec<u8>,
+    buffer_start: u64,
+    buffer_capacity: usize,
+    readahead: usize,
+    hole_policy: HolePolicy,
+    damaged: Mutex<Vec<Range<u64>>>,
+}
+
+impl<'a, F> HFSFile<'a, F> where F: Read + Seek {
+    fn new(fork_data: ForkData<'a, F>) -> fs::Result<HFSFile<'a, F>> {
+        let geometry = fork_data.parent.get_header_geometry(fork_data.header_offset)?;
+        HFSFile::open(fork_data.parent, &fork_data.snapshot()?, fork_data.file_id, ForkType::Data, &geometry, false)
+    }
+
+    // Opens as much of the fork as can be mapped, stopping at the first
+    // extent that is out of bounds or can't be found in the extents overflow
+    // file. Reads past the recoverable length fail with
+    // HFSPError::BeyondRecoverableLength, and truncation() says why the map
+    // stopped.
+    pub fn new_permissive(fork_data: ForkData<'a, F>) -> fs::Result<HFSFile<'a, F>> {
+        let geometry = fork_data.parent.get_header_geometry(fork_data.header_offset)?;
+        HFSFile::open(fork_data.parent, &fork_data.snapshot()?, fork_data.file_id, ForkType::Data, &geometry, true)
+    }
+
+    // Opens a fork described outside the volume header, such as in a catalog
+    // file record. The file ID and fork type locate any overflow extents.
+    pub fn from_snapshot(parent: &'a FileSystem<F>, fork: &ForkDataSnapshot, file_id: u32, fork_type: ForkType)
+        -> fs::Result<HFSFile<'a, F>> {
+        HFSFile::open(parent, fork, file_id, fork_type, &parent.get_geometry()?, false)
+    }
+
+    pub fn from_snapshot_permissive(parent: &'a FileSystem<F>, fork: &ForkDataSnapshot, file_id: u32,
+                                    fork_type: ForkType) -> fs::Result<HFSFile<'a, F>> {
+        HFSFile::open(parent, fork, file_id, fork_type, &parent.get_geometry()?, true)
+    }
+
+    fn open(parent: &'a FileSystem<F>, fork: &ForkDataSnapshot, file_id: u32, fork_type: ForkType, geometry: &Geometry,
+            permissive: bool) -> fs::Result<HFSFile<'a, F>> {
+        #[cfg(feature = "tracing")]
+        let span = ::tracing::debug_span!("open_fork", file_id, fork_type = %fork_type, size = ::tracing::field::Empty,
+                                          extents = ::tracing::field::Empty).entered();
+        let length = fork.logical_size;
+        let (block_size, total_blocks) = (geometry.block_size, geometry.total_blocks);
+        let maximum = match parent.fork_size_limit {
+            ForkSizeLimit::VolumeSize => total_blocks as u64 * block_size as u64,
+            ForkSizeLimit::Bytes(maximum) => maximum,
+            ForkSizeLimit::Unlimited => u64::MAX,
+        };
+        if length > maximum {
+            return Err(HFSPError::ImplausibleForkSize { claimed: length, maximum });
+        }
+        let mut extents = Vec::new();
+        let mut truncation = None;
+        if permissive {
+            // The extents gathered before the error are still usable, but
+            // the error is kept so callers can tell a missing overflow
+            // record from a failed read.
+            if let Err(error) = extend_fork_extents(parent, fork, file_id, fork_type, geometry, &mut None, &mut extents) {
+                truncation = Some(Arc::new(error));
+            }
+        } else {
+            extents = collect_fork_extents(parent, fork, file_id, fork_type, geometry, &mut None)?;
+        }
+        // Block counts are u32 and block sizes at most MAX_BLOCK_SIZE, so the
+        // byte offsets can't overflow once the block total is known to fit.
+        let inline_extents = cmp::min(fork.extents.iter().take_while(|&&(_, count)| count != 0).count(),
+                                      extents.len());
+        let mut offsets = Vec::with_capacity(extents.len());
+        let mut seen_blocks: u32 = 0;
+        for (start_block, block_count) in extents {
+            offsets.push((seen_blocks as u64 * block_size as u64, start_block, block_count));
+            seen_blocks = seen_blocks.checked_add(block_count).ok_or(HFSPError::ArithmeticOverflow)?;
+        }
+        #[cfg(feature = "tracing")]
+        {
+            span.record("size", length);
+            span.record("extents", offsets.len());
+        }
+
+        let result = HFSFile {
+            parent,
+            block_size: block_size as u64,
+            length,
+            recoverable_length: cmp::min(length, seen_blocks as u64 * block_size as u64),
+            offsets: Arc::new(offsets),
+            inline_extents,
+            truncation,
+            offset: 0,
+            buffer: Vec::new(),
+            buffer_start: 0,
+            buffer_capacity: cmp::max(MAX_READAHEAD.div_ceil(block_size as usize), 1) * block_size as usize,
+            readahead: block_size as usize,
+            hole_policy: HolePolicy::Fail,
+            damaged: Mutex::new(Vec::new()),
+        };
+        Ok(result)
+    }
+
+    pub fn recoverable_length(&self) -> u64 {
+        self.recoverable_length
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.recoverable_length == self.length
+    }
+
+    // The error that stopped the extent map of a permissively opened file,
+    // or None if every extent was found.
+    pub fn truncation(&self) -> Option<&HFSPError> {
+        self.truncation.as_deref()
+    }
+
+    // Rewinds to the start and forgets the damaged ranges found so far, as
+    // if the file had just been opened.
+    pub fn reopen(&mut self) {
+        self.offset = 0;
+        self.buffer = Vec::new();
+        self.buffer_start = 0;
+        self.readahead = self.block_size as usize;
+        self.damaged.lock().unwrap().clear();
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn position(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size as u32
+    }
+
+    // Only counts the extents that could be mapped for a partial file.
+    pub fn allocated_size(&self) -> u64 {
+        self.offsets.last().map_or(0, |&(logical_offset, _, block_count)| {
+            logical_offset + block_count as u64 * self.block_size
+        })
+    }
+
+    pub fn metadata(&self) -> FileMetadata {
+        FileMetadata {
+            length: self.length,
+            recoverable_length: self.recoverable_length,
+            position: self.offset,
+            block_size: self.block_size as u32,
+            allocated_size: self.allocated_size(),
+            extent_count: self.offsets.len(),
+        }
+    }
+
+    // Sets the largest read the buffer makes from the device, which defaults
+    // to 1 MiB. It is rounded up to a whole number of blocks. The buffer
+    // starts at one block and grows towards this while reads are sequential.
+    pub fn set_buffer_size(&mut self, size: usize) {
+        let block_size = self.block_size as usize;
+        self.buffer_capacity = cmp::max(size.div_ceil(block_size), 1) * block_size;
+        self.readahead = cmp::min(self.readahead, self.buffer_capacity);
+        self.buffer = Vec::new();
+    }
+
+    pub fn get_buffer_size(&self) -> usize {
+        self.buffer_capacity
+    }
+
+    fn buffer_covers(&self, offset: u64) -> bool {
+        offset >= self.buffer_start && offset < self.buffer_start + self.buffer.len() as u64
+    }
+
+    fn check_recoverable(&self, offset: u64) -> io::Result<()> {
+        if offset >= self.recoverable_length {
+            let error = HFSPError::BeyondRecoverableLength {
+                offset,
+                recoverable_length: self.recoverable_length,
+            };
+            return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+        }
+        Ok(())
+    }
+
+    // Reads from the given offset without using or moving the position or
+    // the buffer. Like read(), it may return fewer bytes than asked for, as
+    // it stops at the end of an extent.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || offset >= self.length {
             return Ok(0);
         }
-        assert!(!self.offsets.is_empty());
-        let extent_index = match self.offsets.binary_search_by_key(&self.offset, |&(o, _)| o) {
+        if self.hole_policy == HolePolicy::ZeroFill && offset >= self.recoverable_length {
+            let size = cmp::min(buf.len() as u64, self.length - offset) as usize;
+            return Ok(self.zero_fill(offset, &mut buf[..size]));
+        }
+        self.check_recoverable(offset)?;
+        let (fs_offset, contiguous) = self.locate(offset).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "file offset is not covered by an extent")
+        })?;
+        let read_size = cmp::min(buf.len() as u64, contiguous) as usize;
+        match self.parent.read(fs_offset, &mut buf[0..read_size]) {
+            Err(ref e) if self.hole_policy == HolePolicy::ZeroFill && e.kind() != io::ErrorKind::Interrupted => {
+                // Retry the first block alone so that one bad sector doesn't
+                // cost the rest of the read.
+                let block_remaining = self.block_size - offset % self.block_size;
+                let size = cmp::min(read_size as u64, block_remaining) as usize;
+                match self.parent.read(fs_offset, &mut buf[..size]) {
+                    Ok(read) => Ok(read),
+                    Err(_) => Ok(self.zero_fill(offset, &mut buf[..size])),
+                }
+            },
+            result => result,
+        }
+    }
+
+    // Sets what reads do with data that can't be read from the device, or
+    // that lies past the recoverable length of a file opened with
+    // new_permissive(). The default is HolePolicy::Fail.
+    pub fn with_hole_policy(mut self, hole_policy: HolePolicy) -> HFSFile<'a, F> {
+        self.hole_policy = hole_policy;
+        self
+    }
+
+    pub fn get_hole_policy(&self) -> HolePolicy {
+        self.hole_policy
+    }
+
+    // The parts of the file that reads have zero-filled so far, in order.
+    pub fn damaged_ranges(&self) -> Vec<Range<u64>> {
+        self.damaged.lock().unwrap().clone()
+    }
+
+    fn zero_fill(&self, offset: u64, buf: &mut [u8]) -> usize {
+        for byte in buf.iter_mut() {
+            *byte = 0;
+        }
+        let end = offset + buf.len() as u64;
+        let mut damaged = self.damaged.lock().unwrap();
+        // Merges the range with any it overlaps or touches.
+        let first = damaged.partition_point(|range| range.end < offset);
+        let last = damaged.partition_point(|range| range.start <= end);
+        let merged = if first < last {
+            cmp::min(offset, damaged[first].start)..cmp::max(end, damaged[last - 1].end)
+        } else {
+            offset..end
+        };
+        damaged.splice(first..last, Some(merged));
+        buf.len()
+    }
+
+    // Copies from the current position to the end of the file, or to the
+    // recoverable length of a partial one, leaving the position where the
+    // copy stopped. The callback is given the progress after each chunk.
+    pub fn copy_to<W, P>(&mut self, mut writer: W, mut progress: P) -> Result<CopyOutcome, CopyError>
+        where W: Write, P: FnMut(CopyProgress) {
+        let start = self.offset;
+        let end = match self.hole_policy {
+            HolePolicy::Fail => self.recoverable_length,
+            HolePolicy::ZeroFill => self.length,
+        };
+        let mut chunk = vec![0; COPY_CHUNK_SIZE];
+        while self.offset < end {
+            let wanted = cmp::min(end - self.offset, COPY_CHUNK_SIZE as u64) as usize;
+            let read = match self.read_at(self.offset, &mut chunk[..wanted]) {
+                Ok(0) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "source ended inside the file")),
+                Ok(read) => Ok(read),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+            let read = read.map_err(|error| CopyError {
+                kind: CopyErrorKind::Read,
+                logical_offset: self.offset,
+                device_offset: self.device_offset_for(self.offset),
+                error,
+            })?;
+            writer.write_all(&chunk[..read]).map_err(|error| CopyError {
+                kind: CopyErrorKind::Write,
+                logical_offset: self.offset,
+                device_offset: None,
+                error,
+            })?;
+            self.offset += read as u64;
+            progress(CopyProgress {
+                bytes_copied: self.offset - start,
+                total_bytes: end.saturating_sub(start),
+            });
+        }
+        let bytes_copied = self.offset.saturating_sub(start);
+        let damaged_bytes: u64 = self.damaged_ranges().iter().map(|range| {
+            cmp::min(range.end, self.offset).saturating_sub(cmp::max(range.start, start))
+        }).sum();
+        if damaged_bytes != 0 {
+            Ok(CopyOutcome::ZeroFilled { bytes_copied, damaged_bytes })
+        } else if self.offset >= self.length {
+            Ok(CopyOutcome::Complete { bytes_copied })
+        } else {
+            Ok(CopyOutcome::Truncated { bytes_copied, length: self.length })
+        }
+    }
+
+    // As copy_to(), also hashing the data as it is written out.
+    #[cfg(feature = "hashing")]
+    pub fn copy_to_with_digests<W, P>(&mut self, writer: W, algorithms: &[HashAlgorithm], progress: P)
+        -> Result<(CopyOutcome, Digests), CopyError> where W: Write, P: FnMut(CopyProgress) {
+        let mut writer = HashingWriter::new(writer, algorithms);
+        let outcome = self.copy_to(&mut writer, progress)?;
+        Ok((outcome, writer.into_inner().1))
+    }
+
+    pub fn read_exact_at(&self, mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(offset, buf) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")),
+                Ok(read) => {
+                    offset += read as u64;
+                    buf = &mut buf[read..];
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    // Fills the buffer from the start of the block holding the current
+    // offset, with at least wanted bytes from the offset if the file and
+    // buffer size allow. Reads continuing on from the last fill double the
+    // size of the next one. Sources may return short reads anywhere, such as
+    // at segment or mapfile region boundaries, so the fill takes as many
+    // reads as it needs.
+    fn refill_buffer(&mut self, wanted: usize) -> io::Result<()> {
+        let block_size = self.block_size as usize;
+        let block_start = self.offset - self.offset % self.block_size;
+        if block_start == self.buffer_start + self.buffer.len() as u64 {
+            self.readahead = cmp::min(self.readahead * 2, self.buffer_capacity);
+        } else {
+            self.readahead = block_size;
+        }
+        let needed = (self.offset - block_start) as usize + wanted;
+        let size = cmp::min(cmp::max(self.readahead, needed.div_ceil(block_size) * block_size), self.buffer_capacity);
+        let mut buffer = mem::take(&mut self.buffer);
+        buffer.resize(size, 0);
+        let mut filled = 0;
+        let mut result = Ok(());
+        while filled < size {
+            match self.read_at(block_start + filled as u64, &mut buffer[filled..]) {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                },
+            }
+        }
+        buffer.truncate(filled);
+        self.buffer = buffer;
+        self.buffer_start = block_start;
+        // An error past the offset is left for the read that reaches it.
+        if self.buffer_covers(self.offset) {
+            return Ok(());
+        }
+        result?;
+        if !self.buffer_covers(self.offset) {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "source ended inside the file"));
+        }
+        Ok(())
+    }
+
+    // The ranges of the source holding the file's data, in file order. The
+    // last range ends at the logical end of the file rather than the end of
+    // its final block, or at the recoverable length if the file is partial.
+    pub fn extents(&self) -> Vec<ExtentRange> {
+        let mut result = Vec::with_capacity(self.offsets.len());
+        for (idx, &(logical_offset, start_block, _)) in self.offsets.iter().enumerate() {
+            let end = self.offsets.get(idx + 1).map_or(self.recoverable_length, |&(next, _, _)| next);
+            result.push(ExtentRange {
+                device_offset: start_block as u64 * self.block_size,
+                length: end - logical_offset,
+                logical_offset,
+            });
+        }
+        result
+    }
+
+    fn extent_rows(&self) -> impl Iterator<Item = ExtentRow> + '_ {
+        self.offsets.iter().enumerate().map(move |(idx, &(logical_offset, start_block, block_count))| {
+            let device_start = start_block as u64 * self.block_size;
+            ExtentRow {
+                logical_offset,
+                start_block,
+                block_count,
+                device_range: device_start..device_start + block_count as u64 * self.block_size,
+                from_overflow: idx >= self.inline_extents,
+            }
+        })
+    }
+
+    pub fn fragmentation(&self) -> FragmentationReport {
+        let extents: Vec<(u32, u32)> = self.offsets.iter().map(|&(_, start, count)| (start, count)).collect();
+        FragmentationReport::new(&extents, self.length, self.block_size as u32)
+    }
+
+    // Returns None for offsets at or beyond the end of the file or the
+    // recoverable part of it.
+    pub fn device_offset_for(&self, logical_offset: u64) -> Option<u64> {
+        self.locate(logical_offset).map(|(device_offset, _)| device_offset)
+    }
+
+    // Returns the device offset of a byte of the file along with the number
+    // of bytes that follow it contiguously on the device, up to the end of
+    // its extent or of the file.
+    fn locate(&self, logical_offset: u64) -> Option<(u64, u64)> {
+        if logical_offset >= self.recoverable_length {
+            return None;
+        }
+        let extent_index = match self.offsets.binary_search_by_key(&logical_offset, |&(o, _, _)| o) {
             Ok(idx) => idx,
+            Err(0) => return None,
             Err(idx) => idx - 1,
         };
-        let extent_offset = self.offsets[extent_index].1 as u64 * self.block_size;
-        let intra_extent_offset = self.offset - self.offsets[extent_index].0;
-        let fs_offset = extent_offset + intra_extent_offset;
-        let read = self.parent.read(fs_offset, &mut buf[0..read_size])?;
-        self.offset += read as u64;
+        let (extent_logical_offset, start_block, block_count) = self.offsets[extent_index];
+        let extent_end = cmp::min(extent_logical_offset + block_count as u64 * self.block_size,
+                                  self.recoverable_length);
+        let device_offset = start_block as u64 * self.block_size + (logical_offset - extent_logical_offset);
+        Some((device_offset, extent_end - logical_offset))
+    }
+}
+
+// Returns the extents needed to cover the fork's logical size, consulting
+// the extents overflow file if the inline ones fall short. The overflow tree
+// is opened on first use and left in extents_tree for later calls.
+fn collect_fork_extents<'a, F>(parent: &'a FileSystem<F>, fork: &ForkDataSnapshot, file_id: u32,
+                               fork_type: ForkType, geometry: &Geometry,
+                               extents_tree: &mut Option<ExtentsTree<HFSFile<'a, F>>>) -> fs::Result<Vec<(u32, u32)>>
+    where F: Read + Seek {
+    if let ForkConsistency::Truncated { logical_size, allocated_bytes } = fork.check_consistency(geometry.block_size) {
+        return Err(HFSPError::TruncatedFork { logical_size, allocated_bytes });
+    }
+    let mut result = Vec::new();
+    extend_fork_extents(parent, fork, file_id, fork_type, geometry, extents_tree, &mut result)?;
+    Ok(result)
+}
+
+// As collect_fork_extents, but without the allocation check, and leaving the
+// extents found before any error in result. The overflow extents come from
+// the extents file of the same header as the geometry.
+fn extend_fork_extents<'a, F>(parent: &'a FileSystem<F>, fork: &ForkDataSnapshot, file_id: u32,
+                              fork_type: ForkType, geometry: &Geometry,
+                              extents_tree: &mut Option<ExtentsTree<HFSFile<'a, F>>>, result: &mut Vec<(u32, u32)>)
+    -> fs::Result<()> where F: Read + Seek {
+    let (block_size, total_blocks) = (geometry.block_size, geometry.total_blocks);
+    let length = fork.logical_size;
+    let covered = |blocks: u32| blocks as u64 * block_size as u64 >= length;
+    let mut blocks = 0;
+    for &(start_block, block_count) in fork.extents.iter() {
+        if block_count == 0 || covered(blocks) {
+            break;
+        }
+        push_extent(result, &mut blocks, start_block, block_count, total_blocks)?;
+    }
+
+    // The extents file can't have overflow extents of its own, so it is
+    // always readable from the header alone.
+    while !covered(blocks) {
+        if file_id == CNID_EXTENTS_FILE {
+            return Err(HFSPError::MissingOverflowExtents);
+        }
+        if extents_tree.is_none() {
+            *extents_tree = Some(BTree::new(HFSFile::open(parent, &geometry.extents_file, CNID_EXTENTS_FILE,
+                                                          ForkType::Data, geometry, false)?));
+        }
+        let extents = extents_tree.as_mut().unwrap().find_overflow_extents(file_id, fork_type, blocks)?
+            .ok_or(HFSPError::MissingOverflowExtents)?;
+        let previous_blocks = blocks;
+        for (start_block, block_count) in extents {
+            if block_count == 0 || covered(blocks) {
+                break;
+            }
+            push_extent(result, &mut blocks, start_block, block_count, total_blocks)?;
+        }
+        if blocks == previous_blocks {
+            return Err(HFSPError::MissingOverflowExtents);
+        }
+    }
+    Ok(())
+}
+
+fn push_extent(extents: &mut Vec<(u32, u32)>, blocks: &mut u32, start: u32, count: u32, total_blocks: u32)
+    -> fs::Result<()> {
+    let index = extents.len();
+    if start as u64 + count as u64 > total_blocks as u64 {
+        return Err(HFSPError::InvalidExtent { index, start, count });
+    }
+    *blocks = blocks.checked_add(count).ok_or(HFSPError::ArithmeticOverflow)?;
+    extents.push((start, count));
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum HolePolicy {
+    // Reads of damaged or unmapped data fail.
+    #[default]
+    Fail,
+    // Reads return zeros in their place, and the ranges are recorded.
+    ZeroFill,
+}
+
+// A copy of an HFSFile's figures that doesn't borrow the file system.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct FileMetadata {
+    pub length: u64,
+    pub recoverable_length: u64,
+    pub position: u64,
+    pub block_size: u32,
+    pub allocated_size: u64,
+    pub extent_count: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ExtentRange {
+    pub device_offset: u64,
+    pub length: u64,
+    pub logical_offset: u64,
+}
+
+// One entry of the extent map as shown by Display and Debug for HFSFile.
+#[derive(Debug)]
+struct ExtentRow {
+    logical_offset: u64,
+    start_block: u32,
+    block_count: u32,
+    device_range: Range<u64>,
+    from_overflow: bool,
+}
+
+struct ExtentRows<'b, 'a: 'b, F: 'a>(&'b HFSFile<'a, F>);
+
+impl<'b, 'a, F> fmt::Debug for ExtentRows<'b, 'a, F> where F: Read + Seek {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        fmt.debug_list().entries(self.0.extent_rows()).finish()
+    }
+}
+
+impl<'a, F> Display for HFSFile<'a, F> where F: Read + Seek {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        writeln!(fmt, "Logical size: {}", self.length)?;
+        writeln!(fmt, "Recoverable length: {}", self.recoverable_length)?;
+        if let Some(ref truncation) = self.truncation {
+            writeln!(fmt, "Extent map stopped by: {}", truncation)?;
+        }
+        writeln!(fmt, "Block size: {}", self.block_size)?;
+        writeln!(fmt, "Extents: {}", self.offsets.len())?;
+        writeln!(fmt, "{:>16} {:>12} {:>12} {:>27}  Source", "Logical offset", "Start block", "Blocks", "Device bytes")?;
+        for row in self.extent_rows() {
+            let device_range = format!("{}-{}", row.device_range.start, row.device_range.end);
+            writeln!(fmt, "{:>16} {:>12} {:>12} {:>27}  {}", row.logical_offset, row.start_block, row.block_count,
+                     device_range, if row.from_overflow { "overflow" } else { "fork data" })?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, F> fmt::Debug for HFSFile<'a, F> where F: Read + Seek {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        let buffer_end = self.buffer_start + self.buffer.len() as u64;
+        fmt.debug_struct("HFSFile")
+            .field("length", &self.length)
+            .field("recoverable_length", &self.recoverable_length)
+            .field("block_size", &self.block_size)
+            .field("position", &self.offset)
+            .field("hole_policy", &self.hole_policy)
+            .field("damaged", &*self.damaged.lock().unwrap())
+            .field("buffered", &(self.buffer_start..buffer_end))
+            .field("buffer_capacity", &self.buffer_capacity)
+            .field("readahead", &self.readahead)
+            .field("inline_extents", &self.inline_extents)
+            .field("truncation", &self.truncation)
+            .field("extents", &ExtentRows(self))
+            .finish_non_exhaustive()
+    }
+}
+
+// Clones share the extent map but have their own position and buffer. The
+// damaged ranges found so far are copied.
+impl<'a, F> Clone for HFSFile<'a, F> {
+    fn clone(&self) -> HFSFile<'a, F> {
+        HFSFile {
+            parent: self.parent,
+            length: self.length,
+            recoverable_length: self.recoverable_length,
+            block_size: self.block_size,
+            offsets: self.offsets.clone(),
+            inline_extents: self.inline_extents,
+            truncation: self.truncation.clone(),
+            offset: self.offset,
+            buffer: Vec::new(),
+            buffer_start: 0,
+            buffer_capacity: self.buffer_capacity,
+            readahead: self.block_size as usize,
+            hole_policy: self.hole_policy,
+            damaged: Mutex::new(self.damaged.lock().unwrap().clone()),
+        }
+    }
+}
+
+impl<'a, F> Read for HFSFile<'a, F> where F: Read + Seek {
+    fn read(&mut self, buf: &mut[u8]) -> io::Result<usize> {
+        // Seeking past the end is allowed, so reading there must be too.
+        if buf.is_empty() || self.offset >= self.length {
+            return Ok(0);
+        }
+        // Reads at least as large as the buffer gain nothing from it.
+        if buf.len() >= self.buffer_capacity && !self.buffer_covers(self.offset) {
+            let read = self.read_at(self.offset, buf)?;
+            self.offset += read as u64;
+            return Ok(read);
+        }
+        if !self.buffer_covers(self.offset) {
+            self.refill_buffer(buf.len())?;
+        }
+        let read = {
+            let available = self.fill_buf()?;
+            let read = cmp::min(available.len(), buf.len());
+            buf[..read].copy_from_slice(&available[..read]);
+            read
+        };
+        self.consume(read);
         Ok(read)
     }
 }
 
+impl<'a, F> BufRead for HFSFile<'a, F> where F: Read + Seek {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.offset >= self.length {
+            return Ok(&[]);
+        }
+        if !self.buffer_covers(self.offset) {
+            self.refill_buffer(0)?;
+        }
+        let start = (self.offset - self.buffer_start) as usize;
+        Ok(&self.buffer[start..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.offset += amount as u64;
+    }
+}
+
 impl<'a, F> Seek for HFSFile<'a, F> where F: Read + Seek {
-    // TODO: Handle invalid seeks better
+    // Seeking past the end is allowed, as for ordinary files.
     fn seek(&mut self, from: io::SeekFrom) -> io::Result<u64> {
-        match from {
-            io::SeekFrom::Start(offset) => {
-                self.offset = offset;
-            },
-            io::SeekFrom::End(offset) => {
-                if offset >= 0 {
-                    self.offset = self.length + (offset as u64);
-                } else {
-                    let offset_magnitude = (-offset) as u64;
-                    if offset_magnitude > self.length {
-                        panic!("Cannot seek before start of file");
-                    } else {
-                        self.offset = self.length - offset_magnitude;
-                    }
-                }
-            },
-            io::SeekFrom::Current(offset) => {
-                if offset >= 0 {
-                    let offset = offset as u64;
-                    self.offset += offset;
-                } else {
-                    let offset_magnitude = (-offset) as u64;
-                    if offset_magnitude > self.offset {
-                        panic!("Cannot seek before start of file");
-                    } else {
-                        self.offset -= offset_magnitude;
-                    }
-                }
-            },
-        }
+        self.offset = match from {
+            io::SeekFrom::Start(offset) => offset,
+            io::SeekFrom::End(offset) => fs::offset_position(self.length, offset)?,
+            io::SeekFrom::Current(offset) => fs::offset_position(self.offset, offset)?,
+        };
         Ok(self.offset)
     }
 }
 
-pub struct BTree<F> {
-    file: F,
+const CNID_ROOT_FOLDER: u32 = 2;
+const CNID_EXTENTS_FILE: u32 = 3;
+const CNID_CATALOG_FILE: u32 = 4;
+const CNID_ALLOCATION_FILE: u32 = 6;
+const CNID_STARTUP_FILE: u32 = 7;
+const CNID_ATTRIBUTES_FILE: u32 = 8;
+
+// Builds the path of a catalog entry from the root folder, or returns None
+// if one of the folders above it is unknown.
+fn catalog_path(folders: &HashMap<u32, (u32, String)>, parent_id: u32, name: &str) -> Option<String> {
+    let mut components = vec![name];
+    let mut folder_id = parent_id;
+    while folder_id != CNID_ROOT_FOLDER {
+        // Guards against loops in a damaged catalog.
+        if components.len() > folders.len() {
+            return None;
+        }
+        let &(parent_id, ref name) = folders.get(&folder_id)?;
+        components.push(name);
+        folder_id = parent_id;
+    }
+    components.reverse();
+    Some(format!("/{}", components.join("/")))
 }
 
-impl<F> BTree<F> {
-    fn new(file: F) -> BTree<F> {
-        BTree {
-            file: file,
+#[cfg(test)]
+mod tests {
+    use ddrescue::{BlockStatus, Region, RegionMap};
+    use fixture::{self, VolumeBuilder};
+    use mapped::{MappedSource, UnrecoveredData};
+    use ownership::{BlockOwner, TreeCoverage};
+    use segmented::SegmentedSource;
+    use snapshot::{HeaderCopy, SpecialFile};
+    use std::io::Read;
+    use super::*;
+
+    const BLOCK_SIZE: u32 = 512;
+    const TOTAL_BLOCKS: u32 = 64;
+
+    // Out of order and with gaps between them, so that reading any of them
+    // from the wrong place gives the wrong bytes. Blocks 2 and 62 hold the
+    // volume headers.
+    const EXTENTS: [(u32, u32); 8] = [(20, 2), (10, 1), (30, 3), (5, 1), (40, 1), (45, 2), (50, 1), (60, 1)];
+
+    fn volume_with_allocation_file(extents: &[(u32, u32)], length: u64) -> (Vec<u8>, Vec<u8>) {
+        let data = fixture::pattern_bytes(length);
+        let image = VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS)
+            .set_fork(SpecialFile::Allocation, length, extents)
+            .write_fork(extents, &data)
+            .build();
+        (image, data)
+    }
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32) -> chrono::NaiveDateTime {
+        chrono::NaiveDate::from_ymd_opt(year, month, day).unwrap().and_hms_opt(hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn dates_are_read_from_their_own_offsets() {
+        // Seconds since 1904 for 2024-01-01, 2024-01-01 01:00, 1904-01-01
+        // and 1970-01-01.
+        let mut volume = VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS);
+        fixture::put_u32(volume.header(), 16, 3786912000);
+        fixture::put_u32(volume.header(), 20, 3786912000 + 3600);
+        fixture::put_u32(volume.header(), 24, 0);
+        fixture::put_u32(volume.header(), 28, 2082844800);
+        let filesystem = FileSystem::from_bytes(volume.build());
+        let header = filesystem.get_volume_header().unwrap();
+        // Only the create date is stored in local time.
+        assert_eq!(header.get_create_date().unwrap().naive_local(), utc(2024, 1, 1, 0));
+        assert_eq!(header.get_modify_date().unwrap().naive_utc(), utc(2024, 1, 1, 1));
+        assert_eq!(header.get_backup_date().unwrap().naive_utc(), utc(1904, 1, 1, 0));
+        assert_eq!(header.get_checked_date().unwrap().naive_utc(), utc(1970, 1, 1, 0));
+        let snapshot = header.snapshot().unwrap();
+        assert_eq!(snapshot.create_date, header.get_create_date().unwrap());
+        assert_eq!(snapshot.modify_date, header.get_modify_date().unwrap());
+        assert_eq!(snapshot.backup_date, header.get_backup_date().unwrap());
+        assert_eq!(snapshot.checked_date, header.get_checked_date().unwrap());
+    }
+
+    #[test]
+    fn fingerprints_change_with_the_fields_they_cover() {
+        // Each header field is patched in turn to a value it doesn't have.
+        let fingerprint = |patch: Option<(usize, u32)>| {
+            let mut volume = VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS);
+            if let Some((offset, value)) = patch {
+                fixture::put_u32(volume.header(), offset, value);
+            }
+            FileSystem::from_bytes(volume.build()).volume_fingerprint().unwrap()
+        };
+        let original = fingerprint(None);
+        assert_eq!(fingerprint(None), original);
+        assert_eq!(fingerprint(None).to_string(), original.to_string());
+        assert!(original.differences(&original).is_empty());
+
+        let cases: [(usize, &[&str]); 5] = [
+            (80, &["finderInfo", "header hash"]),
+            (68, &["writeCount", "header hash"]),
+            (20, &["modifyDate", "header hash"]),
+            (64, &["nextCatalogID", "header hash"]),
+            (48, &["header hash"]),
+        ];
+        for &(offset, expected) in &cases {
+            let changed = fingerprint(Some((offset, 1)));
+            assert_ne!(changed, original, "{}", offset);
+            assert_eq!(original.differences(&changed), expected, "{}", offset);
+        }
+    }
+
+    // A volume with a catalog of just a header node.
+    fn volume_with_catalog(signature: &[u8; 2], version: u16, key_compare_type: u8) -> Vec<u8> {
+        let fields = fixture::BTreeHeaderFields { total_nodes: 1, key_compare_type, ..Default::default() };
+        let catalog = fixture::btree_header_node(BLOCK_SIZE as usize, &fields);
+        let mut volume = VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS);
+        volume.header()[0..2].copy_from_slice(signature);
+        fixture::put_u16(volume.header(), 2, version);
+        volume.set_fork(SpecialFile::Catalog, BLOCK_SIZE as u64, &[(10, 1)]).write_fork(&[(10, 1)], &catalog).build()
+    }
+
+    #[test]
+    fn case_sensitivity_comes_from_the_catalog() {
+        let cases = [
+            (SIGNATURE_HFSX, VERSION_HFSX, 0xbc, true),
+            (SIGNATURE_HFSX, VERSION_HFSX, 0xcf, false),
+            (SIGNATURE_HFS_PLUS, VERSION_HFS_PLUS, 0xbc, false),
+            (SIGNATURE_HFS_PLUS, VERSION_HFS_PLUS, 0, false),
+        ];
+        for &(signature, version, key_compare_type, expected) in &cases {
+            let filesystem = FileSystem::from_bytes(volume_with_catalog(signature, version, key_compare_type));
+            let header = filesystem.get_volume_header().unwrap();
+            assert_eq!(header.is_case_sensitive().unwrap(), expected, "{:#x}", key_compare_type);
+        }
+    }
+
+    #[test]
+    fn comparisons_read_both_headers_even_if_invalid() {
+        let mut volume = VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS);
+        fixture::put_u32(volume.header(), 68, 5);
+        let mut image = volume.build();
+        // The primary header has a bad signature and a stale write count.
+        image[1024..1026].copy_from_slice(b"XX");
+        fixture::put_u32(&mut image, 1024 + 68, 4);
+        let filesystem = FileSystem::from_bytes(image);
+        let comparison = filesystem.compare_volume_headers().unwrap();
+        assert!(comparison.primary_error.is_some());
+        assert!(comparison.alternate_error.is_none());
+        assert_eq!(comparison.newer_write_count, Some(HeaderCopy::Alternate));
+        assert_eq!(comparison.newer_modify_date, None);
+        let fields: Vec<&str> = comparison.differences.iter().map(|d| &d.field[..]).collect();
+        assert_eq!(fields, ["signature", "write_count"]);
+    }
+
+    #[test]
+    fn scan_finds_volume_headers() {
+        let partition_offset = 8 * SIZE_HFS_SECTOR;
+        let mut image = vec![0xaa; partition_offset as usize];
+        image.extend(VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS).build());
+        let length = image.len() as u64;
+        let mut progress = Vec::new();
+        let found = FileSystem::scan_for_volume_headers(&mut Cursor::new(image), 0, length, SIZE_HFS_SECTOR,
+                                                        |offset| progress.push(offset)).unwrap();
+        // The alternate header looks like the header of a volume starting
+        // 1024 bytes before it.
+        let alternate = length - OFFSET_ALTERNATE_VOLUME_HEADER_FROM_END - OFFSET_VOLUME_HEADER;
+        let offsets: Vec<u64> = found.iter().map(|&(offset, _)| offset).collect();
+        assert_eq!(offsets, vec![partition_offset, alternate]);
+        assert_eq!(found[0].1.total_blocks, TOTAL_BLOCKS);
+        assert_eq!(progress.last(), Some(&length));
+    }
+
+    #[test]
+    fn scan_rejects_invalid_steps() {
+        let mut image = Cursor::new(VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS).build());
+        for &step in &[0, 100, SIZE_HFS_SECTOR + 1] {
+            let result = FileSystem::scan_for_volume_headers(&mut image, 0, 1 << 20, step, |_| {});
+            assert!(matches!(result, Err(HFSPError::InvalidArgument(_))), "{}", step);
+        }
+    }
+
+    #[test]
+    fn extent_descriptors_are_read_from_their_own_offsets() {
+        let (image, _) = volume_with_allocation_file(&EXTENTS, 12 * BLOCK_SIZE as u64 - 100);
+        let filesystem = FileSystem::from_bytes(image);
+        let fork = filesystem.get_volume_header().unwrap().get_fork_data_allocation();
+        for (idx, &(start, count)) in EXTENTS.iter().enumerate() {
+            let descriptor = fork.get_extent_descriptor(idx);
+            assert_eq!(descriptor.get_start_block().unwrap(), start);
+            assert_eq!(descriptor.get_block_count().unwrap(), count);
+        }
+        let used: Vec<(u32, u32)> = fork.extent_descriptors().map(Result::unwrap).collect();
+        assert_eq!(used, EXTENTS);
+        assert_eq!(fork.snapshot().unwrap().extents, EXTENTS);
+    }
+
+    #[test]
+    fn fork_snapshot_matches_lazy_accessors() {
+        let (image, _) = volume_with_allocation_file(&EXTENTS, 12 * BLOCK_SIZE as u64 - 100);
+        let filesystem = FileSystem::from_bytes(image);
+        let fork = filesystem.get_volume_header().unwrap().get_fork_data_allocation();
+        let snapshot = fork.snapshot().unwrap();
+        assert_eq!(snapshot.logical_size, fork.get_logical_size().unwrap());
+        assert_eq!(snapshot.clump_size, fork.get_clump_size().unwrap());
+        assert_eq!(snapshot.total_blocks, fork.get_total_blocks().unwrap());
+        assert_eq!(snapshot.total_blocks, 12);
+        let descriptors: Vec<(u32, u32)> = fork.extent_descriptors_raw().map(Result::unwrap).collect();
+        assert_eq!(snapshot.extents[..], descriptors[..]);
+    }
+
+    #[test]
+    fn file_reads_follow_the_extents() {
+        let (image, data) = volume_with_allocation_file(&EXTENTS, 12 * BLOCK_SIZE as u64 - 100);
+        let filesystem = FileSystem::from_bytes(image);
+        let mut file = filesystem.get_volume_header().unwrap().get_file_allocation().unwrap();
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, data);
+    }
+
+    // Two extents with junk between them, so that a read running on past the
+    // end of the first extent gives the wrong bytes.
+    fn volume_with_split_file() -> (Vec<u8>, Vec<u8>) {
+        let extents = [(10, 2), (30, 2)];
+        let data = fixture::pattern_bytes(4 * BLOCK_SIZE as u64 - 50);
+        let image = VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS)
+            .set_fork(SpecialFile::Allocation, data.len() as u64, &extents)
+            .write_fork(&[(12, 18)], &[0xee; 18 * BLOCK_SIZE as usize])
+            .write_fork(&extents, &data)
+            .build();
+        (image, data)
+    }
+
+    #[test]
+    fn reads_stop_at_the_end_of_an_extent() {
+        let (image, data) = volume_with_split_file();
+        let filesystem = FileSystem::from_bytes(image);
+        let mut file = filesystem.get_volume_header().unwrap().get_file_allocation().unwrap();
+        let boundary = 2 * BLOCK_SIZE as u64;
+
+        let mut buf = vec![0; 100];
+        assert_eq!(file.read_at(boundary - 10, &mut buf).unwrap(), 10);
+        assert_eq!(buf[..10], data[boundary as usize - 10..boundary as usize]);
+
+        // Reads as large as the buffer go straight to the source.
+        let mut buf = vec![0; file.get_buffer_size()];
+        file.seek(SeekFrom::Start(boundary - 10)).unwrap();
+        assert_eq!(file.read(&mut buf).unwrap(), 10);
+        assert_eq!(buf[..10], data[boundary as usize - 10..boundary as usize]);
+        assert_eq!(file.position(), boundary);
+        assert_eq!(file.read(&mut buf[..20]).unwrap(), 20);
+        assert_eq!(buf[..20], data[boundary as usize..boundary as usize + 20]);
+    }
+
+    #[test]
+    fn reads_continue_in_the_next_extent() {
+        let (image, data) = volume_with_split_file();
+        let filesystem = FileSystem::from_bytes(image);
+        let mut file = filesystem.get_volume_header().unwrap().get_file_allocation().unwrap();
+        let boundary = 2 * BLOCK_SIZE as usize;
+        for &(start, length) in &[(boundary - 10, 20), (boundary - 1, 2), (0, data.len()), (boundary - 300, 700)] {
+            let mut buf = vec![0; length];
+            file.seek(SeekFrom::Start(start as u64)).unwrap();
+            file.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, &data[start..start + length], "{}..{}", start, start + length);
+            file.read_exact_at(start as u64, &mut buf).unwrap();
+            assert_eq!(buf, &data[start..start + length], "{}..{}", start, start + length);
+        }
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, data);
+    }
+
+    #[test]
+    fn unused_descriptors_end_the_extents() {
+        let extents = [(20, 2), (10, 1)];
+        let (image, data) = volume_with_allocation_file(&extents, 3 * BLOCK_SIZE as u64);
+        let filesystem = FileSystem::from_bytes(image);
+        let header = filesystem.get_volume_header().unwrap();
+        let fork = header.get_fork_data_allocation();
+        assert_eq!(fork.extent_descriptors().count(), 2);
+        assert_eq!(fork.extent_descriptors_raw().count(), 8);
+        let mut contents = Vec::new();
+        header.get_file_allocation().unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, data);
+    }
+
+    #[test]
+    fn buffered_reads_continue_past_short_source_reads() {
+        // The image is split inside blocks of the file, so reads from the
+        // source stop short there. Block 20 holds the start of the file.
+        let (image, data) = volume_with_allocation_file(&EXTENTS, 12 * BLOCK_SIZE as u64 - 100);
+        let splits = [20 * BLOCK_SIZE as usize + 8, 20 * BLOCK_SIZE as usize + 200, 31 * BLOCK_SIZE as usize + 1];
+        let mut pieces = Vec::new();
+        let mut start = 0;
+        for &end in splits.iter().chain(Some(&image.len())) {
+            pieces.push(Cursor::new(image[start..end].to_vec()));
+            start = end;
+        }
+        let filesystem = FileSystem::new(SegmentedSource::concatenate(pieces).unwrap());
+        let mut file = filesystem.get_volume_header().unwrap().get_file_allocation().unwrap();
+        for &(offset, length) in &[(100, 10), (8, 1), (300, 2), (3 * BLOCK_SIZE as usize + 100, 300)] {
+            let mut buf = vec![0; length];
+            file.seek(SeekFrom::Start(offset as u64)).unwrap();
+            file.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, &data[offset..offset + length], "{}..{}", offset, offset + length);
+        }
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, data);
+    }
+
+    #[test]
+    fn reads_at_the_end_return_nothing() {
+        let (image, data) = volume_with_allocation_file(&EXTENTS, 12 * BLOCK_SIZE as u64 - 100);
+        let filesystem = FileSystem::from_bytes(image);
+        let mut file = filesystem.get_volume_header().unwrap().get_file_allocation().unwrap();
+        let mut buf = vec![0; 100];
+        let end = data.len() as u64;
+        file.seek(SeekFrom::End(0)).unwrap();
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+        assert_eq!(file.read_at(end, &mut buf).unwrap(), 0);
+        assert_eq!(file.position(), end);
+
+        // A read running up to the end stops there.
+        file.seek(SeekFrom::Start(end - 10)).unwrap();
+        assert_eq!(file.read(&mut buf).unwrap(), 10);
+        assert_eq!(buf[..10], data[data.len() - 10..]);
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn reads_past_the_end_return_nothing() {
+        let (image, _) = volume_with_allocation_file(&EXTENTS, 12 * BLOCK_SIZE as u64 - 100);
+        let filesystem = FileSystem::from_bytes(image);
+        let mut file = filesystem.get_volume_header().unwrap().get_file_allocation().unwrap();
+        let mut buf = vec![0; 100];
+        for &offset in &[file.len() + 1, file.len() + 10 * BLOCK_SIZE as u64, u64::MAX] {
+            assert_eq!(file.seek(SeekFrom::Start(offset)).unwrap(), offset);
+            assert_eq!(file.read(&mut buf).unwrap(), 0, "{}", offset);
+            assert_eq!(file.read_at(offset, &mut buf).unwrap(), 0, "{}", offset);
+            assert_eq!(file.position(), offset);
+        }
+        file.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(file.read(&mut buf).unwrap(), 100);
+    }
+
+    #[test]
+    fn empty_forks_read_nothing() {
+        let (image, _) = volume_with_allocation_file(&[], 0);
+        let filesystem = FileSystem::from_bytes(image);
+        let mut file = filesystem.get_volume_header().unwrap().get_file_allocation().unwrap();
+        assert!(file.is_empty());
+        assert!(file.is_complete());
+        assert_eq!(file.allocated_size(), 0);
+        let mut buf = vec![0; 100];
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+        file.seek(SeekFrom::Start(1000)).unwrap();
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(file.read_to_end(&mut contents).unwrap(), 0);
+    }
+
+    #[test]
+    fn forks_with_a_length_but_no_extents_fail_to_read() {
+        let image = VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS)
+            .set_fork(SpecialFile::Allocation, 3 * BLOCK_SIZE as u64, &[])
+            .build();
+        let filesystem = FileSystem::from_bytes(image);
+        let header = filesystem.get_volume_header().unwrap();
+        assert!(header.get_file_allocation().is_err());
+        let mut file = HFSFile::new_permissive(header.get_fork_data_allocation()).unwrap();
+        assert_eq!(file.recoverable_length(), 0);
+        let mut buf = vec![0; 100];
+        assert!(file.read(&mut buf).is_err());
+        assert!(file.read_at(BLOCK_SIZE as u64, &mut buf).is_err());
+        file.seek(SeekFrom::End(0)).unwrap();
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+
+        let mut file = file.with_hole_policy(HolePolicy::ZeroFill);
+        file.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(file.read(&mut buf).unwrap(), 100);
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    // A volume claiming the most blocks a header can hold, though the image
+    // is no bigger than usual.
+    fn filesystem_with_huge_extents(length: u64, extents: &[(u32, u32)]) -> FileSystem<Cursor<Vec<u8>>> {
+        let mut volume = VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS);
+        fixture::put_u32(volume.header(), 44, u32::MAX);
+        let mut filesystem = FileSystem::from_bytes(volume.set_fork(SpecialFile::Allocation, length, extents).build());
+        filesystem.set_fork_size_limit(ForkSizeLimit::Unlimited);
+        filesystem
+    }
+
+    #[test]
+    fn block_counts_past_u32_overflow() {
+        let extents = [(1, u32::MAX - 1), (0, 1), (0, 1)];
+        let filesystem = filesystem_with_huge_extents(u64::MAX, &extents);
+        let header = filesystem.get_volume_header().unwrap();
+        // The total blocks of the fork data can't cover the length, so only
+        // a permissive open gets as far as the sum.
+        match header.get_file_allocation() {
+            Err(HFSPError::TruncatedFork { .. }) => {},
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        let file = HFSFile::new_permissive(header.get_fork_data_allocation()).unwrap();
+        assert_eq!(file.recoverable_length(), u32::MAX as u64 * BLOCK_SIZE as u64);
+        assert_eq!(file.extents().len(), 2);
+        match file.truncation() {
+            Some(&HFSPError::ArithmeticOverflow) => {},
+            other => panic!("unexpected truncation {:?}", other),
+        }
+    }
+
+    #[test]
+    fn extents_past_the_end_of_the_image_fail_to_read() {
+        // The byte offsets of these blocks don't fit in 32 bits.
+        let extents = [(u32::MAX - 2, 2)];
+        let filesystem = filesystem_with_huge_extents(2 * BLOCK_SIZE as u64, &extents);
+        let mut file = filesystem.get_volume_header().unwrap().get_file_allocation().unwrap();
+        assert_eq!(file.extents()[0].device_offset, (u32::MAX - 2) as u64 * BLOCK_SIZE as u64);
+        let mut buf = vec![0; 100];
+        assert!(file.read_exact(&mut buf).is_err());
+        assert!(file.read_exact_at(BLOCK_SIZE as u64, &mut buf).is_err());
+
+        // Extents running past the total blocks are rejected before the
+        // sum is formed.
+        let filesystem = filesystem_with_huge_extents(2 * BLOCK_SIZE as u64, &[(u32::MAX - 1, 2)]);
+        match filesystem.get_volume_header().unwrap().get_file_allocation() {
+            Err(HFSPError::InvalidExtent { index: 0, .. }) => {},
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    // An allocation file of four blocks with only the first two in the fork
+    // data, and an extents file whose one record belongs to another file.
+    // Node 0 of the extents file is in block 20 and node 1 in block 21.
+    fn volume_with_missing_overflow_extents() -> VolumeBuilder {
+        let node_size = BLOCK_SIZE as usize;
+        let fields = fixture::BTreeHeaderFields {
+            depth: 1, root: 1, first_leaf: 1, last_leaf: 1, total_nodes: 2, max_key_length: 10,
+            ..Default::default()
+        };
+        let mut tree = fixture::btree_header_node(node_size, &fields);
+        let record = fixture::record(fixture::extent_key(0, 99, 0), &fixture::fork_data(0, &[(40, 1)])[16..]);
+        tree.extend(fixture::btree_node(node_size, -1, 1, 0, &[record]));
+        let mut volume = VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS);
+        volume.set_fork(SpecialFile::Allocation, 4 * BLOCK_SIZE as u64, &[(10, 2)])
+            .set_fork(SpecialFile::Extents, tree.len() as u64, &[(20, 2)])
+            .write_fork(&[(20, 2)], &tree)
+            .write_fork(&[(10, 2)], &fixture::pattern_bytes(2 * BLOCK_SIZE as u64));
+        // The fork data counts the blocks held in the overflow record too.
+        fixture::put_u32(volume.header(), OFFSET_VOLUME_HEADER_FORKS as usize + 12, 4);
+        volume
+    }
+
+    #[test]
+    fn permissive_open_records_a_missing_overflow_record() {
+        let filesystem = FileSystem::from_bytes(volume_with_missing_overflow_extents().build());
+        let header = filesystem.get_volume_header().unwrap();
+        match header.get_file_allocation() {
+            Err(HFSPError::MissingOverflowExtents) => {},
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        let mut file = HFSFile::new_permissive(header.get_fork_data_allocation()).unwrap();
+        assert!(!file.is_complete());
+        assert_eq!(file.recoverable_length(), 2 * BLOCK_SIZE as u64);
+        match file.truncation() {
+            Some(&HFSPError::MissingOverflowExtents) => {},
+            other => panic!("unexpected truncation {:?}", other),
+        }
+        assert!(file.clone().truncation().is_some());
+        let mut buf = vec![0; 2 * BLOCK_SIZE as usize];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, fixture::pattern_bytes(buf.len() as u64));
+    }
+
+    #[test]
+    fn permissive_open_records_a_failed_extents_read() {
+        // The leaf node of the extents file was never recovered.
+        let image = volume_with_missing_overflow_extents().build();
+        let regions = RegionMap::from_regions(vec![
+            Region { offset: 0, length: 21 * BLOCK_SIZE as u64, status: BlockStatus::Finished },
+            Region { offset: 21 * BLOCK_SIZE as u64, length: BLOCK_SIZE as u64, status: BlockStatus::BadSector },
+            Region { offset: 22 * BLOCK_SIZE as u64, length: image.len() as u64 - 22 * BLOCK_SIZE as u64,
+                     status: BlockStatus::Finished },
+        ]);
+        let filesystem = FileSystem::new(MappedSource::new(Cursor::new(image), regions));
+        let header = filesystem.get_volume_header().unwrap();
+        let file = HFSFile::new_permissive(header.get_fork_data_allocation()).unwrap();
+        assert_eq!(file.recoverable_length(), 2 * BLOCK_SIZE as u64);
+        match file.truncation() {
+            Some(HFSPError::IOError(error)) => assert!(UnrecoveredData::from_io_error(error).is_some()),
+            other => panic!("unexpected truncation {:?}", other),
+        }
+    }
+
+    #[test]
+    fn permissive_open_records_an_invalid_extent() {
+        let extents = [(10, 2), (TOTAL_BLOCKS - 1, 2)];
+        let image = VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS)
+            .set_fork(SpecialFile::Allocation, 4 * BLOCK_SIZE as u64, &extents)
+            .build();
+        let filesystem = FileSystem::from_bytes(image);
+        let header = filesystem.get_volume_header().unwrap();
+        let file = HFSFile::new_permissive(header.get_fork_data_allocation()).unwrap();
+        assert_eq!(file.recoverable_length(), 2 * BLOCK_SIZE as u64);
+        match file.truncation() {
+            Some(&HFSPError::InvalidExtent { index: 1, start, count: 2 }) => assert_eq!(start, TOTAL_BLOCKS - 1),
+            other => panic!("unexpected truncation {:?}", other),
+        }
+    }
+
+    #[test]
+    fn complete_files_have_no_truncation() {
+        let (image, _) = volume_with_allocation_file(&EXTENTS, 12 * BLOCK_SIZE as u64);
+        let filesystem = FileSystem::from_bytes(image);
+        let header = filesystem.get_volume_header().unwrap();
+        let file = HFSFile::new_permissive(header.get_fork_data_allocation()).unwrap();
+        assert!(file.is_complete());
+        assert!(file.truncation().is_none());
+    }
+
+    #[test]
+    fn four_character_codes_escape_unprintable_bytes() {
+        let cases = [
+            (*b"10.0", "10.0", Some("10.0")),
+            (*b"H+ \\", "H+ \\\\", Some("H+ \\")),
+            ([b'f', b's', b'c', 0], "fsc\\x00", Some("fsc\0")),
+            ([0x80, b'a', 0xff, 0x7f], "\\x80a\\xff\\x7f", None),
+        ];
+        for &(code, expected, as_str) in &cases {
+            let fourcc = FourCC::new(code);
+            assert_eq!(fourcc.to_string(), expected);
+            assert_eq!(fourcc.as_str(), as_str);
+            assert_eq!(fourcc.get_raw().to_be_bytes(), code);
         }
+        assert_eq!(LastMounted::from_fourcc(FourCC::new([0, 1, b'x', 0xfe])).to_string(),
+                   "unknown (\\x00\\x01x\\xfe)");
+    }
+
+    #[cfg(feature = "hashing")]
+    #[test]
+    fn volume_uuids_are_derived_from_the_volume_id() {
+        // The expected UUID is a version 3 UUID of the volume ID in Apple's
+        // HFS+ namespace, as computed by Python's hashlib.
+        let mut volume = VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS);
+        fixture::put_u32(volume.header(), 104, 0x01234567);
+        fixture::put_u32(volume.header(), 108, 0x89abcdef);
+        let filesystem = FileSystem::from_bytes(volume.build());
+        let finder_info = filesystem.get_volume_header().unwrap().get_finder_info().unwrap();
+        assert_eq!(finder_info.get_volume_id(), 0x0123456789abcdef);
+        assert_eq!(finder_info.get_volume_uuid().unwrap(), "DE53349F-21AD-3C08-9044-AE10CD9587D3");
+        assert_eq!(FinderInfo::new([0; 8]).get_volume_uuid(), None);
+    }
+
+    // An HFS wrapper with 4K allocation blocks starting at sector 16, holding
+    // the volume from allocation block 2 onwards.
+    fn hfs_wrapper(embedded: &[u8], embed_signature: &[u8; 2]) -> Vec<u8> {
+        let offset = 16 * 512 + 2 * 4096;
+        let mut image = vec![0; offset + embedded.len() + 4096];
+        {
+            let mdb = &mut image[1024..1024 + SIZE_MDB];
+            mdb[0..2].copy_from_slice(SIGNATURE_HFS);
+            fixture::put_u32(mdb, OFFSET_MDB_ALLOCATION_BLOCK_SIZE, 4096);
+            fixture::put_u16(mdb, OFFSET_MDB_ALLOCATION_BLOCK_START, 16);
+            mdb[OFFSET_MDB_EMBED_SIGNATURE..OFFSET_MDB_EMBED_SIGNATURE + 2].copy_from_slice(embed_signature);
+            fixture::put_u16(mdb, OFFSET_MDB_EMBED_EXTENT, 2);
+            fixture::put_u16(mdb, OFFSET_MDB_EMBED_EXTENT + 2, (embedded.len() / 4096) as u16);
+        }
+        image[offset..offset + embedded.len()].copy_from_slice(embedded);
+        image
+    }
+
+    #[test]
+    fn embedded_volumes_are_found_inside_an_hfs_wrapper() {
+        let (embedded, data) = volume_with_allocation_file(&EXTENTS, 12 * BLOCK_SIZE as u64);
+        let filesystem = FileSystem::open_embedded(Cursor::new(hfs_wrapper(&embedded, SIGNATURE_HFS_PLUS))).unwrap();
+        let header = filesystem.get_volume_header().unwrap();
+        assert_eq!(header.get_block_size().unwrap(), BLOCK_SIZE);
+        assert_eq!(header.get_total_blocks().unwrap(), TOTAL_BLOCKS);
+        let mut contents = Vec::new();
+        header.get_file_allocation().unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, data);
+        // The slice ends with the embedded volume, so its alternate header is
+        // found too.
+        filesystem.get_alternate_volume_header().unwrap();
+    }
+
+    #[test]
+    fn bare_volumes_open_without_a_wrapper() {
+        let (image, data) = volume_with_allocation_file(&EXTENTS, 12 * BLOCK_SIZE as u64);
+        let filesystem = FileSystem::open_embedded(Cursor::new(image)).unwrap();
+        let header = filesystem.get_volume_header().unwrap();
+        let mut contents = Vec::new();
+        header.get_file_allocation().unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, data);
+    }
+
+    #[test]
+    fn wrappers_without_an_embedded_volume_are_rejected() {
+        let embedded = VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS).build();
+        let plain = hfs_wrapper(&embedded, b"\0\0");
+        assert!(matches!(FileSystem::open_embedded(Cursor::new(plain)), Err(HFSPError::PlainHFSNotSupported)));
+        let mut unknown = hfs_wrapper(&embedded, SIGNATURE_HFS_PLUS);
+        unknown[1024..1026].copy_from_slice(b"XX");
+        assert!(matches!(FileSystem::open_embedded(Cursor::new(unknown)), Err(HFSPError::InvalidVolumeHeader)));
+    }
+
+    #[test]
+    fn headers_with_bad_versions_or_block_sizes_are_rejected() {
+        let open = |version: u16, block_size: u32| {
+            let mut volume = VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS);
+            fixture::put_u16(volume.header(), 2, version);
+            fixture::put_u32(volume.header(), 40, block_size);
+            let filesystem = FileSystem::from_bytes(volume.build());
+            let result = filesystem.get_volume_header().map(|_| ());
+            result
+        };
+        assert!(open(VERSION_HFS_PLUS, BLOCK_SIZE).is_ok());
+        match open(6, BLOCK_SIZE) {
+            Err(error @ HFSPError::UnsupportedVersion(6)) =>
+                assert_eq!(error.to_string(), "Unsupported volume header version 0x0006"),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(matches!(open(VERSION_HFSX, BLOCK_SIZE), Err(HFSPError::SignatureVersionMismatch)));
+        for &block_size in &[0, 256, 513, 3 * 512, MAX_BLOCK_SIZE * 2] {
+            match open(VERSION_HFS_PLUS, block_size) {
+                Err(HFSPError::InvalidBlockSize(size)) => assert_eq!(size, block_size),
+                other => panic!("unexpected result {:?} for {}", other, block_size),
+            }
+        }
+        match open(VERSION_HFS_PLUS, 1000) {
+            Err(error) => assert_eq!(error.to_string(), "Invalid block size 1000"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn forks_larger_than_the_volume_are_implausible() {
+        let volume_bytes = TOTAL_BLOCKS as u64 * BLOCK_SIZE as u64;
+        let open = |length: u64, limit: ForkSizeLimit| {
+            let image = VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS)
+                .set_fork(SpecialFile::Allocation, length, &EXTENTS)
+                .build();
+            let mut filesystem = FileSystem::from_bytes(image);
+            filesystem.set_fork_size_limit(limit);
+            let header = filesystem.get_volume_header().unwrap();
+            let result = HFSFile::new_permissive(header.get_fork_data_allocation()).map(|_| ());
+            result
+        };
+        match open(volume_bytes + 1, ForkSizeLimit::VolumeSize) {
+            Err(error @ HFSPError::ImplausibleForkSize { .. }) => {
+                assert!(matches!(error, HFSPError::ImplausibleForkSize { claimed, maximum }
+                                 if claimed == volume_bytes + 1 && maximum == volume_bytes));
+                assert_eq!(error.to_string(),
+                           "Fork is larger than the size limit: 32769 bytes claimed but at most 32768 allowed");
+            },
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(open(volume_bytes, ForkSizeLimit::VolumeSize).is_ok());
+        assert!(matches!(open(1001, ForkSizeLimit::Bytes(1000)),
+                         Err(HFSPError::ImplausibleForkSize { claimed: 1001, maximum: 1000 })));
+        assert!(open(volume_bytes + 1, ForkSizeLimit::Unlimited).is_ok());
+    }
+
+    #[test]
+    fn clones_read_independently_of_the_original() {
+        let (image, data) = volume_with_allocation_file(&EXTENTS, 12 * BLOCK_SIZE as u64);
+        let filesystem = FileSystem::from_bytes(image);
+        let header = filesystem.get_volume_header().unwrap();
+        let mut file = header.get_file_allocation().unwrap();
+        let mut buf = [0; 100];
+        file.seek(SeekFrom::Start(1000)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+
+        let mut clone = file.clone();
+        assert_eq!(clone.stream_position().unwrap(), 1100);
+        let mut contents = Vec::new();
+        clone.seek(SeekFrom::Start(0)).unwrap();
+        clone.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, data);
+
+        assert_eq!(file.stream_position().unwrap(), 1100);
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[..], data[1100..1200]);
+        file.reopen();
+        assert_eq!(file.stream_position().unwrap(), 0);
+        assert_eq!(clone.stream_position().unwrap(), data.len() as u64);
+    }
+
+    // A volume with a catalog of 1K nodes in blocks 10-13 and an extents
+    // overflow file in blocks 20-21. File 20 is /a.txt and file 21 is /Docs/b.txt, whose data
+    // fork shares block 31 with file 20 and continues in the overflow file
+    // from fork block 8.
+    fn volume_with_owned_blocks() -> Vec<u8> {
+        let fields = fixture::BTreeHeaderFields {
+            depth: 1, root: 1, first_leaf: 1, last_leaf: 1, total_nodes: 2, max_key_length: 516, attributes: 6,
+            ..Default::default()
+        };
+        let b_extents = [(31, 1), (33, 1), (34, 1), (35, 1), (36, 1), (37, 1), (38, 1), (39, 1)];
+        let mut catalog = fixture::btree_header_node(1024, &fields);
+        catalog.extend(fixture::btree_node(1024, -1, 1, 0, &[
+            fixture::record(fixture::catalog_key(1, "Vol"), &fixture::catalog_folder(2)),
+            fixture::record(fixture::catalog_key(2, "a.txt"),
+                            &fixture::catalog_file(20, &fixture::fork_data(1024, &[(30, 2)]),
+                                                   &fixture::fork_data(100, &[(40, 1)]))),
+            fixture::record(fixture::catalog_key(2, "Docs"), &fixture::catalog_folder(16)),
+            fixture::record(fixture::catalog_key(16, "b.txt"),
+                            &fixture::catalog_file(21, &fixture::fork_data(10 * 512, &b_extents),
+                                                   &fixture::fork_data(0, &[]))),
+        ]));
+        let fields = fixture::BTreeHeaderFields {
+            depth: 1, root: 1, first_leaf: 1, last_leaf: 1, total_nodes: 2, max_key_length: 10,
+            ..Default::default()
+        };
+        let mut overflow_extents = [0; 64];
+        fixture::put_u32(&mut overflow_extents, 0, 55);
+        fixture::put_u32(&mut overflow_extents, 4, 2);
+        let mut extents = fixture::btree_header_node(BLOCK_SIZE as usize, &fields);
+        extents.extend(fixture::btree_node(BLOCK_SIZE as usize, -1, 1, 0, &[
+            fixture::record(fixture::extent_key(0, 21, 8), &overflow_extents),
+        ]));
+        VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS)
+            .set_fork(SpecialFile::Catalog, catalog.len() as u64, &[(10, 4)])
+            .write_fork(&[(10, 4)], &catalog)
+            .set_fork(SpecialFile::Extents, extents.len() as u64, &[(20, 2)])
+            .write_fork(&[(20, 2)], &extents)
+            .build()
+    }
+
+    #[test]
+    fn owners_are_found_for_shared_unowned_and_overflow_blocks() {
+        let filesystem = FileSystem::from_bytes(volume_with_owned_blocks());
+        let search = filesystem.find_owners_of_blocks(&[56, 45, 31, 11, 40]).unwrap();
+        let complete = TreeCoverage { nodes_total: 2, nodes_unreadable: 0 };
+        assert_eq!(search.catalog, Some(complete));
+        assert_eq!(search.extents_overflow, Some(complete));
+        let owner = |block, cnid, fork_type, path: Option<&str>, start| BlockOwner {
+            block,
+            cnid,
+            fork_type,
+            path: path.map(|path| path.to_string()),
+            logical_range: start..start + BLOCK_SIZE as u64,
+        };
+        assert_eq!(search.owners, [
+            owner(11, CNID_CATALOG_FILE, ForkType::Data, None, 512),
+            owner(31, 20, ForkType::Data, Some("/a.txt"), 512),
+            owner(31, 21, ForkType::Data, Some("/Docs/b.txt"), 0),
+            owner(40, 20, ForkType::Resource, Some("/a.txt"), 0),
+            owner(56, 21, ForkType::Data, Some("/Docs/b.txt"), 9 * 512),
+        ]);
+        assert!(search.owners.iter().all(|owner| owner.block != 45));
+        assert_eq!(filesystem.find_owner_of_block(45).unwrap().owners, []);
+    }
+
+    // Fails any read touching a bad range, however much of it is good, as a
+    // disk does when a multi-sector read hits a bad sector.
+    struct BadRanges {
+        source: Cursor<Vec<u8>>,
+        bad: Vec<Range<u64>>,
+    }
+
+    impl Read for BadRanges {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let start = self.source.position();
+            let end = start + buf.len() as u64;
+            if self.bad.iter().any(|range| range.start < end && start < range.end) {
+                return Err(io::Error::other("bad sector"));
+            }
+            self.source.read(buf)
+        }
+    }
+
+    impl Seek for BadRanges {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.source.seek(pos)
+        }
+    }
+
+    // Device blocks 31 and 32 are the adjacent file blocks 4 and 5, and
+    // device block 45 is file block 8.
+    fn filesystem_with_bad_blocks() -> (FileSystem<BadRanges>, Vec<u8>) {
+        let (image, data) = volume_with_allocation_file(&EXTENTS, 12 * BLOCK_SIZE as u64);
+        let block = |index: u64| index * BLOCK_SIZE as u64..(index + 1) * BLOCK_SIZE as u64;
+        let source = BadRanges { source: Cursor::new(image), bad: vec![block(31), block(32), block(45)] };
+        (FileSystem::new(source), data)
+    }
+
+    #[test]
+    fn zero_filled_copies_retry_and_merge_damaged_ranges() {
+        let (filesystem, data) = filesystem_with_bad_blocks();
+        let header = filesystem.get_volume_header().unwrap();
+        let mut file = header.get_file_allocation().unwrap().with_hole_policy(HolePolicy::ZeroFill);
+        let mut copied = Vec::new();
+        let mut last_progress = None;
+        let outcome = file.copy_to(&mut copied, |progress| last_progress = Some(progress)).unwrap();
+
+        let damaged = 4 * BLOCK_SIZE as u64..6 * BLOCK_SIZE as u64;
+        let lone = 8 * BLOCK_SIZE as u64..9 * BLOCK_SIZE as u64;
+        assert_eq!(file.damaged_ranges(), [damaged.clone(), lone.clone()]);
+        assert_eq!(outcome, CopyOutcome::ZeroFilled { bytes_copied: data.len() as u64, damaged_bytes: 3 * 512 });
+        assert_eq!(outcome.bytes_copied(), copied.len() as u64);
+        assert!(!outcome.is_complete());
+        assert_eq!(last_progress, Some(CopyProgress { bytes_copied: data.len() as u64, total_bytes: data.len() as u64 }));
+        // File block 3 shared a failed read with the bad blocks, so it is
+        // only intact because the first block was retried alone.
+        let mut expected = data.clone();
+        for range in &[damaged, lone] {
+            for byte in &mut expected[range.start as usize..range.end as usize] {
+                *byte = 0;
+            }
+        }
+        assert_eq!(copied, expected);
+    }
+
+    #[test]
+    fn failed_copies_stop_at_the_first_bad_read() {
+        let (filesystem, data) = filesystem_with_bad_blocks();
+        let header = filesystem.get_volume_header().unwrap();
+        let mut file = header.get_file_allocation().unwrap();
+        let mut copied = Vec::new();
+        let error = file.copy_to(&mut copied, |_| ()).unwrap_err();
+        assert_eq!(error.kind, CopyErrorKind::Read);
+        assert_eq!(error.logical_offset, 3 * BLOCK_SIZE as u64);
+        assert_eq!(error.device_offset, Some(30 * BLOCK_SIZE as u64));
+        assert_eq!(copied, &data[..3 * BLOCK_SIZE as usize]);
+        assert_eq!(file.stream_position().unwrap(), copied.len() as u64);
+        assert!(file.damaged_ranges().is_empty());
+    }
+
+    #[test]
+    fn copy_outcomes_count_the_bytes_written() {
+        let (image, data) = volume_with_allocation_file(&EXTENTS, 12 * BLOCK_SIZE as u64 - 100);
+        let filesystem = FileSystem::from_bytes(image);
+        let header = filesystem.get_volume_header().unwrap();
+        let mut file = header.get_file_allocation().unwrap();
+        file.seek(SeekFrom::Start(1000)).unwrap();
+        let mut copied = Vec::new();
+        let outcome = file.copy_to(&mut copied, |_| ()).unwrap();
+        assert_eq!(outcome, CopyOutcome::Complete { bytes_copied: data.len() as u64 - 1000 });
+        assert_eq!(copied, &data[1000..]);
+
+        // A writer that takes nothing fails the copy on the write side.
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let error = file.copy_to(&mut [][..], |_| ()).unwrap_err();
+        assert_eq!(error.kind, CopyErrorKind::Write);
+        assert_eq!((error.logical_offset, error.device_offset), (0, None));
+    }
+
+    // The allocation file continues from fork block 2 in an overflow record
+    // in the extents file.
+    fn volume_with_overflowing_allocation_file() -> (Vec<u8>, Vec<u8>) {
+        let fields = fixture::BTreeHeaderFields {
+            depth: 1, root: 1, first_leaf: 1, last_leaf: 1, total_nodes: 2, max_key_length: 10,
+            ..Default::default()
+        };
+        let mut tree = fixture::btree_header_node(BLOCK_SIZE as usize, &fields);
+        let record = fixture::extent_key(0, CNID_ALLOCATION_FILE, 2);
+        tree.extend(fixture::btree_node(BLOCK_SIZE as usize, -1, 1, 0, &[
+            fixture::record(record, &fixture::fork_data(0, &[(40, 2)])[16..]),
+        ]));
+        let data = fixture::pattern_bytes(4 * BLOCK_SIZE as u64);
+        let mut volume = VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS);
+        volume.set_fork(SpecialFile::Allocation, data.len() as u64, &[(10, 2)])
+            .set_fork(SpecialFile::Extents, tree.len() as u64, &[(20, 2)])
+            .write_fork(&[(20, 2)], &tree)
+            .write_fork(&[(10, 2), (40, 2)], &data);
+        fixture::put_u32(volume.header(), OFFSET_VOLUME_HEADER_FORKS as usize + 12, 4);
+        (volume.build(), data)
+    }
+
+    #[test]
+    fn forks_from_the_alternate_header_use_its_geometry() {
+        let (mut image, data) = volume_with_overflowing_allocation_file();
+        // The primary header's block size is invalid and its extents file
+        // points at empty blocks.
+        {
+            let primary = &mut image[1024..1024 + SIZE_VOLUME_HEADER];
+            fixture::put_u32(primary, 40, 3);
+            primary[OFFSET_VOLUME_HEADER_FORKS as usize + SIZE_FORK_DATA as usize..][..SIZE_FORK_DATA as usize]
+                .copy_from_slice(&fixture::fork_data(2 * BLOCK_SIZE as u64, &[(50, 2)]));
+        }
+        let filesystem = FileSystem::from_bytes(image);
+        assert!(matches!(filesystem.get_volume_header(), Err(HFSPError::InvalidBlockSize(3))));
+        let header = filesystem.get_alternate_volume_header().unwrap();
+        let mut contents = Vec::new();
+        header.get_file_allocation().unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, data);
+        assert!(matches!(filesystem.get_volume_header_unchecked().get_file_allocation(),
+                         Err(HFSPError::InvalidBlockSize(3))));
+    }
+
+    #[test]
+    fn forks_from_an_unchecked_header_use_its_geometry() {
+        let (mut image, data) = volume_with_overflowing_allocation_file();
+        image[1024..1026].copy_from_slice(b"XX");
+        let length = image.len();
+        image[length - 1024..length - 1024 + SIZE_VOLUME_HEADER].fill(0);
+        let filesystem = FileSystem::from_bytes(image);
+        assert!(filesystem.get_volume_header().is_err());
+        let header = filesystem.get_volume_header_unchecked();
+        let mut contents = Vec::new();
+        header.get_file_allocation().unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, data);
+    }
+
+    #[test]
+    fn forks_continue_through_several_overflow_records() {
+        // Eight inline extents, a full overflow record from fork block 8 and
+        // a partly used one from fork block 16, interleaved on the device.
+        let inline: Vec<(u32, u32)> = (0..8).map(|idx| (30 + 2 * idx, 1)).collect();
+        let first: Vec<(u32, u32)> = (0..8).map(|idx| (31 + 2 * idx, 1)).collect();
+        let second = [(46, 2), (50, 1)];
+        let fields = fixture::BTreeHeaderFields {
+            depth: 1, root: 1, first_leaf: 1, last_leaf: 1, total_nodes: 2, max_key_length: 10,
+            ..Default::default()
+        };
+        let mut tree = fixture::btree_header_node(BLOCK_SIZE as usize, &fields);
+        tree.extend(fixture::btree_node(BLOCK_SIZE as usize, -1, 1, 0, &[
+            fixture::record(fixture::extent_key(0, CNID_ALLOCATION_FILE, 8), &fixture::fork_data(0, &first)[16..]),
+            fixture::record(fixture::extent_key(0, CNID_ALLOCATION_FILE, 16), &fixture::fork_data(0, &second)[16..]),
+        ]));
+        let all: Vec<(u32, u32)> = inline.iter().chain(&first).chain(&second).cloned().collect();
+        let data = fixture::pattern_bytes(19 * BLOCK_SIZE as u64 - 100);
+        let mut volume = VolumeBuilder::new(BLOCK_SIZE, TOTAL_BLOCKS);
+        volume.set_fork(SpecialFile::Allocation, data.len() as u64, &inline)
+            .set_fork(SpecialFile::Extents, tree.len() as u64, &[(20, 2)])
+            .write_fork(&[(20, 2)], &tree)
+            .write_fork(&all, &data);
+        fixture::put_u32(volume.header(), OFFSET_VOLUME_HEADER_FORKS as usize + 12, 19);
+
+        let filesystem = FileSystem::from_bytes(volume.build());
+        let header = filesystem.get_volume_header().unwrap();
+        let mut file = header.get_file_allocation().unwrap();
+        assert!(file.is_complete());
+        let extents = file.extents();
+        assert_eq!(extents.len(), all.len());
+        assert_eq!(extents[8], ExtentRange {
+            device_offset: 31 * BLOCK_SIZE as u64,
+            length: BLOCK_SIZE as u64,
+            logical_offset: 8 * BLOCK_SIZE as u64,
+        });
+        assert_eq!(extents[16].device_offset, 46 * BLOCK_SIZE as u64);
+        assert_eq!(extents[17].length, BLOCK_SIZE as u64 - 100);
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, data);
     }
 }
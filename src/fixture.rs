@@ -0,0 +1,209 @@
+// Builders for synthetic volumes and B-tree nodes held in memory, shared by
+// the tests of several modules.
+use filesystem::{OFFSET_VOLUME_HEADER_FORKS, SIZE_FORK_DATA, SIZE_VOLUME_HEADER};
+use snapshot::SpecialFile;
+
+const OFFSET_VOLUME_HEADER: usize = 1024;
+
+pub fn put_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+pub fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+pub fn put_u64(data: &mut [u8], offset: usize, value: u64) {
+    data[offset..offset + 8].copy_from_slice(&value.to_be_bytes());
+}
+
+// A byte that depends on where it is, so data read from the wrong place
+// shows up.
+pub fn pattern(offset: u64) -> u8 {
+    (offset % 251) as u8
+}
+
+pub fn pattern_bytes(length: u64) -> Vec<u8> {
+    (0..length).map(pattern).collect()
+}
+
+// The 80 bytes of an HFSPlusForkData, with the total blocks taken from the
+// extents. The total wraps as it would on a corrupt volume.
+pub fn fork_data(logical_size: u64, extents: &[(u32, u32)]) -> [u8; SIZE_FORK_DATA as usize] {
+    assert!(extents.len() <= 8);
+    let mut result = [0; SIZE_FORK_DATA as usize];
+    put_u64(&mut result, 0, logical_size);
+    put_u32(&mut result, 12, extents.iter().fold(0, |total: u32, &(_, count)| total.wrapping_add(count)));
+    for (idx, &(start, count)) in extents.iter().enumerate() {
+        put_u32(&mut result, 16 + idx * 8, start);
+        put_u32(&mut result, 20 + idx * 8, count);
+    }
+    result
+}
+
+// An HFS+ volume image starting at the start of the partition. The alternate
+// header is written when the image is built.
+pub struct VolumeBuilder {
+    data: Vec<u8>,
+    block_size: u32,
+}
+
+impl VolumeBuilder {
+    pub fn new(block_size: u32, total_blocks: u32) -> VolumeBuilder {
+        let mut result = VolumeBuilder {
+            data: vec![0; block_size as usize * total_blocks as usize],
+            block_size,
+        };
+        {
+            let header = result.header();
+            header[0..2].copy_from_slice(b"H+");
+            put_u16(header, 2, 4);
+            put_u32(header, 40, block_size);
+            put_u32(header, 44, total_blocks);
+        }
+        result
+    }
+
+    pub fn header(&mut self) -> &mut [u8] {
+        &mut self.data[OFFSET_VOLUME_HEADER..OFFSET_VOLUME_HEADER + SIZE_VOLUME_HEADER]
+    }
+
+    pub fn set_fork(&mut self, file: SpecialFile, logical_size: u64, extents: &[(u32, u32)]) -> &mut VolumeBuilder {
+        let index = match file {
+            SpecialFile::Allocation => 0,
+            SpecialFile::Extents => 1,
+            SpecialFile::Catalog => 2,
+            SpecialFile::Attributes => 3,
+            SpecialFile::Startup => 4,
+        };
+        let offset = (OFFSET_VOLUME_HEADER_FORKS + SIZE_FORK_DATA * index) as usize;
+        self.header()[offset..offset + SIZE_FORK_DATA as usize].copy_from_slice(&fork_data(logical_size, extents));
+        self
+    }
+
+    // Spreads the data over the extents in order, as a fork would be.
+    pub fn write_fork(&mut self, extents: &[(u32, u32)], data: &[u8]) -> &mut VolumeBuilder {
+        let block_size = self.block_size as usize;
+        let mut remaining = data;
+        for &(start, count) in extents {
+            let size = remaining.len().min(count as usize * block_size);
+            let offset = start as usize * block_size;
+            self.data[offset..offset + size].copy_from_slice(&remaining[..size]);
+            remaining = &remaining[size..];
+        }
+        assert!(remaining.is_empty());
+        self
+    }
+
+    pub fn build(&mut self) -> Vec<u8> {
+        let mut result = self.data.clone();
+        let alternate = result.len() - OFFSET_VOLUME_HEADER;
+        result.copy_within(OFFSET_VOLUME_HEADER..OFFSET_VOLUME_HEADER + SIZE_VOLUME_HEADER, alternate);
+        result
+    }
+}
+
+// A B-tree node with the records packed after the descriptor and the offset
+// table at the end.
+pub fn btree_node(node_size: usize, kind: i8, height: u8, next: u32, records: &[Vec<u8>]) -> Vec<u8> {
+    let mut result = vec![0; node_size];
+    put_u32(&mut result, 0, next);
+    result[8] = kind as u8;
+    result[9] = height;
+    put_u16(&mut result, 10, records.len() as u16);
+    let mut offset = 14;
+    for (idx, record) in records.iter().enumerate() {
+        put_u16(&mut result, node_size - 2 * (idx + 1), offset as u16);
+        result[offset..offset + record.len()].copy_from_slice(record);
+        offset += record.len();
+    }
+    put_u16(&mut result, node_size - 2 * (records.len() + 1), offset as u16);
+    result
+}
+
+#[derive(Default)]
+pub struct BTreeHeaderFields {
+    pub depth: u16,
+    pub root: u32,
+    pub first_leaf: u32,
+    pub last_leaf: u32,
+    pub total_nodes: u32,
+    pub max_key_length: u16,
+    pub key_compare_type: u8,
+    pub attributes: u32,
+}
+
+// Node 0 of a tree: the header record, an empty user data record and a map
+// record marking every node as in use.
+pub fn btree_header_node(node_size: usize, fields: &BTreeHeaderFields) -> Vec<u8> {
+    let mut header = vec![0; 106];
+    put_u16(&mut header, 0, fields.depth);
+    put_u32(&mut header, 2, fields.root);
+    put_u32(&mut header, 10, fields.first_leaf);
+    put_u32(&mut header, 14, fields.last_leaf);
+    put_u16(&mut header, 18, node_size as u16);
+    put_u16(&mut header, 20, fields.max_key_length);
+    put_u32(&mut header, 22, fields.total_nodes);
+    header[37] = fields.key_compare_type;
+    put_u32(&mut header, 38, fields.attributes);
+    let map_size = node_size - 14 - 106 - 128 - 2 * 4;
+    btree_node(node_size, 1, 0, 0, &[header, vec![0; 128], vec![0xff; map_size]])
+}
+
+// A catalog key with its length prefix, as found at the start of a record.
+pub fn catalog_key(parent_id: u32, name: &str) -> Vec<u8> {
+    let name: Vec<u16> = name.encode_utf16().collect();
+    let mut result = Vec::new();
+    result.extend_from_slice(&(6 + 2 * name.len() as u16).to_be_bytes());
+    result.extend_from_slice(&parent_id.to_be_bytes());
+    result.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    for unit in name {
+        result.extend_from_slice(&unit.to_be_bytes());
+    }
+    result
+}
+
+// An HFSPlusCatalogFolder record.
+pub fn catalog_folder(folder_id: u32) -> Vec<u8> {
+    let mut result = vec![0; 88];
+    put_u16(&mut result, 0, 1);
+    put_u32(&mut result, 8, folder_id);
+    result
+}
+
+// An HFSPlusCatalogFile record with the given forks.
+pub fn catalog_file(file_id: u32, data_fork: &[u8], resource_fork: &[u8]) -> Vec<u8> {
+    let mut result = vec![0; 248];
+    put_u16(&mut result, 0, 2);
+    put_u32(&mut result, 8, file_id);
+    result[88..88 + data_fork.len()].copy_from_slice(data_fork);
+    result[168..168 + resource_fork.len()].copy_from_slice(resource_fork);
+    result
+}
+
+// An HFSPlusCatalogThread record, whose parent ID and name are laid out as
+// in a key.
+pub fn catalog_thread(parent_id: u32, name: &str) -> Vec<u8> {
+    let mut result = vec![0, 3, 0, 0];
+    result.extend_from_slice(&catalog_key(parent_id, name)[2..]);
+    result
+}
+
+// An extents overflow key with its length prefix.
+pub fn extent_key(fork_type: u8, file_id: u32, start_block: u32) -> Vec<u8> {
+    let mut result = vec![0, 10, fork_type, 0];
+    result.extend_from_slice(&file_id.to_be_bytes());
+    result.extend_from_slice(&start_block.to_be_bytes());
+    result
+}
+
+pub fn record(key: Vec<u8>, data: &[u8]) -> Vec<u8> {
+    let mut result = key;
+    result.extend_from_slice(data);
+    result
+}
+
+#[cfg(feature = "hashing")]
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct FragmentationReport {
+    pub extent_count: usize,
+    pub largest_extent_blocks: u32,
+    pub smallest_extent_blocks: u32,
+    pub allocated_bytes: u64,
+    // From the start of the lowest extent to the end of the highest one.
+    pub span_bytes: u64,
+    pub logical_size: u64,
+    // 0 for a contiguous fork, rising to 1 when every block is its own extent.
+    pub fragmentation_ratio: f64,
+}
+
+impl FragmentationReport {
+    pub fn new(extents: &[(u32, u32)], logical_size: u64, block_size: u32) -> FragmentationReport {
+        let block_size = block_size as u64;
+        let total_blocks: u64 = extents.iter().map(|&(_, count)| count as u64).sum();
+        let lowest = extents.iter().map(|&(start, _)| start as u64).min().unwrap_or(0);
+        let highest = extents.iter().map(|&(start, count)| start as u64 + count as u64).max().unwrap_or(0);
+        let fragmentation_ratio = if extents.len() > 1 && total_blocks > 1 {
+            (extents.len() - 1) as f64 / (total_blocks - 1) as f64
+        } else {
+            0.0
+        };
+        FragmentationReport {
+            extent_count: extents.len(),
+            largest_extent_blocks: extents.iter().map(|&(_, count)| count).max().unwrap_or(0),
+            smallest_extent_blocks: extents.iter().map(|&(_, count)| count).min().unwrap_or(0),
+            allocated_bytes: total_blocks * block_size,
+            span_bytes: (highest - lowest) * block_size,
+            logical_size,
+            fragmentation_ratio,
+        }
+    }
+}
+
+impl Display for FragmentationReport {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        writeln!(fmt, "Extents: {}", self.extent_count)?;
+        writeln!(fmt, "Largest extent: {} blocks", self.largest_extent_blocks)?;
+        writeln!(fmt, "Smallest extent: {} blocks", self.smallest_extent_blocks)?;
+        writeln!(fmt, "Logical size: {} bytes", self.logical_size)?;
+        writeln!(fmt, "Allocated: {} bytes", self.allocated_bytes)?;
+        writeln!(fmt, "Span on disk: {} bytes", self.span_bytes)?;
+        writeln!(fmt, "Fragmentation ratio: {:.3}", self.fragmentation_ratio)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct FragmentationHistogram {
+    pub forks_by_extent_count: BTreeMap<usize, u64>,
+    pub unreadable_forks: u64,
+    // The file ID and extent count of the fork with the most extents.
+    pub most_fragmented: Option<(u32, usize)>,
+}
+
+impl FragmentationHistogram {
+    pub fn new() -> FragmentationHistogram {
+        FragmentationHistogram::default()
+    }
+
+    pub fn add(&mut self, file_id: u32, report: &FragmentationReport) {
+        *self.forks_by_extent_count.entry(report.extent_count).or_insert(0) += 1;
+        let is_worst = match self.most_fragmented {
+            Some((_, extents)) => report.extent_count > extents,
+            None => true,
+        };
+        if is_worst {
+            self.most_fragmented = Some((file_id, report.extent_count));
+        }
+    }
+
+    pub fn add_unreadable(&mut self) {
+        self.unreadable_forks += 1;
+    }
+
+    pub fn total_forks(&self) -> u64 {
+        self.forks_by_extent_count.values().sum::<u64>() + self.unreadable_forks
+    }
+}
+
+impl Display for FragmentationHistogram {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        for (&extents, &forks) in &self.forks_by_extent_count {
+            writeln!(fmt, "{} {}: {} {}", extents, if extents == 1 { "extent" } else { "extents" }, forks,
+                     if forks == 1 { "fork" } else { "forks" })?;
+        }
+        writeln!(fmt, "Unreadable: {}", self.unreadable_forks)?;
+        if let Some((file_id, extents)) = self.most_fragmented {
+            writeln!(fmt, "Most fragmented: file {} with {} extents", file_id, extents)?;
+        }
+        Ok(())
+    }
+}
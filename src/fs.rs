@@ -1,4 +1,66 @@
 use error::HFSPError;
+use filesystem::FileSystem;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek};
 use std::result;
 
 pub type Result<T> = result::Result<T, HFSPError>;
+
+// Anything the library can read a volume from, including in-memory data
+// through io::Cursor.
+pub trait Source: Read + Seek {}
+
+impl<T> Source for T where T: Read + Seek {}
+
+pub type DynSource = Box<dyn Source + Send>;
+
+// Sources that can be duplicated to give independent readers of the same
+// data.
+pub trait TryClone: Sized {
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+// Clones share the OS file offset. FileSlice seeks before every access, so
+// slices of a File work from one thread, but slices read from several threads
+// at once need PositionalFile.
+impl TryClone for File {
+    fn try_clone(&self) -> io::Result<File> {
+        File::try_clone(self)
+    }
+}
+
+// Cheap for borrowed data, but copies owned data.
+impl<T> TryClone for Cursor<T> where T: Clone {
+    fn try_clone(&self) -> io::Result<Cursor<T>> {
+        Ok(self.clone())
+    }
+}
+
+pub type DynFileSystem = FileSystem<DynSource>;
+
+// Like read_exact(), but stops quietly at end of file and returns the number
+// of bytes read.
+pub fn read_fully<R: Read + ?Sized>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buffer.len() {
+        match reader.read(&mut buffer[total..]) {
+            Ok(0) => break,
+            Ok(read) => total += read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(total)
+}
+
+// Applies a relative seek offset, failing as Seek implementations should for
+// positions before the start or beyond u64.
+pub fn offset_position(base: u64, offset: i64) -> io::Result<u64> {
+    let position = if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.unsigned_abs())
+    };
+    position.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                                          "invalid seek to a negative or overflowing position"))
+}
@@ -0,0 +1,212 @@
+use crc32::Crc32;
+use md5::Md5;
+use sha256::Sha256;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum HashAlgorithm {
+    Crc32,
+    Md5,
+    Sha256,
+}
+
+pub const HASH_ALGORITHMS: [HashAlgorithm; 3] = [HashAlgorithm::Crc32, HashAlgorithm::Md5, HashAlgorithm::Sha256];
+
+// Only the selected algorithms have a value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Digests {
+    pub crc32: Option<u32>,
+    pub md5: Option<[u8; 16]>,
+    pub sha256: Option<[u8; 32]>,
+}
+
+impl Display for Digests {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut separator = "";
+        if let Some(crc32) = self.crc32 {
+            write!(f, "CRC32: {:08x}", crc32)?;
+            separator = ", ";
+        }
+        if let Some(md5) = self.md5 {
+            write!(f, "{}MD5: {}", separator, Hex(&md5))?;
+            separator = ", ";
+        }
+        if let Some(sha256) = self.sha256 {
+            write!(f, "{}SHA-256: {}", separator, Hex(&sha256))?;
+        }
+        Ok(())
+    }
+}
+
+struct Hex<'a>(&'a [u8]);
+
+impl<'a> Display for Hex<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct Hasher {
+    crc32: Option<Crc32>,
+    md5: Option<Md5>,
+    sha256: Option<Sha256>,
+}
+
+impl Hasher {
+    fn new(algorithms: &[HashAlgorithm]) -> Hasher {
+        Hasher {
+            crc32: if algorithms.contains(&HashAlgorithm::Crc32) { Some(Crc32::new()) } else { None },
+            md5: if algorithms.contains(&HashAlgorithm::Md5) { Some(Md5::new()) } else { None },
+            sha256: if algorithms.contains(&HashAlgorithm::Sha256) { Some(Sha256::new()) } else { None },
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        if let Some(ref mut crc32) = self.crc32 {
+            crc32.update(data);
+        }
+        if let Some(ref mut md5) = self.md5 {
+            md5.update(data);
+        }
+        if let Some(ref mut sha256) = self.sha256 {
+            sha256.update(data);
+        }
+    }
+
+    fn finish(self) -> Digests {
+        Digests {
+            crc32: self.crc32.map(Crc32::finish),
+            md5: self.md5.map(Md5::finish),
+            sha256: self.sha256.map(Sha256::finish),
+        }
+    }
+}
+
+// Hashes everything read through it. Seeking the inner reader would leave
+// the digests describing a mixture of the data, so it isn't offered.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Hasher,
+}
+
+impl<R> HashingReader<R> where R: Read {
+    pub fn new(inner: R, algorithms: &[HashAlgorithm]) -> HashingReader<R> {
+        HashingReader {
+            inner,
+            hasher: Hasher::new(algorithms),
+        }
+    }
+
+    // The digests of the data read so far.
+    pub fn digests(&self) -> Digests {
+        self.hasher.clone().finish()
+    }
+
+    pub fn into_inner(self) -> (R, Digests) {
+        (self.inner, self.hasher.finish())
+    }
+}
+
+impl<R> Read for HashingReader<R> where R: Read {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+// Hashes everything written through it.
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W> HashingWriter<W> where W: Write {
+    pub fn new(inner: W, algorithms: &[HashAlgorithm]) -> HashingWriter<W> {
+        HashingWriter {
+            inner,
+            hasher: Hasher::new(algorithms),
+        }
+    }
+
+    // The digests of the data written so far.
+    pub fn digests(&self) -> Digests {
+        self.hasher.clone().finish()
+    }
+
+    pub fn into_inner(self) -> (W, Digests) {
+        (self.inner, self.hasher.finish())
+    }
+}
+
+impl<W> Write for HashingWriter<W> where W: Write {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crc32::Crc32;
+    use fixture;
+    use md5;
+    use sha256::Sha256;
+    use std::io::Read;
+    use super::*;
+
+    // Chunk sizes that don't line up with the 64 byte blocks of MD5 and
+    // SHA-256.
+    const CHUNK_SIZES: [usize; 6] = [1, 63, 65, 7, 128, 200];
+
+    #[test]
+    fn uneven_reads_hash_like_one_shot_digests() {
+        let data = fixture::pattern_bytes(1000);
+        let mut reader = HashingReader::new(&data[..], &HASH_ALGORITHMS);
+        let mut contents = Vec::new();
+        let mut buf = [0; 200];
+        for &size in CHUNK_SIZES.iter().cycle() {
+            let read = reader.read(&mut buf[..size]).unwrap();
+            if read == 0 {
+                break;
+            }
+            contents.extend_from_slice(&buf[..read]);
+            assert_eq!(reader.digests().md5, Some(md5::digest(&contents)));
+        }
+        assert_eq!(contents, data);
+
+        let mut crc32 = Crc32::new();
+        crc32.update(&data);
+        let mut sha256 = Sha256::new();
+        sha256.update(&data);
+        let expected = Digests {
+            crc32: Some(crc32.finish()),
+            md5: Some(md5::digest(&data)),
+            sha256: Some(sha256.finish()),
+        };
+        let (_, digests) = reader.into_inner();
+        assert_eq!(digests, expected);
+    }
+
+    #[test]
+    fn only_the_selected_algorithms_are_computed() {
+        let data = fixture::pattern_bytes(100);
+        let mut reader = HashingReader::new(&data[..], &[HashAlgorithm::Md5]);
+        reader.read_to_end(&mut Vec::new()).unwrap();
+        let digests = reader.into_inner().1;
+        assert_eq!(digests, Digests { md5: Some(md5::digest(&data)), ..Default::default() });
+        assert_eq!(digests.to_string(), format!("MD5: {}", fixture::hex(&md5::digest(&data))));
+    }
+}
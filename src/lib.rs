@@ -1,13 +1,72 @@
 extern crate chrono;
+#[cfg(all(feature = "block-device", unix))]
+extern crate libc;
 extern crate num;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
+#[cfg(all(feature = "block-device", unix))]
+mod block_device;
+mod btree;
+mod catalog;
+mod consistency;
+mod copy;
+#[cfg(feature = "hashing")]
+mod crc32;
 mod error;
 mod file_slice;
 mod filesystem;
+#[cfg(test)]
+mod fixture;
+mod fragmentation;
+#[cfg(feature = "hashing")]
+mod hashing;
+mod mapped;
+#[cfg(feature = "hashing")]
+mod md5;
+mod overlay;
+mod ownership;
+#[cfg(any(unix, windows))]
+mod positional;
+mod segmented;
+#[cfg(feature = "repair")]
+mod repair;
+#[cfg(feature = "hashing")]
+mod sha256;
+mod snapshot;
 
+pub mod carve;
+pub mod ddrescue;
 pub mod fs;
 
-pub use filesystem::{FileSystem, VolumeHeader, ForkData};
+pub use filesystem::{FileSystem, VolumeHeader, ForkData, ExtentDescriptors, UsedExtentDescriptors, ExtentRange, HFSFile,
+                     FileMetadata, ForkSizeLimit, HolePolicy, FinderInfo, FourCC, HFSDate, JournalInfoBlock, LastMounted,
+                     TextEncoding, VolumeAttributes, VolumeFingerprint};
+#[cfg(all(feature = "block-device", unix))]
+pub use block_device::source_length;
+pub use btree::{AttributeKey, AttributesTree, BTree, BTreeHeader, BTreeKey, CatalogKey, CatalogTree, ExtentKey, ExtentsTree,
+                LeafNode, LeafNodes, NodeError, NodeErrorKind, NodeReader, Records};
+pub use catalog::FileRecord;
+pub use consistency::{ConsistencyIssue, ExtentIssue, ForkConsistency};
+pub use copy::{CopyError, CopyErrorKind, CopyOutcome, CopyProgress};
 pub use error::HFSPError;
 pub use file_slice::FileSlice;
+pub use fragmentation::{FragmentationHistogram, FragmentationReport};
+#[cfg(feature = "hashing")]
+pub use hashing::{Digests, HashAlgorithm, HashingReader, HashingWriter, HASH_ALGORITHMS};
+pub use mapped::{MappedSource, UnrecoveredData};
+pub use overlay::OverlayReader;
+pub use ownership::{BlockOwner, ForkType, OwnerSearch, TreeCoverage};
+#[cfg(any(unix, windows))]
+pub use positional::PositionalFile;
+#[cfg(feature = "repair")]
+pub use repair::HeaderPatch;
+pub use segmented::SegmentedSource;
+pub use snapshot::{FieldDifference, ForkDataSnapshot, HeaderComparison, HeaderCopy, JournalInfoBlockSnapshot, SpecialFile,
+                   VolumeHeaderSnapshot};
 
@@ -0,0 +1,263 @@
+use ddrescue::{BlockStatus, Mapfile, RegionMap};
+use filesystem::HolePolicy;
+use fs;
+use std::cmp;
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+// The error inside the io::Error returned for reads of data ddrescue never
+// recovered. The status is None for bytes the map doesn't cover.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnrecoveredData {
+    pub offset: u64,
+    pub status: Option<BlockStatus>,
+}
+
+impl UnrecoveredData {
+    // Finds out whether an error from a read was caused by unrecovered data.
+    pub fn from_io_error(error: &io::Error) -> Option<&UnrecoveredData> {
+        error.get_ref().and_then(|e| e.downcast_ref::<UnrecoveredData>())
+    }
+}
+
+impl Display for UnrecoveredData {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "Data at offset {} was not recovered ({})", self.offset, status),
+            None => write!(f, "Data at offset {} is not covered by the mapfile", self.offset),
+        }
+    }
+}
+
+impl error::Error for UnrecoveredData {}
+
+// Reads a ddrescue image, treating everything the mapfile doesn't record as
+// finished as unreadable, so recovered zeros can be told apart from holes.
+// The mapfile is taken to cover the whole device, so the source ends where
+// the map does.
+pub struct MappedSource<F> {
+    source: F,
+    regions: RegionMap,
+    hole_policy: HolePolicy,
+    position: u64,
+    zero_filled: Vec<Range<u64>>,
+}
+
+impl<F> MappedSource<F> where F: Read + Seek {
+    pub fn new(source: F, regions: RegionMap) -> MappedSource<F> {
+        MappedSource {
+            source,
+            regions,
+            hole_policy: HolePolicy::Fail,
+            position: 0,
+            zero_filled: Vec::new(),
+        }
+    }
+
+    pub fn from_mapfile(source: F, mapfile: &Mapfile) -> MappedSource<F> {
+        MappedSource::new(source, mapfile.regions.clone())
+    }
+
+    // With HolePolicy::ZeroFill, unrecovered data reads as zeros and the
+    // ranges read are recorded instead of failing.
+    pub fn with_hole_policy(mut self, hole_policy: HolePolicy) -> MappedSource<F> {
+        self.hole_policy = hole_policy;
+        self
+    }
+
+    pub fn get_hole_policy(&self) -> HolePolicy {
+        self.hole_policy
+    }
+
+    pub fn regions(&self) -> &RegionMap {
+        &self.regions
+    }
+
+    // True if every byte of the range was recovered.
+    pub fn is_range_good(&self, offset: u64, length: u64) -> bool {
+        self.regions.is_range_finished(offset, length)
+    }
+
+    // The unrecovered ranges that have been zero-filled so far, sorted and
+    // merged.
+    pub fn zero_filled_ranges(&self) -> &[Range<u64>] {
+        &self.zero_filled
+    }
+
+    pub fn into_inner(self) -> F {
+        self.source
+    }
+
+    fn len(&self) -> u64 {
+        self.regions.regions().next_back().map_or(0, |r| r.end())
+    }
+
+    // The first unrecovered part of the range, if any, with its status.
+    fn first_unrecovered(&self, offset: u64, end: u64) -> Option<(Range<u64>, Option<BlockStatus>)> {
+        let regions = self.regions.regions().as_slice();
+        let mut position = offset;
+        for region in &regions[regions.partition_point(|r| r.end() <= offset)..] {
+            if position >= end {
+                break;
+            }
+            if region.offset > position {
+                return Some((position..cmp::min(region.offset, end), None));
+            }
+            if !region.status.is_finished() {
+                return Some((position..cmp::min(region.end(), end), Some(region.status)));
+            }
+            position = region.end();
+        }
+        if position < end {
+            Some((position..end, None))
+        } else {
+            None
+        }
+    }
+
+    fn zero_fill(&mut self, range: Range<u64>, buf: &mut [u8]) -> usize {
+        let size = (range.end - range.start) as usize;
+        for byte in &mut buf[..size] {
+            *byte = 0;
+        }
+        // Merges the range with any it overlaps or touches.
+        let first = self.zero_filled.partition_point(|r| r.end < range.start);
+        let last = self.zero_filled.partition_point(|r| r.start <= range.end);
+        let merged = if first < last {
+            cmp::min(range.start, self.zero_filled[first].start)..cmp::max(range.end, self.zero_filled[last - 1].end)
+        } else {
+            range
+        };
+        self.zero_filled.splice(first..last, Some(merged));
+        size
+    }
+}
+
+// Reads stop short at the boundaries between recovered and unrecovered data.
+impl<F> Read for MappedSource<F> where F: Read + Seek {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = self.len();
+        if buf.is_empty() || self.position >= length {
+            return Ok(0);
+        }
+        let end = cmp::min(self.position.saturating_add(buf.len() as u64), length);
+        let size = match self.first_unrecovered(self.position, end) {
+            Some((bad, status)) if bad.start == self.position => {
+                if self.hole_policy == HolePolicy::Fail {
+                    let error = UnrecoveredData { offset: self.position, status };
+                    return Err(io::Error::other(error));
+                }
+                let size = self.zero_fill(bad, buf);
+                self.position += size as u64;
+                return Ok(size);
+            },
+            Some((bad, _)) => (bad.start - self.position) as usize,
+            None => (end - self.position) as usize,
+        };
+        self.source.seek(SeekFrom::Start(self.position))?;
+        let read = self.source.read(&mut buf[..size])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<F> Seek for MappedSource<F> where F: Read + Seek {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::Current(offset) => fs::offset_position(self.position, offset)?,
+            SeekFrom::End(offset) => fs::offset_position(self.len(), offset)?,
+        };
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ddrescue::Region;
+    use fixture;
+    use std::io::Cursor;
+    use super::*;
+
+    // Recovered data either side of a bad region, a gap the map doesn't
+    // cover and an untried region.
+    fn mapped_source() -> (MappedSource<Cursor<Vec<u8>>>, Vec<u8>) {
+        let data = fixture::pattern_bytes(400);
+        let regions = RegionMap::from_regions(vec![
+            Region { offset: 0, length: 100, status: BlockStatus::Finished },
+            Region { offset: 100, length: 50, status: BlockStatus::BadSector },
+            Region { offset: 200, length: 100, status: BlockStatus::NonTried },
+            Region { offset: 300, length: 100, status: BlockStatus::Finished },
+        ]);
+        (MappedSource::new(Cursor::new(data.clone()), regions), data)
+    }
+
+    fn unrecovered(result: io::Result<usize>) -> UnrecoveredData {
+        let error = result.unwrap_err();
+        *UnrecoveredData::from_io_error(&error).unwrap()
+    }
+
+    #[test]
+    fn reads_fail_at_unrecovered_data() {
+        let (mut source, data) = mapped_source();
+        let mut buf = vec![0; 400];
+        assert_eq!(source.read(&mut buf).unwrap(), 100);
+        assert_eq!(buf[..100], data[..100]);
+        let cases = [(100, Some(BlockStatus::BadSector)), (149, Some(BlockStatus::BadSector)), (150, None),
+                     (199, None), (200, Some(BlockStatus::NonTried)), (299, Some(BlockStatus::NonTried))];
+        for &(offset, status) in &cases {
+            source.seek(SeekFrom::Start(offset)).unwrap();
+            assert_eq!(unrecovered(source.read(&mut buf)), UnrecoveredData { offset, status });
+            assert_eq!(source.stream_position().unwrap(), offset);
+        }
+
+        // Reads from recovered data stop where it ends.
+        source.seek(SeekFrom::Start(50)).unwrap();
+        assert_eq!(source.read(&mut buf).unwrap(), 50);
+        assert_eq!(buf[..50], data[50..100]);
+        source.seek(SeekFrom::Start(300)).unwrap();
+        assert_eq!(source.read(&mut buf).unwrap(), 100);
+        assert_eq!(buf[..100], data[300..]);
+        assert_eq!(source.read(&mut buf).unwrap(), 0);
+        assert!(source.zero_filled_ranges().is_empty());
+    }
+
+    #[test]
+    fn zero_fill_replaces_unrecovered_data() {
+        let (source, data) = mapped_source();
+        let mut source = source.with_hole_policy(HolePolicy::ZeroFill);
+        let mut contents = Vec::new();
+        source.read_to_end(&mut contents).unwrap();
+        let mut expected = data.clone();
+        for byte in &mut expected[100..300] {
+            *byte = 0;
+        }
+        assert_eq!(contents, expected);
+        assert_eq!(source.zero_filled_ranges(), &[Range { start: 100, end: 300 }]);
+
+        // Reads inside the unrecovered regions stop at their boundaries.
+        let (source, _) = mapped_source();
+        let mut source = source.with_hole_policy(HolePolicy::ZeroFill);
+        let mut buf = vec![0xff; 400];
+        source.seek(SeekFrom::Start(120)).unwrap();
+        assert_eq!(source.read(&mut buf).unwrap(), 30);
+        assert_eq!(source.read(&mut buf).unwrap(), 50);
+        assert_eq!(source.read(&mut buf).unwrap(), 100);
+        assert!(buf[..100].iter().all(|&byte| byte == 0));
+        assert_eq!(source.read(&mut buf).unwrap(), 100);
+        assert_eq!(buf[..100], data[300..]);
+        assert_eq!(source.zero_filled_ranges(), &[Range { start: 120, end: 300 }]);
+    }
+
+    #[test]
+    fn range_checks_follow_the_map() {
+        let (source, _) = mapped_source();
+        assert!(source.is_range_good(0, 100));
+        assert!(!source.is_range_good(0, 101));
+        assert!(!source.is_range_good(160, 10));
+        assert!(source.is_range_good(300, 100));
+        assert!(!source.is_range_good(300, 101));
+    }
+}
@@ -0,0 +1,258 @@
+use error::HFSPError;
+use fs;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const OVERLAY_MAGIC: &[u8; 8] = b"HFSROVL1";
+
+// Reads from the source except where a range has been written to the
+// overlay. Writes only ever go to the overlay, so the source is never
+// modified.
+pub struct OverlayReader<F> {
+    source: F,
+    overlay: BTreeMap<u64, Vec<u8>>,
+    position: u64,
+}
+
+impl<F> OverlayReader<F> where F: Read + Seek {
+    pub fn new(source: F) -> OverlayReader<F> {
+        OverlayReader {
+            source,
+            overlay: BTreeMap::new(),
+            position: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> F {
+        self.source
+    }
+
+    pub fn overlay_ranges(&self) -> btree_map::Iter<'_, u64, Vec<u8>> {
+        self.overlay.iter()
+    }
+
+    pub fn clear_overlay(&mut self) {
+        self.overlay.clear();
+    }
+
+    pub fn write_overlay(&mut self, offset: u64, data: &[u8]) -> fs::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let end = offset.checked_add(data.len() as u64).ok_or(HFSPError::InvalidFileView)?;
+
+        // Entries are disjoint and sorted, so their ends increase too. Collect
+        // every entry that overlaps or touches the new range.
+        let mut merged = Vec::new();
+        for (&start, existing) in self.overlay.range(..=end).rev() {
+            if start + existing.len() as u64 >= offset {
+                merged.push(start);
+            } else {
+                break;
+            }
+        }
+        let start = merged.iter().cloned().min().map_or(offset, |s| cmp::min(s, offset));
+        let merged_end = merged.iter().map(|s| s + self.overlay[s].len() as u64).max().map_or(end, |e| cmp::max(e, end));
+        let mut buffer = vec![0; (merged_end - start) as usize];
+        for existing_start in merged {
+            let existing = self.overlay.remove(&existing_start).unwrap();
+            let index = (existing_start - start) as usize;
+            buffer[index..index + existing.len()].copy_from_slice(&existing);
+        }
+        let index = (offset - start) as usize;
+        buffer[index..index + data.len()].copy_from_slice(data);
+        self.overlay.insert(start, buffer);
+        Ok(())
+    }
+
+    // The format is a magic number, an index of (offset, length) pairs and
+    // then the data for each entry in index order. All integers are
+    // big-endian u64.
+    pub fn save_overlay<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(OVERLAY_MAGIC)?;
+        writer.write_all(&(self.overlay.len() as u64).to_be_bytes())?;
+        for (offset, data) in &self.overlay {
+            writer.write_all(&offset.to_be_bytes())?;
+            writer.write_all(&(data.len() as u64).to_be_bytes())?;
+        }
+        for data in self.overlay.values() {
+            writer.write_all(data)?;
+        }
+        Ok(())
+    }
+
+    // Entries from the file are layered over any existing overlay.
+    pub fn load_overlay<R: Read>(&mut self, mut reader: R) -> fs::Result<()> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != OVERLAY_MAGIC {
+            return Err(HFSPError::InvalidOverlay);
+        }
+        let count = read_u64(&mut reader)?;
+        let mut index = Vec::new();
+        for _ in 0..count {
+            let offset = read_u64(&mut reader)?;
+            let length = read_u64(&mut reader)?;
+            offset.checked_add(length).ok_or(HFSPError::InvalidOverlay)?;
+            index.push((offset, length));
+        }
+        for (offset, length) in index {
+            let mut data = Vec::new();
+            reader.by_ref().take(length).read_to_end(&mut data)?;
+            if data.len() as u64 != length {
+                return Err(HFSPError::InvalidOverlay);
+            }
+            self.write_overlay(offset, &data)?;
+        }
+        Ok(())
+    }
+
+    fn overlay_end(&self) -> u64 {
+        self.overlay.iter().next_back().map_or(0, |(start, data)| start + data.len() as u64)
+    }
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+impl<F> Read for OverlayReader<F> where F: Read + Seek {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if let Some((&start, data)) = self.overlay.range(..=self.position).next_back() {
+            let end = start + data.len() as u64;
+            if self.position < end {
+                let index = (self.position - start) as usize;
+                let size = cmp::min(buf.len(), data.len() - index);
+                buf[..size].copy_from_slice(&data[index..index + size]);
+                self.position += size as u64;
+                return Ok(size);
+            }
+        }
+        let next_overlay = self.overlay.range(self.position..).next().map(|(&start, _)| start - self.position);
+        let size = match next_overlay {
+            Some(limit) => cmp::min(buf.len() as u64, limit) as usize,
+            None => buf.len(),
+        };
+        self.source.seek(SeekFrom::Start(self.position))?;
+        let mut read = self.source.read(&mut buf[..size])?;
+        if read == 0 && next_overlay.is_some() {
+            // Between the end of the source and overlay data written beyond it.
+            for byte in &mut buf[..size] {
+                *byte = 0;
+            }
+            read = size;
+        }
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<F> Seek for OverlayReader<F> where F: Read + Seek {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            },
+            SeekFrom::Current(offset) => (self.position, offset),
+            SeekFrom::End(offset) => {
+                let source_length = self.source.seek(SeekFrom::End(0))?;
+                (cmp::max(source_length, self.overlay_end()), offset)
+            },
+        };
+        self.position = fs::offset_position(base, offset)?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fixture;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+    use super::*;
+
+    fn read_all(reader: &mut OverlayReader<Cursor<Vec<u8>>>, offset: u64, length: usize) -> Vec<u8> {
+        reader.seek(SeekFrom::Start(offset)).unwrap();
+        let mut buf = vec![0; length];
+        reader.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn overlay_bytes_replace_the_source() {
+        let source = fixture::pattern_bytes(1000);
+        let mut reader = OverlayReader::new(Cursor::new(source.clone()));
+        reader.write_overlay(100, &[0xaa; 50]).unwrap();
+        assert_eq!(read_all(&mut reader, 100, 50), vec![0xaa; 50]);
+        assert_eq!(read_all(&mut reader, 120, 10), vec![0xaa; 10]);
+        assert_eq!(read_all(&mut reader, 0, 100), &source[..100]);
+        assert_eq!(reader.into_inner().into_inner(), source);
+    }
+
+    #[test]
+    fn reads_across_overlay_boundaries_splice_both_sources() {
+        let source = fixture::pattern_bytes(1000);
+        let mut reader = OverlayReader::new(Cursor::new(source.clone()));
+        reader.write_overlay(100, &[0xaa; 50]).unwrap();
+        reader.write_overlay(300, &[0xbb; 10]).unwrap();
+        let mut expected = source.clone();
+        expected[100..150].copy_from_slice(&[0xaa; 50]);
+        expected[300..310].copy_from_slice(&[0xbb; 10]);
+        assert_eq!(read_all(&mut reader, 90, 230), &expected[90..320]);
+        assert_eq!(read_all(&mut reader, 140, 20), &expected[140..160]);
+        assert_eq!(read_all(&mut reader, 0, 1000), expected);
+    }
+
+    #[test]
+    fn overlapping_writes_merge_into_one_entry() {
+        let mut reader = OverlayReader::new(Cursor::new(fixture::pattern_bytes(100)));
+        reader.write_overlay(10, &[1; 10]).unwrap();
+        reader.write_overlay(30, &[3; 10]).unwrap();
+        reader.write_overlay(15, &[2; 15]).unwrap();
+        let entries: Vec<_> = reader.overlay_ranges().map(|(&start, data)| (start, data.len())).collect();
+        assert_eq!(entries, vec![(10, 30)]);
+        let mut expected = vec![1; 5];
+        expected.extend_from_slice(&[2; 15]);
+        expected.extend_from_slice(&[3; 10]);
+        assert_eq!(read_all(&mut reader, 10, 30), expected);
+    }
+
+    #[test]
+    fn writes_past_the_source_end_are_zero_padded() {
+        let source = fixture::pattern_bytes(100);
+        let mut reader = OverlayReader::new(Cursor::new(source.clone()));
+        reader.write_overlay(120, &[0xcc; 8]).unwrap();
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), 128);
+        let mut contents = Vec::new();
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        reader.read_to_end(&mut contents).unwrap();
+        let mut expected = source;
+        expected.extend_from_slice(&[0; 20]);
+        expected.extend_from_slice(&[0xcc; 8]);
+        assert_eq!(contents, expected);
+    }
+
+    #[test]
+    fn saved_overlays_load_over_another_source() {
+        let mut reader = OverlayReader::new(Cursor::new(fixture::pattern_bytes(100)));
+        reader.write_overlay(5, &[7; 3]).unwrap();
+        reader.write_overlay(50, &[9; 4]).unwrap();
+        let mut saved = Vec::new();
+        reader.save_overlay(&mut saved).unwrap();
+
+        let mut loaded = OverlayReader::new(Cursor::new(vec![0; 100]));
+        loaded.load_overlay(&saved[..]).unwrap();
+        assert_eq!(read_all(&mut loaded, 4, 5), [0, 7, 7, 7, 0]);
+        assert_eq!(read_all(&mut loaded, 50, 4), [9; 4]);
+        assert!(matches!(loaded.load_overlay(&saved[..saved.len() - 1]), Err(HFSPError::InvalidOverlay)));
+        saved[0] = b'X';
+        assert!(matches!(loaded.load_overlay(&saved[..]), Err(HFSPError::InvalidOverlay)));
+    }
+}
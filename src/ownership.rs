@@ -0,0 +1,114 @@
+use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum ForkType {
+    Data,
+    Resource,
+}
+
+impl ForkType {
+    pub fn from_raw(raw: u8) -> Option<ForkType> {
+        match raw {
+            0x00 => Some(ForkType::Data),
+            0xff => Some(ForkType::Resource),
+            _ => None,
+        }
+    }
+
+    pub fn to_raw(self) -> u8 {
+        match self {
+            ForkType::Data => 0x00,
+            ForkType::Resource => 0xff,
+        }
+    }
+}
+
+impl Display for ForkType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            ForkType::Data => write!(f, "data"),
+            ForkType::Resource => write!(f, "resource"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct BlockOwner {
+    pub block: u32,
+    pub cnid: u32,
+    pub fork_type: ForkType,
+    // Relative to the root folder. None for the special files and for files
+    // whose parent folders couldn't be found.
+    pub path: Option<String>,
+    // The bytes of the fork stored in the block. This may run past the
+    // logical end of the fork.
+    pub logical_range: Range<u64>,
+}
+
+impl Display for BlockOwner {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Block {}: CNID {} ({} fork", self.block, self.cnid, self.fork_type)?;
+        if let Some(ref path) = self.path {
+            write!(f, " of {}", path)?;
+        }
+        write!(f, ") bytes {}-{}", self.logical_range.start, self.logical_range.end)
+    }
+}
+
+// How much of a B-tree a linear scan managed to read. Nodes marked free in
+// the header's map aren't read and don't count as unreadable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct TreeCoverage {
+    pub nodes_total: u32,
+    pub nodes_unreadable: u32,
+}
+
+impl TreeCoverage {
+    pub fn is_complete(&self) -> bool {
+        self.nodes_unreadable == 0
+    }
+}
+
+impl Display for TreeCoverage {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} of {} nodes unreadable", self.nodes_unreadable, self.nodes_total)
+    }
+}
+
+// The tree coverages are None when the tree couldn't be opened at all, in
+// which case none of the files recorded in it were searched.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct OwnerSearch {
+    pub owners: Vec<BlockOwner>,
+    pub catalog: Option<TreeCoverage>,
+    pub extents_overflow: Option<TreeCoverage>,
+}
+
+// Records an owner for each of the sorted blocks that falls within the
+// extents, which are contiguous in the fork starting at first_fork_block.
+pub fn match_extents<I>(owners: &mut Vec<BlockOwner>, blocks: &[u32], extents: I, first_fork_block: u64,
+                        block_size: u32, cnid: u32, fork_type: ForkType)
+    where I: IntoIterator<Item = (u32, u32)> {
+    let block_size = block_size as u64;
+    let mut fork_block = first_fork_block;
+    for (start, count) in extents {
+        let end = start as u64 + count as u64;
+        let first = blocks.partition_point(|&block| block < start);
+        for &block in blocks[first..].iter().take_while(|&&block| (block as u64) < end) {
+            let offset = (fork_block + (block - start) as u64) * block_size;
+            owners.push(BlockOwner {
+                block,
+                cnid,
+                fork_type,
+                path: None,
+                logical_range: offset..offset + block_size,
+            });
+        }
+        fork_block += count as u64;
+    }
+}
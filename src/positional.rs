@@ -0,0 +1,194 @@
+use fs::{self, TryClone};
+use std::fs::File;
+#[cfg(feature = "write-support")]
+use std::io::Write;
+use std::io::{self, Read, Seek, SeekFrom};
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(windows)]
+use std::os::windows::fs::FileExt;
+use std::sync::Arc;
+
+// A file read with positional I/O rather than through the OS file offset.
+// Clones share the open file but have their own positions, so slices of one
+// disk can be read from several threads at once. A plain File can't do this,
+// as its clones share one offset.
+pub struct PositionalFile {
+    file: Arc<File>,
+    position: u64,
+}
+
+impl PositionalFile {
+    pub fn new(file: File) -> PositionalFile {
+        PositionalFile {
+            file: Arc::new(file),
+            position: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &File {
+        &self.file
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    file.read_at(buf, offset)
+}
+
+// Windows moves the file offset too, but nothing here depends on it.
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    file.seek_read(buf, offset)
+}
+
+#[cfg(all(feature = "write-support", unix))]
+fn write_at(file: &File, data: &[u8], offset: u64) -> io::Result<usize> {
+    file.write_at(data, offset)
+}
+
+#[cfg(all(feature = "write-support", windows))]
+fn write_at(file: &File, data: &[u8], offset: u64) -> io::Result<usize> {
+    file.seek_write(data, offset)
+}
+
+impl Read for PositionalFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = read_at(&self.file, buf, self.position)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(feature = "write-support")]
+impl Write for PositionalFile {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let written = write_at(&self.file, data, self.position)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.file).flush()
+    }
+}
+
+impl Seek for PositionalFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::Current(offset) => fs::offset_position(self.position, offset)?,
+            // Seeking the shared file is the only way to find the length of
+            // a block device. Reads don't use its offset, so moving it is
+            // harmless.
+            SeekFrom::End(offset) => fs::offset_position((&*self.file).seek(SeekFrom::End(0))?, offset)?,
+        };
+        Ok(self.position)
+    }
+}
+
+impl TryClone for PositionalFile {
+    fn try_clone(&self) -> io::Result<PositionalFile> {
+        Ok(PositionalFile {
+            file: self.file.clone(),
+            position: self.position,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use file_slice::FileSlice;
+    use fixture;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Seek, SeekFrom};
+    use std::path::PathBuf;
+    use std::process;
+    use std::thread;
+    use super::*;
+
+    // A file of pattern bytes, removed when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, length: u64) -> TempFile {
+            let path = env::temp_dir().join(format!("hfsplus-rescue-{}-{}", process::id(), name));
+            fs::write(&path, fixture::pattern_bytes(length)).unwrap();
+            TempFile(path)
+        }
+
+        fn open(&self) -> File {
+            File::open(&self.0).unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn clones_have_their_own_positions() {
+        let temp = TempFile::new("clones", 1000);
+        let mut first = PositionalFile::new(temp.open());
+        first.seek(SeekFrom::Start(100)).unwrap();
+        let mut second = first.try_clone().unwrap();
+        assert_eq!(second.stream_position().unwrap(), 100);
+        assert_eq!(second.seek(SeekFrom::End(-10)).unwrap(), 990);
+        let mut buf = [0; 20];
+        assert_eq!(first.read(&mut buf).unwrap(), 20);
+        assert_eq!(buf[..], fixture::pattern_bytes(120)[100..]);
+        assert_eq!(second.read(&mut buf).unwrap(), 10);
+        assert_eq!(buf[..10], fixture::pattern_bytes(1000)[990..]);
+        assert_eq!(first.stream_position().unwrap(), 120);
+    }
+
+    #[test]
+    fn nested_slices_read_independently_from_threads() {
+        let length = 64 * 1024;
+        let temp = TempFile::new("threads", length);
+        let data = fixture::pattern_bytes(length);
+        let partition = FileSlice::new(PositionalFile::new(temp.open()), 1024, None).unwrap();
+        let mut handles = Vec::new();
+        for idx in 0..8 {
+            let offset = idx * 7 * 1024;
+            let mut slice = partition.slice(offset, 7 * 1024 + 3).unwrap();
+            handles.push(thread::spawn(move || {
+                let mut contents = Vec::new();
+                for _ in 0..50 {
+                    contents.clear();
+                    slice.seek(SeekFrom::Start(0)).unwrap();
+                    let mut buf = [0; 61];
+                    loop {
+                        let read = slice.read(&mut buf).unwrap();
+                        if read == 0 {
+                            break;
+                        }
+                        contents.extend_from_slice(&buf[..read]);
+                    }
+                }
+                (offset, contents)
+            }));
+        }
+        for handle in handles {
+            let (offset, contents) = handle.join().unwrap();
+            let start = 1024 + offset as usize;
+            assert_eq!(contents, &data[start..start + 7 * 1024 + 3], "{}", offset);
+        }
+    }
+
+    #[test]
+    fn slices_of_slices_seek_from_their_own_end() {
+        let temp = TempFile::new("nested", 4096);
+        let data = fixture::pattern_bytes(4096);
+        let partition = FileSlice::new(PositionalFile::new(temp.open()), 512, Some(2048)).unwrap();
+        let mut journal = FileSlice::new(partition, 1024, Some(512)).unwrap();
+        assert_eq!(journal.seek(SeekFrom::End(-100)).unwrap(), 412);
+        let mut contents = Vec::new();
+        journal.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, &data[512 + 1024 + 412..512 + 1024 + 512]);
+        assert!(FileSlice::new(journal, 0, Some(513)).is_err());
+    }
+}
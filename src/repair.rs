@@ -0,0 +1,220 @@
+use error::HFSPError;
+use filesystem::{Structure, VolumeAttributes, VolumeHeader};
+use fs;
+use std::io::{Read, Seek, Write};
+
+const OFFSET_ATTRIBUTES: usize = 4;
+const OFFSET_JOURNAL_INFO_BLOCK: usize = 12;
+const OFFSET_FILE_COUNT: usize = 32;
+const OFFSET_FOLDER_COUNT: usize = 36;
+const OFFSET_FREE_BLOCKS: usize = 48;
+const OFFSET_NEXT_ALLOCATION: usize = 52;
+const OFFSET_NEXT_CATALOG_ID: usize = 64;
+const OFFSET_WRITE_COUNT: usize = 68;
+
+// Fields left as None are not touched.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeaderPatch {
+    pub attributes: Option<VolumeAttributes>,
+    pub journal_info_block: Option<u32>,
+    pub file_count: Option<u32>,
+    pub folder_count: Option<u32>,
+    pub free_blocks: Option<u32>,
+    pub next_allocation: Option<u32>,
+    pub next_catalog_id: Option<u32>,
+    pub write_count: Option<u32>,
+    pub mirror_to_alternate: bool,
+}
+
+impl HeaderPatch {
+    pub fn new() -> HeaderPatch {
+        HeaderPatch::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields().iter().all(|&(_, value)| value.is_none())
+    }
+
+    fn fields(&self) -> [(usize, Option<u32>); 8] {
+        [
+            (OFFSET_ATTRIBUTES, self.attributes.map(|a| a.get_raw())),
+            (OFFSET_JOURNAL_INFO_BLOCK, self.journal_info_block),
+            (OFFSET_FILE_COUNT, self.file_count),
+            (OFFSET_FOLDER_COUNT, self.folder_count),
+            (OFFSET_FREE_BLOCKS, self.free_blocks),
+            (OFFSET_NEXT_ALLOCATION, self.next_allocation),
+            (OFFSET_NEXT_CATALOG_ID, self.next_catalog_id),
+            (OFFSET_WRITE_COUNT, self.write_count),
+        ]
+    }
+
+    fn set_field(&mut self, offset: usize, value: u32) {
+        match offset {
+            OFFSET_ATTRIBUTES => self.attributes = Some(VolumeAttributes::new(value)),
+            OFFSET_JOURNAL_INFO_BLOCK => self.journal_info_block = Some(value),
+            OFFSET_FILE_COUNT => self.file_count = Some(value),
+            OFFSET_FOLDER_COUNT => self.folder_count = Some(value),
+            OFFSET_FREE_BLOCKS => self.free_blocks = Some(value),
+            OFFSET_NEXT_ALLOCATION => self.next_allocation = Some(value),
+            OFFSET_NEXT_CATALOG_ID => self.next_catalog_id = Some(value),
+            OFFSET_WRITE_COUNT => self.write_count = Some(value),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<'a, F> VolumeHeader<'a, F> where F: Read + Write + Seek {
+    // Returns a patch holding the previous values of this header, which
+    // reverts the change when applied. Everything to be overwritten is read
+    // before anything is written, and if a write fails, those already made
+    // are undone as far as the source allows before the error is returned.
+    pub fn patch(&self, patch: &HeaderPatch) -> fs::Result<HeaderPatch> {
+        let filesystem = self.get_filesystem();
+        if !filesystem.is_writable() {
+            return Err(HFSPError::ReadOnlySource);
+        }
+        let alternate = if patch.mirror_to_alternate {
+            Some(filesystem.get_alternate_volume_header()?).filter(|a| a.get_offset() != self.get_offset())
+        } else {
+            None
+        };
+        let mut previous = HeaderPatch {
+            mirror_to_alternate: patch.mirror_to_alternate,
+            ..HeaderPatch::default()
+        };
+        // Each header and offset to write, with the old and new values.
+        let mut writes = Vec::new();
+        for &(offset, value) in patch.fields().iter() {
+            if let Some(value) = value {
+                let old = self.read_number(offset)?;
+                previous.set_field(offset, old);
+                writes.push((self, offset, old, value));
+            }
+        }
+        if let Some(ref alternate) = alternate {
+            for &(offset, value) in patch.fields().iter() {
+                if let Some(value) = value {
+                    writes.push((alternate, offset, alternate.read_number(offset)?, value));
+                }
+            }
+        }
+        for (idx, &(header, offset, _, value)) in writes.iter().enumerate() {
+            if let Err(e) = header.write_number(offset, value) {
+                // The failed write may have been partly made, so it is undone
+                // too.
+                for &(header, offset, old, _) in writes[..=idx].iter().rev() {
+                    let _ = header.write_number(offset, old);
+                }
+                return Err(e);
+            }
+        }
+        Ok(previous)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use filesystem::FileSystem;
+    use fixture::{self, VolumeBuilder};
+    use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+    use super::*;
+
+    fn volume() -> Vec<u8> {
+        let mut volume = VolumeBuilder::new(512, 64);
+        fixture::put_u32(volume.header(), OFFSET_FREE_BLOCKS, 10);
+        fixture::put_u32(volume.header(), OFFSET_NEXT_CATALOG_ID, 100);
+        volume.build()
+    }
+
+    // Fails the given write, counting from zero, and no others.
+    struct FailingWrite {
+        inner: Cursor<Vec<u8>>,
+        writes: usize,
+        fail_at: usize,
+    }
+
+    impl Read for FailingWrite {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Seek for FailingWrite {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    impl Write for FailingWrite {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            if self.writes - 1 == self.fail_at {
+                // Leaves half of the value written.
+                self.inner.write_all(&buf[..buf.len() / 2])?;
+                return Err(io::Error::other("injected write failure"));
+            }
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn header_values<F: Read + Seek>(header: &VolumeHeader<F>) -> (u32, u32, u32) {
+        (header.get_free_blocks().unwrap(), header.get_next_catalog_id().unwrap(), header.get_write_count().unwrap())
+    }
+
+    #[test]
+    fn patch_returns_a_reverting_patch() {
+        let filesystem = FileSystem::new_writable(Cursor::new(volume()));
+        let header = filesystem.get_volume_header().unwrap();
+        let patch = HeaderPatch {
+            free_blocks: Some(20),
+            next_catalog_id: Some(200),
+            mirror_to_alternate: true,
+            ..HeaderPatch::new()
+        };
+        let revert = header.patch(&patch).unwrap();
+        assert_eq!(revert, HeaderPatch {
+            free_blocks: Some(10),
+            next_catalog_id: Some(100),
+            mirror_to_alternate: true,
+            ..HeaderPatch::new()
+        });
+        let alternate = filesystem.get_alternate_volume_header().unwrap();
+        assert_eq!(header_values(&header), (20, 200, 0));
+        assert_eq!(header_values(&alternate), (20, 200, 0));
+        assert_eq!(header.patch(&revert).unwrap(), patch);
+        assert_eq!(header_values(&header), (10, 100, 0));
+        assert_eq!(header_values(&alternate), (10, 100, 0));
+    }
+
+    #[test]
+    fn patch_refuses_read_only_sources() {
+        let filesystem = FileSystem::new(Cursor::new(volume()));
+        let patch = HeaderPatch { free_blocks: Some(20), ..HeaderPatch::new() };
+        let result = filesystem.get_volume_header().unwrap().patch(&patch);
+        assert!(matches!(result, Err(HFSPError::ReadOnlySource)));
+    }
+
+    #[test]
+    fn failed_patch_is_undone() {
+        let patch = HeaderPatch {
+            free_blocks: Some(20),
+            next_catalog_id: Some(200),
+            write_count: Some(5),
+            mirror_to_alternate: true,
+            ..HeaderPatch::new()
+        };
+        // Six writes are needed, three to each header.
+        for fail_at in 0..6 {
+            let source = FailingWrite { inner: Cursor::new(volume()), writes: 0, fail_at };
+            let filesystem = FileSystem::new_writable(source);
+            let header = filesystem.get_volume_header().unwrap();
+            assert!(header.patch(&patch).is_err());
+            assert_eq!(header_values(&header), (10, 100, 0), "{}", fail_at);
+            assert_eq!(header_values(&filesystem.get_alternate_volume_header().unwrap()), (10, 100, 0), "{}", fail_at);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use error::HFSPError;
+use fs;
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom};
+
+struct Segment<F> {
+    source: F,
+    offset: u64,
+    length: u64,
+    // Where the segment starts in the combined source.
+    start: u64,
+}
+
+// Presents byte ranges of several sources, in order, as one contiguous
+// source, for images that were captured or stored in pieces.
+pub struct SegmentedSource<F> {
+    segments: Vec<Segment<F>>,
+    length: u64,
+    position: u64,
+}
+
+impl<F> SegmentedSource<F> where F: Seek {
+    // Each segment is a source with the offset and length of the range of it
+    // to use. Ranges must lie within their sources.
+    pub fn new<I>(segments: I) -> fs::Result<SegmentedSource<F>> where I: IntoIterator<Item = (F, u64, u64)> {
+        let mut result = SegmentedSource {
+            segments: Vec::new(),
+            length: 0,
+            position: 0,
+        };
+        for (mut source, offset, length) in segments {
+            let end = offset.checked_add(length).ok_or(HFSPError::ArithmeticOverflow)?;
+            if source.seek(SeekFrom::End(0))? < end {
+                return Err(HFSPError::InvalidFileView);
+            }
+            if length == 0 {
+                continue;
+            }
+            let start = result.length;
+            result.length = start.checked_add(length).ok_or(HFSPError::ArithmeticOverflow)?;
+            result.segments.push(Segment { source, offset, length, start });
+        }
+        Ok(result)
+    }
+
+    // Joins the whole of each source, as for split image files.
+    pub fn concatenate<I>(sources: I) -> fs::Result<SegmentedSource<F>> where I: IntoIterator<Item = F> {
+        let mut segments = Vec::new();
+        for mut source in sources {
+            let length = source.seek(SeekFrom::End(0))?;
+            segments.push((source, 0, length));
+        }
+        SegmentedSource::new(segments)
+    }
+}
+
+impl<F> SegmentedSource<F> {
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn num_segments(&self) -> usize {
+        self.segments.len()
+    }
+}
+
+impl<F> Read for SegmentedSource<F> where F: Read + Seek {
+    // Reads stop at the end of a segment, with the next read continuing in
+    // the following one.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.length {
+            return Ok(0);
+        }
+        let index = self.segments.partition_point(|segment| segment.start <= self.position) - 1;
+        let segment = &mut self.segments[index];
+        let within = self.position - segment.start;
+        let size = cmp::min(buf.len() as u64, segment.length - within) as usize;
+        segment.source.seek(SeekFrom::Start(segment.offset + within))?;
+        let read = segment.source.read(&mut buf[..size])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<F> Seek for SegmentedSource<F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::Current(offset) => fs::offset_position(self.position, offset)?,
+            SeekFrom::End(offset) => fs::offset_position(self.length, offset)?,
+        };
+        Ok(self.position)
+    }
+}
@@ -0,0 +1,491 @@
+use consistency::{self, ForkConsistency};
+use filesystem::{self, FinderInfo, FourCC, HFSDate, TextEncoding, VolumeAttributes};
+use std::cmp;
+use std::fmt::{self, Display, Formatter};
+
+pub const NUM_EXTENT_DESCRIPTORS: usize = 8;
+
+pub fn be_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+pub fn be_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+pub fn be_u64(data: &[u8], offset: usize) -> u64 {
+    (be_u32(data, offset) as u64) << 32 | be_u32(data, offset + 4) as u64
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ForkDataSnapshot {
+    pub logical_size: u64,
+    pub clump_size: u32,
+    pub total_blocks: u32,
+    pub extents: [(u32, u32); NUM_EXTENT_DESCRIPTORS],
+}
+
+impl ForkDataSnapshot {
+    // Expects the 80 bytes of an HFSPlusForkData.
+    pub fn from_bytes(data: &[u8]) -> ForkDataSnapshot {
+        let mut extents = [(0, 0); NUM_EXTENT_DESCRIPTORS];
+        for (idx, extent) in extents.iter_mut().enumerate() {
+            let offset = 16 + idx * 8;
+            *extent = (be_u32(data, offset), be_u32(data, offset + 4));
+        }
+        ForkDataSnapshot {
+            logical_size: be_u64(data, 0),
+            clump_size: be_u32(data, 8),
+            total_blocks: be_u32(data, 12),
+            extents,
+        }
+    }
+
+    pub fn check_consistency(&self, block_size: u32) -> ForkConsistency {
+        consistency::check_fork(self, block_size)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum SpecialFile {
+    Allocation,
+    Extents,
+    Catalog,
+    Attributes,
+    Startup,
+}
+
+pub const SPECIAL_FILES: [SpecialFile; 5] = [
+    SpecialFile::Allocation,
+    SpecialFile::Extents,
+    SpecialFile::Catalog,
+    SpecialFile::Attributes,
+    SpecialFile::Startup,
+];
+
+impl SpecialFile {
+    pub fn get_name(&self) -> &'static str {
+        match *self {
+            SpecialFile::Allocation => "allocation",
+            SpecialFile::Extents => "extents",
+            SpecialFile::Catalog => "catalog",
+            SpecialFile::Attributes => "attributes",
+            SpecialFile::Startup => "startup",
+        }
+    }
+
+    pub fn get_cnid(&self) -> u32 {
+        match *self {
+            SpecialFile::Extents => 3,
+            SpecialFile::Catalog => 4,
+            SpecialFile::Allocation => 6,
+            SpecialFile::Startup => 7,
+            SpecialFile::Attributes => 8,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct VolumeHeaderSnapshot {
+    pub signature: [u8; 2],
+    pub version: u16,
+    pub attributes: VolumeAttributes,
+    pub last_mounted_version: FourCC,
+    pub journal_info_block: u32,
+    pub create_date: HFSDate,
+    pub modify_date: HFSDate,
+    pub backup_date: HFSDate,
+    pub checked_date: HFSDate,
+    pub file_count: u32,
+    pub folder_count: u32,
+    pub block_size: u32,
+    pub total_blocks: u32,
+    pub free_blocks: u32,
+    pub next_allocation: u32,
+    pub rsrc_clump_size: u32,
+    pub data_clump_size: u32,
+    pub next_catalog_id: u32,
+    pub write_count: u32,
+    pub encodings_bitmap: u64,
+    pub finder_info: FinderInfo,
+    pub allocation_file: ForkDataSnapshot,
+    pub extents_file: ForkDataSnapshot,
+    pub catalog_file: ForkDataSnapshot,
+    pub attributes_file: ForkDataSnapshot,
+    pub startup_file: ForkDataSnapshot,
+}
+
+impl VolumeHeaderSnapshot {
+    // Expects the 512 bytes of an HFSPlusVolumeHeader.
+    pub fn from_bytes(data: &[u8]) -> VolumeHeaderSnapshot {
+        let mut finder_info = [0; 8];
+        for (idx, word) in finder_info.iter_mut().enumerate() {
+            *word = be_u32(data, filesystem::OFFSET_FINDER_INFO + idx * 4);
+        }
+        let fork = |idx: u64| {
+            let offset = (filesystem::OFFSET_VOLUME_HEADER_FORKS + filesystem::SIZE_FORK_DATA * idx) as usize;
+            ForkDataSnapshot::from_bytes(&data[offset..offset + filesystem::SIZE_FORK_DATA as usize])
+        };
+        VolumeHeaderSnapshot {
+            signature: [data[0], data[1]],
+            version: be_u16(data, 2),
+            attributes: VolumeAttributes::new(be_u32(data, 4)),
+            last_mounted_version: FourCC::new([data[8], data[9], data[10], data[11]]),
+            journal_info_block: be_u32(data, 12),
+            create_date: filesystem::hfs_date(be_u32(data, 16), true),
+            modify_date: filesystem::hfs_date(be_u32(data, 20), false),
+            backup_date: filesystem::hfs_date(be_u32(data, 24), false),
+            checked_date: filesystem::hfs_date(be_u32(data, 28), false),
+            file_count: be_u32(data, 32),
+            folder_count: be_u32(data, 36),
+            block_size: be_u32(data, 40),
+            total_blocks: be_u32(data, 44),
+            free_blocks: be_u32(data, 48),
+            next_allocation: be_u32(data, 52),
+            rsrc_clump_size: be_u32(data, 56),
+            data_clump_size: be_u32(data, 60),
+            next_catalog_id: be_u32(data, 64),
+            write_count: be_u32(data, 68),
+            encodings_bitmap: be_u64(data, 72),
+            finder_info: FinderInfo::new(finder_info),
+            allocation_file: fork(0),
+            extents_file: fork(1),
+            catalog_file: fork(2),
+            attributes_file: fork(3),
+            startup_file: fork(4),
+        }
+    }
+
+    pub fn get_special_file(&self, file: SpecialFile) -> &ForkDataSnapshot {
+        match file {
+            SpecialFile::Allocation => &self.allocation_file,
+            SpecialFile::Extents => &self.extents_file,
+            SpecialFile::Catalog => &self.catalog_file,
+            SpecialFile::Attributes => &self.attributes_file,
+            SpecialFile::Startup => &self.startup_file,
+        }
+    }
+
+    pub fn is_hfsx(&self) -> bool {
+        &self.signature == filesystem::SIGNATURE_HFSX
+    }
+
+    pub fn get_encodings(&self) -> Vec<TextEncoding> {
+        filesystem::decode_encodings(self.encodings_bitmap)
+    }
+
+    // The fields that differ from another copy of the header, in header
+    // order. Forks are compared field by field and extent by extent.
+    pub fn diff(&self, other: &VolumeHeaderSnapshot) -> Vec<FieldDifference> {
+        let mut result = Vec::new();
+        macro_rules! compare {
+            ($($field:ident),*) => {
+                $(if self.$field != other.$field {
+                    result.push(FieldDifference::new(stringify!($field).to_string(), &self.$field, &other.$field));
+                })*
+            };
+        }
+        compare!(signature, version, attributes, last_mounted_version, journal_info_block, create_date, modify_date,
+                 backup_date, checked_date, file_count, folder_count, block_size, total_blocks, free_blocks,
+                 next_allocation, rsrc_clump_size, data_clump_size, next_catalog_id, write_count, encodings_bitmap,
+                 finder_info);
+        for &file in SPECIAL_FILES.iter() {
+            let (ours, theirs) = (self.get_special_file(file), other.get_special_file(file));
+            let name = |field: &str| format!("{}_file.{}", file.get_name(), field);
+            if ours.logical_size != theirs.logical_size {
+                result.push(FieldDifference::new(name("logical_size"), &ours.logical_size, &theirs.logical_size));
+            }
+            if ours.clump_size != theirs.clump_size {
+                result.push(FieldDifference::new(name("clump_size"), &ours.clump_size, &theirs.clump_size));
+            }
+            if ours.total_blocks != theirs.total_blocks {
+                result.push(FieldDifference::new(name("total_blocks"), &ours.total_blocks, &theirs.total_blocks));
+            }
+            for (idx, (a, b)) in ours.extents.iter().zip(theirs.extents.iter()).enumerate() {
+                if a != b {
+                    result.push(FieldDifference::new(name(&format!("extents[{}]", idx)), a, b));
+                }
+            }
+        }
+        result
+    }
+}
+
+// A header field whose value differs between two copies of the header, with
+// both values as they would be printed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct FieldDifference {
+    pub field: String,
+    pub left: String,
+    pub right: String,
+}
+
+impl FieldDifference {
+    fn new<T>(field: String, left: &T, right: &T) -> FieldDifference where T: fmt::Debug {
+        FieldDifference {
+            field,
+            left: format!("{:?}", left),
+            right: format!("{:?}", right),
+        }
+    }
+}
+
+impl Display for FieldDifference {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}: {} / {}", self.field, self.left, self.right)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum HeaderCopy {
+    Primary,
+    Alternate,
+}
+
+impl Display for HeaderCopy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            HeaderCopy::Primary => write!(f, "primary"),
+            HeaderCopy::Alternate => write!(f, "alternate"),
+        }
+    }
+}
+
+// The primary and alternate volume headers side by side. The errors are
+// why a copy failed validation, if it did. The newer copy is None where
+// both agree.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct HeaderComparison {
+    pub primary: VolumeHeaderSnapshot,
+    pub alternate: VolumeHeaderSnapshot,
+    pub primary_error: Option<String>,
+    pub alternate_error: Option<String>,
+    pub newer_modify_date: Option<HeaderCopy>,
+    pub newer_write_count: Option<HeaderCopy>,
+    pub differences: Vec<FieldDifference>,
+}
+
+impl HeaderComparison {
+    pub fn new(primary: VolumeHeaderSnapshot, alternate: VolumeHeaderSnapshot, primary_error: Option<String>,
+               alternate_error: Option<String>) -> HeaderComparison {
+        let newer = |ordering: cmp::Ordering| match ordering {
+            cmp::Ordering::Greater => Some(HeaderCopy::Primary),
+            cmp::Ordering::Less => Some(HeaderCopy::Alternate),
+            cmp::Ordering::Equal => None,
+        };
+        HeaderComparison {
+            newer_modify_date: newer(primary.modify_date.cmp(&alternate.modify_date)),
+            newer_write_count: newer(primary.write_count.cmp(&alternate.write_count)),
+            differences: primary.diff(&alternate),
+            primary,
+            alternate,
+            primary_error,
+            alternate_error,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct JournalInfoBlockSnapshot {
+    pub flags: u32,
+    pub device_signature: [u8; filesystem::SIZE_JOURNAL_DEVICE_SIGNATURE],
+    pub journal_offset: u64,
+    pub journal_size: u64,
+}
+
+impl JournalInfoBlockSnapshot {
+    // Expects the first 52 bytes of a JournalInfoBlock.
+    pub fn from_bytes(data: &[u8]) -> JournalInfoBlockSnapshot {
+        let mut device_signature = [0; filesystem::SIZE_JOURNAL_DEVICE_SIGNATURE];
+        device_signature.copy_from_slice(&data[4..4 + filesystem::SIZE_JOURNAL_DEVICE_SIGNATURE]);
+        JournalInfoBlockSnapshot {
+            flags: be_u32(data, 0),
+            device_signature,
+            journal_offset: be_u64(data, 36),
+            journal_size: be_u64(data, 44),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fixture::{self, VolumeBuilder};
+    use super::*;
+
+    // The 512 bytes of a header with a catalog file and some dates set.
+    fn header_bytes() -> Vec<u8> {
+        let mut volume = VolumeBuilder::new(4096, 1000);
+        volume.set_fork(SpecialFile::Catalog, 8192, &[(10, 1), (20, 1)]);
+        let header = volume.header();
+        fixture::put_u32(header, 20, 3786912000);
+        fixture::put_u32(header, 32, 12);
+        fixture::put_u32(header, 48, 250);
+        header.to_vec()
+    }
+
+    #[test]
+    fn fork_data_from_bytes() {
+        let mut data = [0; 80];
+        data[0..8].copy_from_slice(&0x0000_0001_0000_0200u64.to_be_bytes());
+        data[8..12].copy_from_slice(&0x10000u32.to_be_bytes());
+        data[12..16].copy_from_slice(&0x0102_0304u32.to_be_bytes());
+        for idx in 0..NUM_EXTENT_DESCRIPTORS {
+            data[16 + idx * 8..20 + idx * 8].copy_from_slice(&(1000 + idx as u32).to_be_bytes());
+            data[20 + idx * 8..24 + idx * 8].copy_from_slice(&(idx as u32 + 1).to_be_bytes());
+        }
+        let fork = ForkDataSnapshot::from_bytes(&data);
+        assert_eq!(fork.logical_size, 0x1_0000_0200);
+        assert_eq!(fork.clump_size, 0x10000);
+        assert_eq!(fork.total_blocks, 0x0102_0304);
+        assert_eq!(fork.extents, [(1000, 1), (1001, 2), (1002, 3), (1003, 4), (1004, 5), (1005, 6), (1006, 7), (1007, 8)]);
+        assert_eq!(ForkDataSnapshot::from_bytes(&fixture::fork_data(8192, &[(10, 1), (20, 1)])).extents[..3],
+                   [(10, 1), (20, 1), (0, 0)]);
+    }
+
+    #[test]
+    fn volume_header_from_bytes() {
+        let snapshot = VolumeHeaderSnapshot::from_bytes(&header_bytes());
+        assert_eq!(&snapshot.signature, b"H+");
+        assert_eq!(snapshot.version, 4);
+        assert_eq!(snapshot.file_count, 12);
+        assert_eq!(snapshot.block_size, 4096);
+        assert_eq!(snapshot.total_blocks, 1000);
+        assert_eq!(snapshot.free_blocks, 250);
+        assert_eq!(snapshot.modify_date.naive_utc().to_string(), "2024-01-01 00:00:00");
+        assert_eq!(snapshot.get_special_file(SpecialFile::Catalog).logical_size, 8192);
+        assert_eq!(snapshot.get_special_file(SpecialFile::Catalog).extents[1], (20, 1));
+        for &special in SPECIAL_FILES.iter().filter(|&&f| f != SpecialFile::Catalog) {
+            assert_eq!(snapshot.get_special_file(special).logical_size, 0, "{}", special.get_name());
+        }
+        assert!(!snapshot.is_hfsx());
+    }
+
+    #[test]
+    fn volume_header_fields_come_from_their_offsets() {
+        // Each 32-bit field from offset 4 to 80 holds its own offset.
+        let mut data = header_bytes();
+        for offset in (4..80).step_by(4) {
+            fixture::put_u32(&mut data, offset, offset as u32);
+        }
+        let snapshot = VolumeHeaderSnapshot::from_bytes(&data);
+        assert_eq!(snapshot.attributes.get_raw(), 4);
+        assert_eq!(snapshot.last_mounted_version.get_raw(), 8);
+        assert_eq!(snapshot.journal_info_block, 12);
+        assert_eq!(snapshot.file_count, 32);
+        assert_eq!(snapshot.folder_count, 36);
+        assert_eq!(snapshot.block_size, 40);
+        assert_eq!(snapshot.total_blocks, 44);
+        assert_eq!(snapshot.free_blocks, 48);
+        assert_eq!(snapshot.next_allocation, 52);
+        assert_eq!(snapshot.rsrc_clump_size, 56);
+        assert_eq!(snapshot.data_clump_size, 60);
+        assert_eq!(snapshot.next_catalog_id, 64);
+        assert_eq!(snapshot.write_count, 68);
+        assert_eq!(snapshot.encodings_bitmap, 72 << 32 | 76);
+    }
+
+    #[test]
+    fn journal_info_block_from_bytes() {
+        let mut data = [0; 52];
+        fixture::put_u32(&mut data, 0, 1);
+        data[4..36].copy_from_slice(&[0xab; 32]);
+        fixture::put_u64(&mut data, 36, 0x1000);
+        fixture::put_u64(&mut data, 44, 0x80_0000);
+        let journal = JournalInfoBlockSnapshot::from_bytes(&data);
+        assert_eq!(journal, JournalInfoBlockSnapshot {
+            flags: 1,
+            device_signature: [0xab; 32],
+            journal_offset: 0x1000,
+            journal_size: 0x80_0000,
+        });
+    }
+
+    #[test]
+    fn identical_headers_have_no_differences() {
+        let snapshot = VolumeHeaderSnapshot::from_bytes(&header_bytes());
+        assert!(snapshot.diff(&snapshot.clone()).is_empty());
+    }
+
+    #[test]
+    fn differences_name_the_field_and_both_values() {
+        let primary = header_bytes();
+        let mut alternate = primary.clone();
+        fixture::put_u32(&mut alternate, 48, 300);
+        fixture::put_u32(&mut alternate, 68, 7);
+        // The second extent of the catalog file.
+        let catalog = filesystem::OFFSET_VOLUME_HEADER_FORKS as usize + 2 * filesystem::SIZE_FORK_DATA as usize;
+        fixture::put_u32(&mut alternate, catalog + 24, 30);
+        let primary = VolumeHeaderSnapshot::from_bytes(&primary);
+        let alternate = VolumeHeaderSnapshot::from_bytes(&alternate);
+        let differences = primary.diff(&alternate);
+        let expected = [
+            ("free_blocks", "250", "300"),
+            ("write_count", "0", "7"),
+            ("catalog_file.extents[1]", "(20, 1)", "(30, 1)"),
+        ];
+        assert_eq!(differences.len(), expected.len());
+        for (difference, &(field, left, right)) in differences.iter().zip(expected.iter()) {
+            assert_eq!((&difference.field[..], &difference.left[..], &difference.right[..]), (field, left, right));
+        }
+        assert_eq!(differences[0].to_string(), "free_blocks: 250 / 300");
+        assert_eq!(alternate.diff(&primary)[1].left, "7");
+    }
+
+    #[test]
+    fn comparisons_find_the_newer_copy() {
+        let primary = header_bytes();
+        let mut alternate = primary.clone();
+        fixture::put_u32(&mut alternate, 20, 3786912000 - 60);
+        fixture::put_u32(&mut alternate, 68, 3);
+        let comparison = HeaderComparison::new(VolumeHeaderSnapshot::from_bytes(&primary),
+                                               VolumeHeaderSnapshot::from_bytes(&alternate), None, None);
+        assert_eq!(comparison.newer_modify_date, Some(HeaderCopy::Primary));
+        assert_eq!(comparison.newer_write_count, Some(HeaderCopy::Alternate));
+        assert_eq!(comparison.differences.len(), 2);
+
+        let comparison = HeaderComparison::new(comparison.primary.clone(), comparison.primary, None, None);
+        assert_eq!(comparison.newer_modify_date, None);
+        assert_eq!(comparison.newer_write_count, None);
+        assert!(comparison.differences.is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn volume_header_round_trips_through_json() {
+        use chrono::DateTime;
+        use serde_json::{self, Value};
+
+        let snapshot = VolumeHeaderSnapshot::from_bytes(&header_bytes());
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let value: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value, serde_json::to_value(&snapshot).unwrap());
+
+        assert_eq!(value["signature"], serde_json::json!([b'H', b'+']));
+        assert_eq!(value["block_size"], 4096);
+        assert_eq!(value["total_blocks"], 1000);
+        assert_eq!(value["free_blocks"], 250);
+        assert_eq!(value["file_count"], 12);
+        assert_eq!(value["catalog_file"]["logical_size"], 8192);
+        assert_eq!(value["catalog_file"]["total_blocks"], 2);
+        assert_eq!(value["catalog_file"]["extents"][1], serde_json::json!([20, 1]));
+        for &field in &["create_date", "modify_date", "backup_date", "checked_date"] {
+            let date = DateTime::parse_from_rfc3339(value[field].as_str().unwrap()).unwrap();
+            let expected = match field {
+                "create_date" => snapshot.create_date,
+                "modify_date" => snapshot.modify_date,
+                "backup_date" => snapshot.backup_date,
+                _ => snapshot.checked_date,
+            };
+            assert_eq!(date, expected, "{}", field);
+        }
+        let modify_date = DateTime::parse_from_rfc3339(value["modify_date"].as_str().unwrap()).unwrap();
+        assert_eq!(modify_date.naive_utc().to_string(), "2024-01-01 00:00:00");
+    }
+}